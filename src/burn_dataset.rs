@@ -1,164 +1,831 @@
-use crate::categories::{Encryption, IpProtocol, PacketDirection};
-use crate::data_structure::{BasePacket, MetadataWrapper};
+use crate::categories::IpProtocol;
+use crate::data_structure::{BasePacket, FlowStream, MetadataWrapper};
+use crate::features::{FeatureSet, Granularity};
+use crate::labels::{Label, LabelError, LabelRegistry};
+use crate::normalization::Normalization;
+use crate::parse_data::ParseError;
+use crate::query::FlowQuery;
 use burn::{
-    data::{
-        dataloader::batcher::Batcher,
-        dataset::{
-            transform::{PartialDataset, ShuffledDataset},
-            Dataset,
-        },
-    },
+    data::{dataloader::batcher::Batcher, dataset::Dataset},
     prelude::*,
 };
-use itertools::Itertools;
-use rayon::prelude::*;
+use chrono::TimeDelta;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
 use std::sync::Arc;
 
+/// One labelled flow: a cheap handle into the wrappers a [`NetworkDataset`]
+/// shares, so no packet vector is copied.
+#[derive(Clone, Debug)]
+pub struct FlowItem {
+    data: Arc<Vec<MetadataWrapper>>,
+    wrapper: usize,
+    flow: usize,
+}
+
+impl FlowItem {
+    pub fn label(&self) -> &Label {
+        &self.data[self.wrapper].label
+    }
+
+    pub fn flow(&self) -> &IpProtocol {
+        &self.data[self.wrapper].all_packets[self.flow]
+    }
+}
+
+/// A dataset of single flows. `index` maps each position to a wrapper and a
+/// flow within it, so splits and shuffles only reorder that index.
 #[derive(Clone)]
-pub struct NetworkDataset(pub Arc<Vec<MetadataWrapper>>);
-impl Dataset<MetadataWrapper> for NetworkDataset {
-    fn get(&self, index: usize) -> Option<MetadataWrapper> {
-        Some(self.0.get(index)?.to_owned())
+pub struct NetworkDataset {
+    data: Arc<Vec<MetadataWrapper>>,
+    index: Arc<Vec<(usize, usize)>>,
+}
+
+impl Dataset<FlowItem> for NetworkDataset {
+    fn get(&self, index: usize) -> Option<FlowItem> {
+        let &(wrapper, flow) = self.index.get(index)?;
+        Some(FlowItem {
+            data: self.data.clone(),
+            wrapper,
+            flow,
+        })
     }
 
     fn len(&self) -> usize {
-        self.0.iter().map(|data| data.all_packets.len()).sum()
+        self.index.len()
     }
 }
 
 impl NetworkDataset {
-    fn new(data: Vec<MetadataWrapper>) -> Self {
-        Self(Arc::new(data))
-    }
-    pub fn split(self, train_ratio: f32, seed: &mut rand::rngs::StdRng) -> (Self, Self) {
-        let total_len = self.len();
-        let train_len = (total_len as f32 * train_ratio) as usize;
-
-        // Shuffle the dataset
-        let shuffled = ShuffledDataset::new(self, seed);
-
-        let train_data: Vec<_> = (0..train_len).filter_map(|i| shuffled.get(i)).collect();
-        let valid_data: Vec<_> = (train_len..total_len)
-            .filter_map(|i| shuffled.get(i))
-            .collect();
-        (
-            NetworkDataset(train_data.into()),
-            NetworkDataset(valid_data.into()),
-        )
+    pub fn new(data: Vec<MetadataWrapper>) -> Self {
+        let index = data
+            .iter()
+            .enumerate()
+            .flat_map(|(wrapper, data)| {
+                (0..data.all_packets.len()).map(move |flow| (wrapper, flow))
+            })
+            .collect::<Vec<_>>();
+        Self {
+            data: Arc::new(data),
+            index: Arc::new(index),
+        }
+    }
+
+    /// Keeps only the flows `query` selects.
+    pub fn with_query(mut data: Vec<MetadataWrapper>, query: &FlowQuery) -> Self {
+        query.retain(&mut data);
+        Self::new(data)
+    }
+}
+
+/// What has to stay on one side of a split, so related flows never end up
+/// in training and evaluation at once.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum SplitGrouping {
+    /// Every flow on its own.
+    #[default]
+    Flow,
+    /// Flows between the same two addresses, standing in for one capture
+    /// session.
+    Session,
+    /// Flows starting within the same block of time.
+    TimeBlock(TimeDelta),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum GroupKey {
+    Flow(usize),
+    Session(IpAddr, IpAddr),
+    TimeBlock(i64),
+}
+
+impl SplitGrouping {
+    /// Flows without the addresses or the start time a grouping needs are
+    /// not related to anything and get a group of their own.
+    fn key(self, position: usize, flow: &IpProtocol) -> GroupKey {
+        let key = match self {
+            SplitGrouping::Flow => None,
+            SplitGrouping::Session => {
+                let (source, destination) = flow.endpoints();
+                source
+                    .address
+                    .zip(destination.address)
+                    .map(|(source, destination)| {
+                        GroupKey::Session(source.min(destination), source.max(destination))
+                    })
+            }
+            SplitGrouping::TimeBlock(block) => {
+                let packets: Vec<&BasePacket> = flow.into();
+                let start = packets.iter().map(|packet| packet.timestamp_start).min();
+                let block = block.num_milliseconds().max(1);
+                start.map(|start| GroupKey::TimeBlock(start.timestamp_millis().div_euclid(block)))
+            }
+        };
+        key.unwrap_or(GroupKey::Flow(position))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct ClassCounts {
+    pub train: usize,
+    pub valid: usize,
+    pub test: usize,
+}
+
+/// Flows per split for every label, keyed by its `tunnel application` name.
+#[derive(Debug, Default, Serialize)]
+pub struct SplitReport {
+    pub classes: BTreeMap<String, ClassCounts>,
+}
+
+pub struct DatasetSplits {
+    pub train: NetworkDataset,
+    pub valid: NetworkDataset,
+    pub test: NetworkDataset,
+    pub report: SplitReport,
+}
+
+/// Gives every label its share of all three splits however rare it is.
+/// Groups of [`SplitGrouping`] are formed over all labels and assigned whole,
+/// each to the split its labels are furthest below their targets in, so a
+/// session never ends up in two splits even when its flows carry different
+/// labels. A label with fewer groups than splits leaves some of them empty.
+#[derive(Clone, Copy, Debug)]
+pub struct StratifiedSplitter {
+    train_ratio: f32,
+    valid_ratio: f32,
+    grouping: SplitGrouping,
+}
+
+impl StratifiedSplitter {
+    /// The test split gets whatever `train_ratio` and `valid_ratio` leave.
+    pub fn new(train_ratio: f32, valid_ratio: f32) -> Self {
+        Self {
+            train_ratio,
+            valid_ratio,
+            grouping: SplitGrouping::default(),
+        }
+    }
+
+    pub fn with_grouping(mut self, grouping: SplitGrouping) -> Self {
+        self.grouping = grouping;
+        self
+    }
+
+    pub fn split(&self, dataset: &NetworkDataset, rng: &mut StdRng) -> DatasetSplits {
+        let mut groups: BTreeMap<GroupKey, Vec<(usize, usize)>> = BTreeMap::new();
+        let mut totals: BTreeMap<&Label, usize> = BTreeMap::new();
+        for (position, &(wrapper, flow)) in dataset.index.iter().enumerate() {
+            let data = &dataset.data[wrapper];
+            *totals.entry(&data.label).or_default() += 1;
+            groups
+                .entry(self.grouping.key(position, &data.all_packets[flow]))
+                .or_default()
+                .push((wrapper, flow));
+        }
+
+        let ratios = [
+            self.train_ratio,
+            self.valid_ratio,
+            (1.0 - self.train_ratio - self.valid_ratio).max(0.0),
+        ];
+        let mut counts: BTreeMap<&Label, [usize; 3]> =
+            totals.keys().map(|&label| (label, [0; 3])).collect();
+        let mut splits: [Vec<(usize, usize)>; 3] = Default::default();
+        let mut groups = groups.into_values().collect::<Vec<_>>();
+        groups.shuffle(rng);
+        for group in groups {
+            let mut members: BTreeMap<&Label, usize> = BTreeMap::new();
+            for &(wrapper, _) in &group {
+                *members.entry(&dataset.data[wrapper].label).or_default() += 1;
+            }
+            // How far each label falls short of its share, weighted by the
+            // flows of that label the group would add
+            let deficit = |split: usize| {
+                members
+                    .iter()
+                    .map(|(label, &flows)| {
+                        let share = counts[label][split] as f32 / totals[label] as f32;
+                        flows as f32 * (ratios[split] - share)
+                    })
+                    .sum::<f32>()
+            };
+            let split = (0..3)
+                .max_by(|&a, &b| deficit(a).total_cmp(&deficit(b)).then(b.cmp(&a)))
+                .unwrap_or_default();
+            for (label, flows) in members {
+                counts.get_mut(label).unwrap()[split] += flows;
+            }
+            splits[split].extend(group);
+        }
+
+        let mut report = SplitReport::default();
+        for (label, [train, valid, test]) in counts {
+            report
+                .classes
+                .insert(label.to_string(), ClassCounts { train, valid, test });
+        }
+
+        let [train, valid, test] = splits.map(|index| NetworkDataset {
+            data: dataset.data.clone(),
+            index: Arc::new(index),
+        });
+        DatasetSplits {
+            train,
+            valid,
+            test,
+            report,
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct NetworkTrafficBatcher<B: Backend> {
     device: B::Device,
+    labels: Arc<LabelRegistry>,
+    features: FeatureSet,
+    normalization: Option<Arc<Normalization>>,
+    sequence_length: usize,
 }
 
+/// Every flow as its first `N` packets with `F` features each.
 #[derive(Clone, Debug)]
-pub struct NetworkTrafficBatch<B: Backend> {
-    pub inputs: Tensor<B, 1>,
+pub struct FlowSequenceBatch<B: Backend> {
+    /// `[B, N, F]`, zero where a flow has fewer than `N` packets.
+    pub inputs: Tensor<B, 3>,
+    /// `[B, N]`, true for the packets that are there.
+    pub mask: Tensor<B, 2, Bool>,
+    /// `[B]` application indices.
     pub targets: Tensor<B, 1, Int>,
 }
 
+#[derive(Debug)]
+pub enum BatchError {
+    Parse(ParseError),
+    /// A flow's label has no class in the batcher's registry.
+    Label(LabelError),
+    /// See [`NetworkTrafficBatcher::with_normalization`].
+    MissingNormalization,
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Parse(err) => write!(f, "{err}"),
+            BatchError::Label(err) => write!(f, "{err}"),
+            BatchError::MissingNormalization => {
+                write!(f, "the batcher has no normalization statistics")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl From<ParseError> for BatchError {
+    fn from(err: ParseError) -> Self {
+        BatchError::Parse(err)
+    }
+}
+
+impl From<LabelError> for BatchError {
+    fn from(err: LabelError) -> Self {
+        BatchError::Label(err)
+    }
+}
+
 impl<B: Backend> NetworkTrafficBatcher<B> {
     pub fn new(device: B::Device) -> Self {
-        Self { device }
-    }
-
-    pub fn min_max_norm<const D: usize>(&self, inp: Tensor<B, D>) -> Tensor<B, D> {
-        let min = inp.clone().min_dim(0);
-        let max = inp.clone().max_dim(0);
-        (inp.clone() - min.clone()).div(max - min)
-    }
-}
-
-impl<B: Backend> Batcher<MetadataWrapper, NetworkTrafficBatch<B>> for NetworkTrafficBatcher<B> {
-    fn batch(&self, items: Vec<MetadataWrapper>) -> NetworkTrafficBatch<B> {
-        let mut inputs: Vec<Tensor<B, 1>> = Vec::new();
-
-        for item in items.iter() {
-            for data in &item.all_packets {
-                if let IpProtocol::Tcp(data) = data {
-                    inputs.extend(
-                        data.packets
-                            .par_iter()
-                            .map(|packet| {
-                                let mut inputs = get_base_float(
-                                    data.port_source,
-                                    data.port_destination,
-                                    &packet.base,
-                                );
-                                /*inputs.extend([
-                                    packet.tcp_flags as f32,
-                                    packet.tcp_header_len as f32,
-                                    packet.tcp_acknowledgment_number as f32,
-                                    packet.tcp_header_len as f32,
-                                ]);*/
-                                Tensor::<B, 1>::from_floats(&*inputs, &self.device)
-                            })
-                            .collect::<Vec<_>>(),
-                    )
-                } else {
-                    let data = match data {
-                        IpProtocol::Udp(data) | IpProtocol::Gre(data) | IpProtocol::Icmp(data) => {
-                            data
-                        }
-                        IpProtocol::Tcp(_) => unreachable!(),
-                    };
-                    let things = data
-                        .packets
-                        .par_iter()
-                        .map(|packet| {
-                            Tensor::<B, 1>::from_floats(
-                                &*get_base_float(data.port_source, data.port_destination, packet),
-                                &self.device,
-                            )
-                        })
-                        .collect::<Vec<_>>();
-                    inputs.extend(things);
+        Self {
+            device,
+            labels: Arc::default(),
+            features: FeatureSet::default(),
+            normalization: None,
+            sequence_length: 32,
+        }
+    }
+
+    /// The registry targets are numbered by, the built-in labels by default.
+    pub fn with_labels(mut self, labels: Arc<LabelRegistry>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// What each flow is turned into, [`FeatureSet::default`] unless set.
+    pub fn with_features(mut self, features: FeatureSet) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// How many packets of each flow a [`FlowSequenceBatch`] holds, 32 by
+    /// default. Longer flows are cut and shorter ones padded.
+    pub fn with_sequence_length(mut self, sequence_length: usize) -> Self {
+        self.sequence_length = sequence_length;
+        self
+    }
+
+    /// Statistics fitted on the training split. Every batcher of one model,
+    /// including the ones used for inference, needs the same statistics, and
+    /// batching fails without any.
+    pub fn with_normalization(mut self, normalization: Arc<Normalization>) -> Self {
+        self.normalization = Some(normalization);
+        self
+    }
+}
+
+impl<B: Backend> NetworkTrafficBatcher<B> {
+    fn normalization(&self) -> Result<&Normalization, BatchError> {
+        self.normalization
+            .as_deref()
+            .ok_or(BatchError::MissingNormalization)
+    }
+
+    fn flow_rows(&self, flow: &IpProtocol) -> Result<Vec<Vec<f64>>, BatchError> {
+        let normalization = self.normalization()?;
+        let mut rows = self.features.extract(flow);
+        rows.iter_mut().for_each(|row| normalization.apply(row));
+        Ok(rows)
+    }
+
+    /// The application and tunnel index of `label`, see
+    /// [`LabelRegistry::target`].
+    fn flow_target(&self, label: &Label) -> Result<Tensor<B, 1, Int>, LabelError> {
+        let target = self.labels.target(label)?;
+        Ok(Tensor::<B, 1, Int>::from_ints(
+            target.map(|index| index as i32),
+            &self.device,
+        ))
+    }
+
+    /// [`Batcher::batch`] has no way to report an error, so a dataset is
+    /// checked for labels outside the registry before it is batched.
+    pub fn check_labels(&self, dataset: &NetworkDataset) -> Result<(), LabelError> {
+        dataset
+            .data
+            .iter()
+            .try_for_each(|wrapper| self.labels.target(&wrapper.label).map(|_| ()))
+    }
+
+    /// Panics unless the features are per-packet.
+    fn sequence_batch(
+        &self,
+        flows: &[(&Label, &IpProtocol)],
+    ) -> Result<FlowSequenceBatch<B>, BatchError> {
+        assert_eq!(
+            self.features.granularity(),
+            Granularity::Packet,
+            "packet sequences need per-packet features"
+        );
+        let (length, width) = (self.sequence_length, self.features.width());
+        let mut inputs = vec![0f32; flows.len() * length * width];
+        let mut mask = vec![false; flows.len() * length];
+        for (position, (_, flow)) in flows.iter().enumerate() {
+            let rows = self.flow_rows(flow)?;
+            for (packet, row) in rows.into_iter().take(length).enumerate() {
+                let start = (position * length + packet) * width;
+                for (input, value) in inputs[start..start + width].iter_mut().zip(row) {
+                    *input = value as f32;
                 }
+                mask[position * length + packet] = true;
             }
         }
+        // Only the application index, the model has one output per application
+        let targets = flows
+            .iter()
+            .map(|(label, _)| Ok(self.flow_target(label)?.narrow(0, 0, 1)))
+            .collect::<Result<Vec<_>, LabelError>>()?;
+        Ok(FlowSequenceBatch {
+            inputs: Tensor::from_data(
+                TensorData::new(inputs, [flows.len(), length, width]),
+                &self.device,
+            ),
+            mask: Tensor::from_data(TensorData::new(mask, [flows.len(), length]), &self.device),
+            targets: Tensor::cat(targets, 0),
+        })
+    }
 
-        let inputs = Tensor::cat(inputs, 0);
-        let inputs = self.min_max_norm(inputs);
+    /// Turns the flows of a [`FlowStream`] that `query` selects into
+    /// batches of up to `batch_size` flows, reading each batch's flows only
+    /// when it is built, so no more than one batch is held at a time.
+    pub fn batch_stream<'a>(
+        &'a self,
+        stream: FlowStream,
+        query: &'a FlowQuery,
+        batch_size: usize,
+    ) -> Result<impl Iterator<Item = Result<FlowSequenceBatch<B>, BatchError>> + 'a, BatchError>
+    {
+        self.normalization()?;
+        self.labels.target(&stream.label)?;
+        let label = stream.label.clone();
+        let selected = query.matches_label(&label);
+        let mut flows =
+            stream.filter(move |flow| flow.as_ref().map_or(true, |flow| query.matches(flow)));
+        Ok(std::iter::from_fn(move || {
+            if !selected {
+                return None;
+            }
+            match flows
+                .by_ref()
+                .take(batch_size)
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(batch) if batch.is_empty() => None,
+                Ok(batch) => {
+                    let batch = batch.iter().map(|flow| (&label, flow)).collect::<Vec<_>>();
+                    Some(self.sequence_batch(&batch))
+                }
+                Err(err) => Some(Err(err.into())),
+            }
+        }))
+    }
+}
 
-        let targets = items
+impl<B: Backend> Batcher<FlowItem, FlowSequenceBatch<B>> for NetworkTrafficBatcher<B> {
+    /// Panics unless the features are per-packet, without normalization
+    /// statistics and on labels [`NetworkTrafficBatcher::check_labels`]
+    /// rejects.
+    fn batch(&self, items: Vec<FlowItem>) -> FlowSequenceBatch<B> {
+        let flows = items
             .iter()
-            .flat_map(|item| {
-                item.all_packets
-                    .iter()
-                    .map(|_| {
-                        Tensor::<B, 1, Int>::from_ints(
-                            [
-                                item.data_category as u8,
-                                match item.encryption {
-                                    Encryption::VPN(vpn) => vpn as u8 + 1,
-                                    Encryption::NonVPN => 0,
-                                },
-                            ],
-                            &self.device,
-                        )
+            .map(|item| (item.label(), item.flow()))
+            .collect::<Vec<_>>();
+        self.sequence_batch(&flows)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::{DataCategory, Encryption, PacketDirection, VPN};
+    use crate::data_structure::stream_some_data;
+    use crate::data_structure::{get_all_data, Data};
+    use crate::dataset_layout::DatasetLayout;
+    use crate::normalization::Scaling;
+    use crate::test_support::{fixture_layout, scratch_dir};
+    use burn::backend::NdArray;
+    use chrono::DateTime;
+    use rand::SeedableRng;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    fn fixture_dataset(name: &str) -> (NetworkDataset, Arc<LabelRegistry>) {
+        let layout = fixture_layout(name);
+        let mut data = get_all_data(&layout);
+        data.sort_by(|a, b| a.label.cmp(&b.label));
+        (NetworkDataset::new(data), layout.labels().clone())
+    }
+
+    fn fitted(features: &FeatureSet, dataset: &NetworkDataset) -> Arc<Normalization> {
+        Arc::new(Normalization::fit(features, dataset, |_| Scaling::MinMax).unwrap())
+    }
+
+    #[test]
+    fn batches_keep_the_labels_of_their_flows() {
+        let (dataset, labels) = fixture_dataset("batch-targets");
+        let items = dataset.iter().collect::<Vec<_>>();
+        let flows = items
+            .iter()
+            .map(|item| {
+                let (_, destination) = item.flow().endpoints();
+                (item.label().to_string(), destination.port)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flows,
+            [
+                ("OpenVPN SSH".to_string(), 22),
+                ("OpenVPN SSH".to_string(), 1194),
+                ("NonVPN Mail".to_string(), 993),
+            ]
+        );
+
+        let batcher = NetworkTrafficBatcher::<NdArray>::new(Default::default())
+            .with_labels(labels.clone())
+            .with_normalization(fitted(&FeatureSet::default(), &dataset));
+        batcher.check_labels(&dataset).unwrap();
+        let expected = items
+            .iter()
+            .map(|item| labels.target(item.label()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(expected[0], expected[1]);
+        assert_ne!(expected[1], expected[2]);
+
+        let batch: FlowSequenceBatch<NdArray> = batcher.batch(items);
+        assert_eq!(
+            batch.targets.into_data().to_vec::<i64>().unwrap(),
+            expected
+                .iter()
+                .map(|[application, _]| *application as i64)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// A single packet flow between `10.0.0.<session>` and `10.0.1.1`,
+    /// starting `seconds` after the epoch.
+    fn session_flow(session: u8, seconds: i64) -> IpProtocol {
+        let timestamp = DateTime::UNIX_EPOCH + TimeDelta::seconds(seconds);
+        IpProtocol::from_number(
+            IpProtocol::GRE,
+            Data {
+                ip_destination: Some([10, 0, 1, 1].into()),
+                ip_source: Some([10, 0, 0, session].into()),
+                port_destination: 0,
+                port_source: 0,
+                packets: vec![BasePacket {
+                    bytes: 100,
+                    direction: PacketDirection::Outgoing,
+                    ip_header_length: 20,
+                    packets: 1,
+                    timestamp_start: timestamp,
+                    timestamp_end: timestamp,
+                }],
+            },
+        )
+    }
+
+    fn labelled(category: DataCategory, flows: Vec<IpProtocol>) -> MetadataWrapper {
+        MetadataWrapper {
+            label: Label::builtin(&Encryption::VPN(VPN::OpenVPN), category),
+            all_packets: flows,
+            skipped_flows: 0,
+        }
+    }
+
+    fn counts(report: &SplitReport, label: &str) -> [usize; 3] {
+        let counts = report.classes[label];
+        [counts.train, counts.valid, counts.test]
+    }
+
+    #[test]
+    fn indexes_every_flow_of_every_wrapper() {
+        let flows = |seconds: std::ops::Range<i64>| seconds.map(|second| session_flow(0, second));
+        let dataset = NetworkDataset::new(vec![
+            labelled(DataCategory::SSH, flows(0..3).collect()),
+            labelled(DataCategory::Mail, vec![]),
+            labelled(DataCategory::Meet, flows(3..4).collect()),
+            labelled(DataCategory::Streaming, flows(4..6).collect()),
+        ]);
+        assert_eq!(dataset.len(), 6);
+        let items = (0..dataset.len())
+            .map(|index| {
+                let item = dataset.get(index).unwrap();
+                let packets: Vec<&BasePacket> = item.flow().into();
+                (
+                    item.label().application.to_string(),
+                    packets[0].timestamp_start.timestamp(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            items,
+            [
+                ("SSH".to_string(), 0),
+                ("SSH".to_string(), 1),
+                ("SSH".to_string(), 2),
+                ("Meet".to_string(), 3),
+                ("Streaming".to_string(), 4),
+                ("Streaming".to_string(), 5),
+            ]
+        );
+        assert!(dataset.get(dataset.len()).is_none());
+        assert_eq!(dataset.iter().count(), dataset.len());
+
+        let empty = NetworkDataset::new(vec![labelled(DataCategory::Mail, vec![])]);
+        assert_eq!(empty.len(), 0);
+        assert!(empty.get(0).is_none());
+    }
+
+    #[test]
+    fn splits_every_label_by_the_ratios() {
+        let dataset = NetworkDataset::new(vec![
+            labelled(
+                DataCategory::SSH,
+                (0..100).map(|flow| session_flow(0, flow)).collect(),
+            ),
+            labelled(
+                DataCategory::Mail,
+                (0..10).map(|flow| session_flow(0, flow)).collect(),
+            ),
+        ]);
+        let mut rng = StdRng::seed_from_u64(0);
+        let splits = StratifiedSplitter::new(0.8, 0.1).split(&dataset, &mut rng);
+
+        assert_eq!(counts(&splits.report, "OpenVPN SSH"), [80, 10, 10]);
+        assert_eq!(counts(&splits.report, "OpenVPN Mail"), [8, 1, 1]);
+        assert_eq!(
+            [splits.train.len(), splits.valid.len(), splits.test.len()],
+            [88, 11, 11]
+        );
+        // The report counts what the splits hold
+        for (split, column) in [(&splits.train, 0), (&splits.valid, 1), (&splits.test, 2)] {
+            let mail = split
+                .iter()
+                .filter(|item| &*item.label().application == "Mail")
+                .count();
+            assert_eq!(mail, counts(&splits.report, "OpenVPN Mail")[column]);
+        }
+
+        let json = serde_json::to_value(&splits.report).unwrap();
+        assert_eq!(json["classes"]["OpenVPN Mail"]["valid"], 1);
+    }
+
+    #[test]
+    fn groups_never_span_two_splits() {
+        // Twenty sessions, each with flows of both labels an hour apart
+        let flows = |offset: i64| {
+            (0..20)
+                .flat_map(|session| {
+                    (0..3).map(move |flow| {
+                        session_flow(session, offset + 3600 * i64::from(session) + flow)
                     })
-                    .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let dataset = NetworkDataset::new(vec![
+            labelled(DataCategory::SSH, flows(0)),
+            labelled(DataCategory::Mail, flows(10)),
+        ]);
+
+        for grouping in [
+            SplitGrouping::Session,
+            SplitGrouping::TimeBlock(TimeDelta::hours(1)),
+        ] {
+            let mut rng = StdRng::seed_from_u64(7);
+            let splits = StratifiedSplitter::new(0.6, 0.2)
+                .with_grouping(grouping)
+                .split(&dataset, &mut rng);
+            let keys = |split: &NetworkDataset| {
+                split
+                    .iter()
+                    .map(|item| grouping.key(0, item.flow()))
+                    .collect::<BTreeSet<_>>()
+            };
+            let [train, valid, test] = [&splits.train, &splits.valid, &splits.test].map(keys);
+            assert_eq!(train.len() + valid.len() + test.len(), 20, "{grouping:?}");
+            assert!(
+                train.is_disjoint(&valid) && train.is_disjoint(&test) && valid.is_disjoint(&test)
+            );
+            // Every session holds both labels in equal parts, so both follow
+            // the ratios to the group
+            assert_eq!(
+                counts(&splits.report, "OpenVPN SSH"),
+                [36, 12, 12],
+                "{grouping:?}"
+            );
+            assert_eq!(
+                counts(&splits.report, "OpenVPN Mail"),
+                [36, 12, 12],
+                "{grouping:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn flows_without_group_fields_are_split_alone() {
+        // JSON flows usually come without addresses
+        let root = scratch_dir("split-json");
+        let flow = |port: u16| {
+            format!(
+                r#"{{"ip_proto": "tcp", "port_src": {port}, "port_dst": 22, "x_packets": [{{"bytes": "60", "ip_header_len": "20", "packets": "1", "tcp_ack_number": "0", "tcp_header_len": "40", "tcp_flags": "00000010", "tcp_seq_number": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"}}]}}"#
+            )
+        };
+        let flows = (50000..50004).map(flow).collect::<Vec<_>>();
+        fs::create_dir_all(root.join("VPN/OpenVPN")).unwrap();
+        fs::write(
+            root.join("VPN/OpenVPN/ssh.json"),
+            format!("[{}]", flows.join(",")),
+        )
+        .unwrap();
+        let layout = DatasetLayout::scan(&root).with_cache_dir(None);
+        let dataset = NetworkDataset::new(get_all_data(&layout));
+        assert_eq!(dataset.len(), 4);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let splits = StratifiedSplitter::new(0.5, 0.25)
+            .with_grouping(SplitGrouping::Session)
+            .split(&dataset, &mut rng);
+        assert_eq!(counts(&splits.report, "OpenVPN SSH"), [2, 1, 1]);
+
+        let empty = IpProtocol::from_number(
+            IpProtocol::GRE,
+            Data {
+                ip_destination: None,
+                ip_source: None,
+                port_destination: 0,
+                port_source: 0,
+                packets: vec![],
+            },
+        );
+        let grouping = SplitGrouping::TimeBlock(TimeDelta::hours(1));
+        assert_ne!(grouping.key(0, &empty), grouping.key(1, &empty));
+    }
+
+    #[test]
+    fn sequence_batches_pad_and_truncate_flows() {
+        let (dataset, labels) = fixture_dataset("sequence-batch");
+        let items = dataset.iter().collect::<Vec<_>>();
+        let features = FeatureSet::default();
+        let width = features.width();
+        // The OpenVPN SSH flows have three and two packets, the mail flow two
+        let normalization = fitted(&features, &dataset);
+        let rows = items
+            .iter()
+            .map(|item| {
+                let mut rows = features.extract(item.flow());
+                rows.iter_mut().for_each(|row| normalization.apply(row));
+                rows
             })
-            .collect();
-
-        let targets = Tensor::cat(targets, 0);
-        NetworkTrafficBatch { inputs, targets }
-    }
-}
-
-fn get_base_float(port_source: u16, port_destination: u16, packet: &BasePacket) -> Vec<f32> {
-    vec![
-        port_source as f32,
-        port_destination as f32,
-        packet.packets as f32,
-        packet.packet_duration.num_milliseconds() as f32,
-        packet.ip_header_length as f32,
-        match packet.direction {
-            PacketDirection::Outgoing => 1.,
-            PacketDirection::Incoming => 0.,
-        },
-        packet.bytes as f32,
-    ]
+            .collect::<Vec<_>>();
+        assert_eq!(rows.iter().map(Vec::len).collect::<Vec<_>>(), [3, 2, 2]);
+
+        for length in [2, 4] {
+            let batcher = NetworkTrafficBatcher::<NdArray>::new(Default::default())
+                .with_labels(labels.clone())
+                .with_features(features.clone())
+                .with_normalization(normalization.clone())
+                .with_sequence_length(length);
+            let batch: FlowSequenceBatch<NdArray> = batcher.batch(items.clone());
+            assert_eq!(batch.inputs.dims(), [3, length, width]);
+            assert_eq!(batch.mask.dims(), [3, length]);
+            assert_eq!(batch.targets.dims(), [3]);
+
+            let inputs = batch.inputs.into_data().to_vec::<f32>().unwrap();
+            let mask = batch.mask.into_data().to_vec::<bool>().unwrap();
+            for (flow, rows) in rows.iter().enumerate() {
+                for packet in 0..length {
+                    let start = (flow * length + packet) * width;
+                    let input = &inputs[start..start + width];
+                    match rows.get(packet) {
+                        Some(row) => {
+                            assert!(mask[flow * length + packet]);
+                            let row = row.iter().map(|&value| value as f32).collect::<Vec<_>>();
+                            assert_eq!(input, row.as_slice());
+                        }
+                        None => {
+                            assert!(!mask[flow * length + packet]);
+                            assert!(input.iter().all(|&value| value == 0.));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn streams_batches_of_the_selected_flows() {
+        let layout = fixture_layout("batch-stream");
+        let ssh = layout.labels().find("OpenVPN", "SSH").unwrap();
+        let (dataset, labels) = fixture_dataset("batch-stream-data");
+        let features = FeatureSet::default();
+        let everything = FlowQuery::new();
+        let batcher = NetworkTrafficBatcher::<NdArray>::new(Default::default())
+            .with_labels(labels)
+            .with_sequence_length(4);
+        let stream = stream_some_data(&layout, &ssh).unwrap();
+        assert!(matches!(
+            batcher.batch_stream(stream, &everything, 1),
+            Err(BatchError::MissingNormalization)
+        ));
+
+        let batcher = batcher.with_normalization(fitted(&features, &dataset));
+        let stream = stream_some_data(&layout, &ssh).unwrap();
+        let batches = batcher
+            .batch_stream(stream, &everything, 1)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 2);
+        for batch in batches {
+            assert_eq!(batch.inputs.dims(), [1, 4, features.width()]);
+            let inputs = batch.inputs.into_data().to_vec::<f32>().unwrap();
+            assert!(inputs.iter().all(|value| (0.0..=1.0).contains(value)));
+        }
+
+        let stream = stream_some_data(&layout, &ssh).unwrap();
+        let udp = FlowQuery::new().with_protocol(IpProtocol::UDP);
+        let batches = batcher
+            .batch_stream(stream, &udp, 64)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].mask.dims(), [1, 4]);
+
+        let stream = stream_some_data(&layout, &ssh).unwrap();
+        let mail = FlowQuery::new().with_category(DataCategory::Mail);
+        assert_eq!(batcher.batch_stream(stream, &mail, 64).unwrap().count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "the batcher has no normalization statistics")]
+    fn batches_without_statistics_panic() {
+        let (dataset, labels) = fixture_dataset("batch-raw");
+        let batcher = NetworkTrafficBatcher::<NdArray>::new(Default::default()).with_labels(labels);
+        let _: FlowSequenceBatch<NdArray> = batcher.batch(dataset.iter().collect());
+    }
 }
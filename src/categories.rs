@@ -1,20 +1,38 @@
-use crate::data_structure::{BasePacket, Data, TcpPacket};
-use burn::prelude::Backend;
-use burn::tensor::TensorKind;
+use crate::data_structure::{BasePacket, Data, Endpoint, FlowKey, TcpPacket, UdpPacket};
 use rayon::prelude::*;
-use strum_macros::{Display, EnumIter};
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
 #[derive(Clone, Debug, PartialEq, Hash, Eq, Display)]
 pub enum Encryption {
     VPN(VPN),
     NonVPN,
 }
+impl Encryption {
+    pub fn all() -> impl Iterator<Item = Encryption> {
+        VPN::iter().map(Encryption::VPN).chain([Encryption::NonVPN])
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("NonVPN") || s.eq_ignore_ascii_case("Non VPN") {
+            return Ok(Encryption::NonVPN);
+        }
+        VPN::from_str(s).map(Encryption::VPN)
+    }
+}
+
 #[derive(EnumIter)]
 pub enum EncryptionRepresentation {
     VPN,
     NonVPN,
 }
 #[allow(clippy::enum_variant_names)]
-#[derive(EnumIter, Copy, Clone, Debug, PartialEq, Hash, Eq, Display)]
+#[derive(EnumIter, EnumString, Copy, Clone, Debug, PartialEq, Hash, Eq, Display)]
+#[strum(ascii_case_insensitive)]
 pub enum VPN {
     L2TP,
     L2TPIP,
@@ -23,7 +41,8 @@ pub enum VPN {
     SSTP,
     WireGuard,
 }
-#[derive(EnumIter, Copy, Clone, Debug, Hash, PartialEq, Eq, Display)]
+#[derive(EnumIter, EnumString, Copy, Clone, Debug, Hash, PartialEq, Eq, Display)]
+#[strum(ascii_case_insensitive)]
 pub enum DataCategory {
     Mail,
     Meet,
@@ -32,29 +51,153 @@ pub enum DataCategory {
     Streaming,
 }
 
-#[derive(Clone, Debug)]
+/// Direction relative to the capturing host. Forward traffic is
+/// [`PacketDirection::Outgoing`], backward traffic is
+/// [`PacketDirection::Incoming`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, Display)]
+#[strum(ascii_case_insensitive, serialize_all = "lowercase")]
 pub enum PacketDirection {
+    #[strum(serialize = "outgoing", serialize = "out", serialize = "forward")]
     Outgoing,
+    #[strum(serialize = "incoming", serialize = "in", serialize = "backward")]
     Incoming,
+    /// A zero-length packet without a sign or a direction field.
+    Unknown,
 }
 #[derive(Clone, Debug)]
 pub enum IpProtocol {
-    Udp(Data<BasePacket>),
+    Udp(Data<UdpPacket>),
     Tcp(Data<TcpPacket>),
     Gre(Data<BasePacket>),
     Icmp(Data<BasePacket>),
+    Esp(Data<BasePacket>),
+    Ah(Data<BasePacket>),
+    Icmpv6(Data<BasePacket>),
+    /// Any other protocol, with its IANA number.
+    Other(u8, Data<BasePacket>),
 }
 impl<'a> From<&'a IpProtocol> for Vec<&'a BasePacket> {
     fn from(packet: &'a IpProtocol) -> Self {
         match packet {
-            IpProtocol::Udp(data) | IpProtocol::Gre(data) | IpProtocol::Icmp(data) => {
-                data.packets.par_iter().collect::<Vec<_>>()
-            }
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => data.packets.par_iter().collect::<Vec<_>>(),
             IpProtocol::Tcp(data) => data
                 .packets
                 .par_iter()
                 .map(|packet| &packet.base)
                 .collect::<Vec<_>>(),
+            IpProtocol::Udp(data) => data
+                .packets
+                .par_iter()
+                .map(|packet| &packet.base)
+                .collect::<Vec<_>>(),
+        }
+    }
+}
+
+impl IpProtocol {
+    pub const ICMP: u8 = 1;
+    pub const TCP: u8 = 6;
+    pub const UDP: u8 = 17;
+    pub const GRE: u8 = 47;
+    pub const ESP: u8 = 50;
+    pub const AH: u8 = 51;
+    pub const ICMPV6: u8 = 58;
+    pub const SCTP: u8 = 132;
+
+    /// Wraps a flow without transport details in the variant for `number`.
+    /// UDP lengths are derived with [`UdpPacket::from_base`]. TCP flows carry
+    /// [`TcpPacket`]s and are built directly, so a TCP number here ends up in
+    /// [`IpProtocol::Other`].
+    pub fn from_number(number: u8, data: Data<BasePacket>) -> Self {
+        match number {
+            Self::ICMP => IpProtocol::Icmp(data),
+            Self::UDP => IpProtocol::Udp(Data {
+                ip_destination: data.ip_destination,
+                ip_source: data.ip_source,
+                port_destination: data.port_destination,
+                port_source: data.port_source,
+                packets: data.packets.into_iter().map(UdpPacket::from_base).collect(),
+            }),
+            Self::GRE => IpProtocol::Gre(data),
+            Self::ESP => IpProtocol::Esp(data),
+            Self::AH => IpProtocol::Ah(data),
+            Self::ICMPV6 => IpProtocol::Icmpv6(data),
+            number => IpProtocol::Other(number, data),
+        }
+    }
+
+    /// Looks up the number for a protocol name as written in the dataset.
+    pub fn number_from_name(name: &str) -> Option<u8> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "icmp" => Self::ICMP,
+            "tcp" => Self::TCP,
+            "udp" => Self::UDP,
+            "gre" => Self::GRE,
+            "esp" => Self::ESP,
+            "ah" => Self::AH,
+            "icmpv6" | "ipv6-icmp" => Self::ICMPV6,
+            "sctp" => Self::SCTP,
+            _ => return None,
+        })
+    }
+
+    /// The IANA protocol number carried in the IP header.
+    pub fn number(&self) -> u8 {
+        match self {
+            IpProtocol::Icmp(_) => Self::ICMP,
+            IpProtocol::Tcp(_) => Self::TCP,
+            IpProtocol::Udp(_) => Self::UDP,
+            IpProtocol::Gre(_) => Self::GRE,
+            IpProtocol::Esp(_) => Self::ESP,
+            IpProtocol::Ah(_) => Self::AH,
+            IpProtocol::Icmpv6(_) => Self::ICMPV6,
+            IpProtocol::Other(number, _) => *number,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IpProtocol::Udp(_) => "udp",
+            IpProtocol::Tcp(_) => "tcp",
+            IpProtocol::Gre(_) => "gre",
+            IpProtocol::Icmp(_) => "icmp",
+            IpProtocol::Esp(_) => "esp",
+            IpProtocol::Ah(_) => "ah",
+            IpProtocol::Icmpv6(_) => "icmpv6",
+            IpProtocol::Other(..) => "other",
+        }
+    }
+
+    /// Source and destination endpoints of the flow.
+    pub fn endpoints(&self) -> (Endpoint, Endpoint) {
+        match self {
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => (data.source(), data.destination()),
+            IpProtocol::Tcp(data) => (data.source(), data.destination()),
+            IpProtocol::Udp(data) => (data.source(), data.destination()),
+        }
+    }
+
+    pub fn flow_key(&self) -> FlowKey {
+        let number = self.number();
+        match self {
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => data.flow_key(number),
+            IpProtocol::Tcp(data) => data.flow_key(number),
+            IpProtocol::Udp(data) => data.flow_key(number),
         }
     }
 }
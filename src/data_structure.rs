@@ -1,91 +1,642 @@
-use crate::categories::{
-    DataCategory, Encryption, EncryptionRepresentation, IpProtocol, PacketDirection, ToPath, VPN,
-};
-use crate::parse_data::get_data;
-use chrono::TimeDelta;
+use crate::categories::{IpProtocol, PacketDirection};
+use crate::dataset_layout::DatasetLayout;
+use crate::flow_cache;
+use crate::labels::Label;
+use crate::parse_data::{DirectionConvention, FlowReader, ParseError, ParseMode, ParsedFile};
+use crate::parse_pcap::{get_pcap_data, is_capture};
+use chrono::{DateTime, TimeDelta, Utc};
 use rayon::prelude::*;
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Mutex;
-use strum::IntoEnumIterator;
 #[derive(Clone, Debug)]
 pub struct Data<IpProtocol: Clone + Debug> {
+    pub ip_destination: Option<IpAddr>,
+    pub ip_source: Option<IpAddr>,
     pub port_destination: u16,
     pub port_source: u16,
     pub packets: Vec<IpProtocol>,
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Endpoint {
+    pub address: Option<IpAddr>,
+    pub port: u16,
+}
+/// Identifies a flow independent of which side sent the first packet, so a
+/// conversation and its reverse produce the same key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FlowKey {
+    pub protocol: u8,
+    pub lower: Endpoint,
+    pub upper: Endpoint,
+}
 #[derive(Clone, Debug)]
 pub struct BasePacket {
-    pub bytes: u32,
+    /// Bytes on the IP layer, summed over the record for aggregated records.
+    pub bytes: u64,
     pub direction: PacketDirection,
-    pub ip_header_length: u8,
-    pub packets: u8,
-    pub packet_duration: TimeDelta,
+    /// Wider than the IPv4 maximum of 60 so IPv6 extension headers fit.
+    pub ip_header_length: u16,
+    /// How many packets the record stands for.
+    pub packets: u32,
+    pub timestamp_start: DateTime<Utc>,
+    pub timestamp_end: DateTime<Utc>,
+}
+bitflags::bitflags! {
+    /// The flag byte of the TCP header.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct TcpFlags: u8 {
+        const FIN = 0x01;
+        const SYN = 0x02;
+        const RST = 0x04;
+        const PSH = 0x08;
+        const ACK = 0x10;
+        const URG = 0x20;
+        const ECE = 0x40;
+        const CWR = 0x80;
+    }
 }
 #[derive(Clone, Debug)]
 pub struct TcpPacket {
     pub base: BasePacket,
     pub tcp_header_len: u16,
-    pub tcp_flags: u8,
+    pub tcp_flags: TcpFlags,
     pub tcp_acknowledgment_number: u32,
     pub tcp_sequence_number: u32,
 }
 #[derive(Clone, Debug)]
+pub struct UdpPacket {
+    pub base: BasePacket,
+    /// The length field of the UDP header, header included.
+    pub udp_length: u16,
+    pub payload_length: u16,
+}
+#[derive(Clone, Debug)]
 pub struct MetadataWrapper {
-    pub encryption: Encryption,
-    pub data_category: DataCategory,
+    pub label: Label,
     pub all_packets: Vec<IpProtocol>,
+    pub skipped_flows: usize,
 }
-pub fn get_all_data() -> Vec<MetadataWrapper> {
-    let all_data: Mutex<Vec<MetadataWrapper>> = Mutex::new(vec![]);
-    EncryptionRepresentation::iter().par_bridge().for_each(
-        |encryption_type| match encryption_type {
-            EncryptionRepresentation::VPN => VPN::iter().par_bridge().for_each(|vpn_type| {
-                DataCategory::iter().par_bridge().for_each(|data_category| {
-                    let path = format!("dataset/VPN/{}/{}", vpn_type.path(), data_category.path());
-                    let metadata = MetadataWrapper {
-                        encryption: Encryption::VPN(vpn_type),
-                        data_category,
-                        all_packets: get_data(path),
-                    };
-                    all_data.lock().unwrap().push(metadata)
-                })
-            }),
-            EncryptionRepresentation::NonVPN => {
-                DataCategory::iter().par_bridge().for_each(|data_category| {
-                    let path = format!("dataset/Non VPN/{}", data_category.path());
-                    let metadata = MetadataWrapper {
-                        encryption: Encryption::NonVPN,
-                        data_category,
-                        all_packets: get_data(path),
-                    };
-                    all_data.lock().unwrap().push(metadata)
-                })
+/// Walks every file of one [`Label`] flow by flow, opening each file only
+/// when the previous one is exhausted. Captures have no flow array to
+/// stream, so they are read whole when their turn comes.
+pub struct FlowStream {
+    pub label: Label,
+    files: std::vec::IntoIter<PathBuf>,
+    current: Option<FlowSource>,
+    convention: DirectionConvention,
+    skipped_flows: usize,
+}
+
+enum FlowSource {
+    Json(FlowReader<BufReader<File>>),
+    Capture(std::vec::IntoIter<IpProtocol>),
+}
+
+impl FlowSource {
+    fn open(path: PathBuf, convention: DirectionConvention) -> Result<Self, ParseError> {
+        if is_capture(&path) {
+            Ok(FlowSource::Capture(get_pcap_data(path)?.flows.into_iter()))
+        } else {
+            let reader = FlowReader::open(path, ParseMode::Lenient)?;
+            Ok(FlowSource::Json(
+                reader.with_direction_convention(convention),
+            ))
+        }
+    }
+
+    fn skipped_flows(&self) -> usize {
+        match self {
+            FlowSource::Json(reader) => reader.skipped_flows(),
+            FlowSource::Capture(_) => 0,
+        }
+    }
+}
+
+impl Iterator for FlowSource {
+    type Item = Result<IpProtocol, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            FlowSource::Json(reader) => reader.next(),
+            FlowSource::Capture(flows) => flows.next().map(Ok),
+        }
+    }
+}
+
+impl FlowStream {
+    pub fn new(label: Label, files: Vec<PathBuf>) -> Self {
+        Self {
+            label,
+            files: files.into_iter(),
+            current: None,
+            convention: DirectionConvention::default(),
+            skipped_flows: 0,
+        }
+    }
+
+    pub fn with_direction_convention(mut self, convention: DirectionConvention) -> Self {
+        self.convention = convention;
+        self
+    }
+
+    pub fn skipped_flows(&self) -> usize {
+        self.skipped_flows
+            + self
+                .current
+                .as_ref()
+                .map_or(0, |source| source.skipped_flows())
+    }
+}
+
+impl Iterator for FlowStream {
+    type Item = Result<IpProtocol, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(source) = &mut self.current {
+                match source.next() {
+                    Some(flow) => return Some(flow),
+                    None => {
+                        self.skipped_flows += source.skipped_flows();
+                        self.current = None;
+                    }
+                }
+            }
+            let path = self.files.next()?;
+            match FlowSource::open(path, self.convention) {
+                Ok(source) => self.current = Some(source),
+                Err(err) => return Some(Err(err)),
             }
-        },
-    );
+        }
+    }
+}
+
+impl MetadataWrapper {
+    pub fn from_stream(mut stream: FlowStream) -> Result<Self, ParseError> {
+        let all_packets = stream.by_ref().collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            skipped_flows: stream.skipped_flows(),
+            label: stream.label,
+            all_packets,
+        })
+    }
+}
+
+fn load_files(layout: &DatasetLayout, files: &[PathBuf]) -> Result<ParsedFile, ParseError> {
+    let mut parsed = ParsedFile::default();
+    for path in files {
+        let file =
+            flow_cache::load_or_parse(path, layout.cache_dir(), layout.direction_convention())?;
+        if file.skipped_flows > 0 {
+            log::warn!(
+                "skipped {} malformed flows in {}",
+                file.skipped_flows,
+                path.display()
+            );
+        }
+        parsed.flows.extend(file.flows);
+        parsed.skipped_flows += file.skipped_flows;
+    }
+    Ok(parsed)
+}
+
+pub fn get_all_data(layout: &DatasetLayout) -> Vec<MetadataWrapper> {
+    for label in layout.missing() {
+        log::warn!(
+            "no {} data for {} under {}",
+            label.application,
+            label.tunnel,
+            layout.root().display()
+        );
+    }
+    let all_data: Mutex<Vec<MetadataWrapper>> = Mutex::new(vec![]);
+    layout.present().par_bridge().for_each(|(label, files)| {
+        let parsed = match load_files(layout, files) {
+            Ok(parsed) => parsed,
+            Err(err) => return log::error!("skipping {label}: {err}"),
+        };
+        all_data.lock().unwrap().push(MetadataWrapper {
+            label: label.clone(),
+            all_packets: parsed.flows,
+            skipped_flows: parsed.skipped_flows,
+        })
+    });
     all_data.into_inner().unwrap()
 }
 
-pub fn get_some_data(encryption: Encryption, data_category: DataCategory) -> MetadataWrapper {
-    let path = match encryption {
-        Encryption::VPN(vpn) => {
-            format!("dataset/VPN/{}/{}", vpn.path(), data_category.path())
+/// Loads one label of the layout's registry, see [`LabelRegistry::find`].
+/// Returns `Ok(None)` when the layout has no files for it.
+///
+/// [`LabelRegistry::find`]: crate::labels::LabelRegistry::find
+pub fn get_some_data(
+    layout: &DatasetLayout,
+    label: &Label,
+) -> Result<Option<MetadataWrapper>, ParseError> {
+    let files = layout.files(label);
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let parsed = load_files(layout, files)?;
+    Ok(Some(MetadataWrapper {
+        label: label.clone(),
+        all_packets: parsed.flows,
+        skipped_flows: parsed.skipped_flows,
+    }))
+}
+
+/// Prepares a lazy walk over a label's files, or `None` when the layout has
+/// no files for it.
+pub fn stream_some_data(layout: &DatasetLayout, label: &Label) -> Option<FlowStream> {
+    let files = layout.files(label).to_vec();
+    (!files.is_empty()).then(|| {
+        FlowStream::new(label.clone(), files)
+            .with_direction_convention(layout.direction_convention())
+    })
+}
+
+impl FlowKey {
+    pub fn new(protocol: u8, a: Endpoint, b: Endpoint) -> Self {
+        let (lower, upper) = if a <= b { (a, b) } else { (b, a) };
+        Self {
+            protocol,
+            lower,
+            upper,
+        }
+    }
+}
+
+impl<P: Clone + Debug> Data<P> {
+    pub fn source(&self) -> Endpoint {
+        Endpoint {
+            address: self.ip_source,
+            port: self.port_source,
+        }
+    }
+
+    pub fn destination(&self) -> Endpoint {
+        Endpoint {
+            address: self.ip_destination,
+            port: self.port_destination,
+        }
+    }
+
+    pub fn flow_key(&self, protocol: u8) -> FlowKey {
+        FlowKey::new(protocol, self.source(), self.destination())
+    }
+}
+
+impl BasePacket {
+    pub fn packet_duration(&self) -> TimeDelta {
+        self.timestamp_end - self.timestamp_start
+    }
+}
+
+impl AsRef<BasePacket> for BasePacket {
+    fn as_ref(&self) -> &BasePacket {
+        self
+    }
+}
+
+impl AsRef<BasePacket> for TcpPacket {
+    fn as_ref(&self) -> &BasePacket {
+        &self.base
+    }
+}
+
+impl TcpFlags {
+    pub fn fin(self) -> bool {
+        self.contains(TcpFlags::FIN)
+    }
+
+    pub fn syn(self) -> bool {
+        self.contains(TcpFlags::SYN)
+    }
+
+    pub fn rst(self) -> bool {
+        self.contains(TcpFlags::RST)
+    }
+
+    pub fn psh(self) -> bool {
+        self.contains(TcpFlags::PSH)
+    }
+
+    pub fn ack(self) -> bool {
+        self.contains(TcpFlags::ACK)
+    }
+
+    pub fn urg(self) -> bool {
+        self.contains(TcpFlags::URG)
+    }
+
+    pub fn ece(self) -> bool {
+        self.contains(TcpFlags::ECE)
+    }
+
+    pub fn cwr(self) -> bool {
+        self.contains(TcpFlags::CWR)
+    }
+}
+
+impl TcpPacket {
+    /// Bytes of application data: the IP length minus the IP and TCP headers.
+    pub fn payload_length(&self) -> u64 {
+        self.base
+            .bytes
+            .saturating_sub(u64::from(self.base.ip_header_length))
+            .saturating_sub(u64::from(self.tcp_header_len))
+    }
+}
+
+impl AsRef<BasePacket> for UdpPacket {
+    fn as_ref(&self) -> &BasePacket {
+        &self.base
+    }
+}
+
+impl UdpPacket {
+    pub const HEADER_LENGTH: u16 = 8;
+
+    pub fn new(base: BasePacket, udp_length: u16) -> Self {
+        Self {
+            base,
+            udp_length,
+            payload_length: udp_length.saturating_sub(Self::HEADER_LENGTH),
         }
-        Encryption::NonVPN => format!("dataset/Non VPN/{}", data_category.path()),
-    };
-    MetadataWrapper {
-        encryption: Encryption::NonVPN,
-        data_category,
-        all_packets: get_data(path),
+    }
+
+    /// For sources without the UDP header, takes the UDP length to be
+    /// everything after the IP header. Records of several packets count the
+    /// bytes of all of them, so their average packet is used.
+    pub fn from_base(base: BasePacket) -> Self {
+        let packet_bytes = base.bytes / u64::from(base.packets.max(1));
+        let udp_length = packet_bytes.saturating_sub(u64::from(base.ip_header_length));
+        Self::new(base, u16::try_from(udp_length).unwrap_or(u16::MAX))
+    }
+}
+
+impl<P: AsRef<BasePacket> + Clone + Debug> Data<P> {
+    pub fn timestamp_start(&self) -> Option<DateTime<Utc>> {
+        self.packets
+            .iter()
+            .map(|packet| packet.as_ref().timestamp_start)
+            .min()
+    }
+
+    pub fn timestamp_end(&self) -> Option<DateTime<Utc>> {
+        self.packets
+            .iter()
+            .map(|packet| packet.as_ref().timestamp_end)
+            .max()
+    }
+
+    /// Time from the first packet starting to the last one ending.
+    pub fn duration(&self) -> TimeDelta {
+        match (self.timestamp_start(), self.timestamp_end()) {
+            (Some(start), Some(end)) => end - start,
+            _ => TimeDelta::zero(),
+        }
+    }
+
+    /// Packets sent by the capturing host, in flow order.
+    pub fn forward(&self) -> impl Iterator<Item = &P> {
+        self.packets
+            .iter()
+            .filter(|packet| packet.as_ref().direction == PacketDirection::Outgoing)
+    }
+
+    /// Packets received by the capturing host, in flow order. Packets of
+    /// unknown direction are in neither this nor [`Data::forward`].
+    pub fn backward(&self) -> impl Iterator<Item = &P> {
+        self.packets
+            .iter()
+            .filter(|packet| packet.as_ref().direction == PacketDirection::Incoming)
+    }
+
+    /// Gaps between the start of consecutive packets, in flow order.
+    pub fn inter_arrival_times(&self) -> Vec<TimeDelta> {
+        self.packets
+            .windows(2)
+            .map(|pair| pair[1].as_ref().timestamp_start - pair[0].as_ref().timestamp_start)
+            .collect()
+    }
+
+    /// Silences longer than `threshold` between one packet ending and the next
+    /// one starting.
+    pub fn idle_gaps(&self, threshold: TimeDelta) -> Vec<TimeDelta> {
+        self.packets
+            .windows(2)
+            .map(|pair| pair[1].as_ref().timestamp_start - pair[0].as_ref().timestamp_end)
+            .filter(|gap| *gap > threshold)
+            .collect()
     }
 }
 
 impl Data<TcpPacket> {
     pub fn to_base_packet(&self) -> Data<&BasePacket> {
         Data {
+            ip_destination: self.ip_destination,
+            ip_source: self.ip_source,
             port_source: self.port_source,
-            port_destination: self.port_source,
+            port_destination: self.port_destination,
             packets: self.packets.iter().map(|packet| &packet.base).collect(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::{DataCategory, Encryption, VPN};
+    use crate::test_support::{fixture, fixture_layout};
+
+    fn tcp_directions(wrapper: &MetadataWrapper) -> Vec<PacketDirection> {
+        let IpProtocol::Tcp(flow) = &wrapper.all_packets[0] else {
+            panic!("expected a TCP flow first: {:?}", wrapper.all_packets[0]);
+        };
+        flow.packets
+            .iter()
+            .map(|packet| packet.base.direction)
+            .collect()
+    }
+
+    /// What `VPN/OpenVPN/ssh.json` holds, however it was loaded.
+    fn assert_openvpn_ssh(wrapper: &MetadataWrapper) {
+        assert_eq!(&*wrapper.label.group, crate::labels::ENCRYPTED);
+        assert_eq!(&*wrapper.label.tunnel, "OpenVPN");
+        assert_eq!(&*wrapper.label.application, "SSH");
+        assert_eq!(wrapper.all_packets.len(), 2);
+        assert_eq!(wrapper.skipped_flows, 0);
+
+        let IpProtocol::Tcp(tcp) = &wrapper.all_packets[0] else {
+            panic!("expected a TCP flow first: {:?}", wrapper.all_packets[0]);
+        };
+        let base = tcp.to_base_packet();
+        assert_eq!(base.port_destination, 22);
+        assert_eq!(base.port_source, 50000);
+        assert_eq!(base.ip_source, Some("10.8.0.2".parse().unwrap()));
+        assert_eq!(base.ip_destination, Some("10.8.0.1".parse().unwrap()));
+        assert_eq!(
+            base.packets
+                .iter()
+                .map(|packet| packet.bytes)
+                .collect::<Vec<_>>(),
+            [60, 60, 52]
+        );
+        assert_eq!(
+            base.timestamp_end().unwrap() - base.timestamp_start().unwrap(),
+            TimeDelta::milliseconds(40)
+        );
+        assert_eq!(
+            tcp.packets
+                .iter()
+                .map(|packet| packet.tcp_flags)
+                .collect::<Vec<_>>(),
+            [TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK, TcpFlags::ACK]
+        );
+        assert_eq!(tcp.packets[0].tcp_sequence_number, 1000);
+        assert_eq!(tcp.packets[2].tcp_acknowledgment_number, 5001);
+        assert_eq!(tcp.packets[2].tcp_header_len, 32);
+
+        let IpProtocol::Udp(udp) = &wrapper.all_packets[1] else {
+            panic!("expected a UDP flow second: {:?}", wrapper.all_packets[1]);
+        };
+        assert_eq!(udp.port_destination, 1194);
+        assert_eq!(
+            udp.packets
+                .iter()
+                .map(|packet| (packet.udp_length, packet.payload_length))
+                .collect::<Vec<_>>(),
+            [(108, 100), (76, 68)]
+        );
+    }
+
+    #[test]
+    fn every_loader_keeps_labels_and_flow_fields() {
+        let layout = fixture_layout("loader-paths");
+        let cache = layout.cache_dir().unwrap().to_path_buf();
+        let ssh = layout.labels().find("OpenVPN", "SSH").unwrap();
+        assert_ne!(ssh, Label::builtin(&Encryption::NonVPN, DataCategory::SSH));
+
+        // Parsed the first time and read back from the cache the second.
+        for _ in 0..2 {
+            let wrapper = get_some_data(&layout, &ssh).unwrap().unwrap();
+            assert_openvpn_ssh(&wrapper);
+        }
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+
+        let stream = stream_some_data(&layout, &ssh).unwrap();
+        assert_openvpn_ssh(&MetadataWrapper::from_stream(stream).unwrap());
+
+        let uncached = layout.clone().with_cache_dir(None);
+        assert_openvpn_ssh(&get_some_data(&uncached, &ssh).unwrap().unwrap());
+
+        let all = get_all_data(&layout);
+        assert_eq!(all.len(), 2);
+        assert_openvpn_ssh(all.iter().find(|wrapper| wrapper.label == ssh).unwrap());
+        let mail = all.iter().find(|wrapper| wrapper.label != ssh).unwrap();
+        assert_eq!(
+            mail.label,
+            Label::builtin(&Encryption::NonVPN, DataCategory::Mail)
+        );
+        let IpProtocol::Tcp(tcp) = &mail.all_packets[0] else {
+            panic!("expected a TCP flow: {:?}", mail.all_packets[0]);
+        };
+        assert_eq!(tcp.to_base_packet().port_destination, 993);
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 2);
+
+        let missing = layout.labels().find("OpenVPN", "Mail").unwrap();
+        assert!(get_some_data(&layout, &missing).unwrap().is_none());
+        assert!(stream_some_data(&layout, &missing).is_none());
+    }
+
+    #[test]
+    fn udp_lengths_are_derived_per_packet() {
+        let base = |bytes: u64, packets: u32| BasePacket {
+            bytes,
+            direction: PacketDirection::Outgoing,
+            ip_header_length: 20,
+            packets,
+            timestamp_start: DateTime::UNIX_EPOCH,
+            timestamp_end: DateTime::UNIX_EPOCH,
+        };
+        let packet = UdpPacket::from_base(base(128, 1));
+        assert_eq!((packet.udp_length, packet.payload_length), (108, 100));
+        // Ten packets of 128 bytes each
+        let packet = UdpPacket::from_base(base(1280, 10));
+        assert_eq!((packet.udp_length, packet.payload_length), (108, 100));
+        let packet = UdpPacket::from_base(base(128, 0));
+        assert_eq!(packet.udp_length, 108);
+        // Shorter than its own headers
+        let packet = UdpPacket::from_base(base(4, 1));
+        assert_eq!((packet.udp_length, packet.payload_length), (0, 0));
+        let packet = UdpPacket::from_base(base(u64::MAX, 1));
+        assert_eq!(packet.udp_length, u16::MAX);
+    }
+
+    #[test]
+    fn streams_captures_next_to_json_files() {
+        let label = Label::builtin(&Encryption::VPN(VPN::OpenVPN), DataCategory::Mail);
+        let stream = FlowStream::new(
+            label.clone(),
+            vec![fixture("capture.pcap"), fixture("capture.pcapng")],
+        );
+        let wrapper = MetadataWrapper::from_stream(stream).unwrap();
+        assert_eq!(wrapper.label, label);
+        assert_eq!(wrapper.all_packets.len(), 4);
+        assert_eq!(wrapper.skipped_flows, 0);
+    }
+
+    #[test]
+    fn loaders_follow_the_layout_direction_convention() {
+        let openvpn = Label::builtin(&Encryption::VPN(VPN::OpenVPN), DataCategory::SSH);
+        let incoming_first = [
+            PacketDirection::Incoming,
+            PacketDirection::Outgoing,
+            PacketDirection::Incoming,
+        ];
+        let outgoing_first = [
+            PacketDirection::Outgoing,
+            PacketDirection::Incoming,
+            PacketDirection::Outgoing,
+        ];
+
+        // Both conventions share one cache directory, so a cache written for
+        // one must not be read back for the other.
+        let default = fixture_layout("direction-convention");
+        let cache = default.cache_dir().unwrap().to_path_buf();
+        let flipped = default
+            .clone()
+            .with_direction_convention(DirectionConvention::NegativeIsOutgoing);
+        for _ in 0..2 {
+            let wrapper = get_some_data(&default, &openvpn).unwrap().unwrap();
+            assert_eq!(tcp_directions(&wrapper), outgoing_first);
+            let wrapper = get_some_data(&flipped, &openvpn).unwrap().unwrap();
+            assert_eq!(tcp_directions(&wrapper), incoming_first);
+        }
+        assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 2);
+
+        let stream = stream_some_data(&flipped, &openvpn).unwrap();
+        let wrapper = MetadataWrapper::from_stream(stream).unwrap();
+        assert_eq!(tcp_directions(&wrapper), incoming_first);
+
+        let all = get_all_data(&flipped);
+        let ssh = all
+            .iter()
+            .find(|wrapper| &*wrapper.label.tunnel == "OpenVPN")
+            .unwrap();
+        assert_eq!(tcp_directions(ssh), incoming_first);
+        // An explicit direction field is not affected by the convention.
+        let mail = all
+            .iter()
+            .find(|wrapper| &*wrapper.label.tunnel == "NonVPN")
+            .unwrap();
+        assert_eq!(
+            tcp_directions(mail),
+            [PacketDirection::Outgoing, PacketDirection::Incoming]
+        );
+    }
+}
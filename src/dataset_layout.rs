@@ -0,0 +1,251 @@
+use crate::labels::{Label, LabelError, LabelRegistry};
+use crate::parse_data::DirectionConvention;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_DATASET_ROOT: &str = "dataset";
+const ROOT_VARIABLE: &str = "VPN_DATASET_ROOT";
+const MAPPING_VARIABLE: &str = "VPN_DATASET_MAPPING";
+const CACHE_VARIABLE: &str = "VPN_DATASET_CACHE";
+const LABELS_VARIABLE: &str = "VPN_DATASET_LABELS";
+const DIRECTION_VARIABLE: &str = "VPN_DATASET_DIRECTION";
+const DEFAULT_CACHE_DIR: &str = ".flow-cache";
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    Labels(LabelError),
+    /// `VPN_DATASET_DIRECTION` names no [`DirectionConvention`].
+    Direction(String),
+}
+
+impl Display for LayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutError::Io { path, source } => {
+                write!(f, "could not read {}: {source}", path.display())
+            }
+            LayoutError::Json { path, source } => {
+                write!(f, "invalid mapping file {}: {source}", path.display())
+            }
+            LayoutError::Labels(err) => write!(f, "{err}"),
+            LayoutError::Direction(value) => write!(
+                f,
+                "unknown direction convention {value:?}, expected negative-is-incoming or negative-is-outgoing"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+impl From<LabelError> for LayoutError {
+    fn from(err: LabelError) -> Self {
+        LayoutError::Labels(err)
+    }
+}
+
+fn direction_convention(value: &str) -> Result<DirectionConvention, LayoutError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "negative-is-incoming" => Ok(DirectionConvention::NegativeIsIncoming),
+        "negative-is-outgoing" => Ok(DirectionConvention::NegativeIsOutgoing),
+        _ => Err(LayoutError::Direction(value.to_string())),
+    }
+}
+
+/// One line of a mapping file, e.g.
+/// `{"encryption": "OpenVPN", "category": "Mail", "path": "2024-05-01/openvpn/mail.json"}`.
+/// `encryption` names a tunnel and `category` an application of the
+/// [`LabelRegistry`]. Paths are relative to the dataset root and may also
+/// name pcap or pcapng captures.
+#[derive(Deserialize, Debug)]
+struct MappingEntry {
+    encryption: String,
+    category: String,
+    path: PathBuf,
+}
+
+/// Which files under a dataset root hold which [`Label`]. A label can be
+/// spread over several files, for example one capture per day.
+#[derive(Clone, Debug)]
+pub struct DatasetLayout {
+    root: PathBuf,
+    labels: Arc<LabelRegistry>,
+    files: HashMap<Label, Vec<PathBuf>>,
+    cache_dir: Option<PathBuf>,
+    direction_convention: DirectionConvention,
+}
+
+impl DatasetLayout {
+    fn new(root: PathBuf, labels: Arc<LabelRegistry>, files: HashMap<Label, Vec<PathBuf>>) -> Self {
+        Self {
+            cache_dir: Some(root.join(DEFAULT_CACHE_DIR)),
+            direction_convention: DirectionConvention::default(),
+            root,
+            labels,
+            files,
+        }
+    }
+
+    /// Looks for the standard `VPN/{vpn}/{category}.json` and
+    /// `Non VPN/{category}.json` tree under `root`.
+    pub fn scan(root: impl Into<PathBuf>) -> Self {
+        Self::scan_labels(root, Arc::default())
+    }
+
+    /// Looks for the tree `labels` describes: `{group}/{tunnel}/{file}`.
+    pub fn scan_labels(root: impl Into<PathBuf>, labels: Arc<LabelRegistry>) -> Self {
+        let root = root.into();
+        let files = labels
+            .labels()
+            .filter_map(|label| {
+                let path = root.join(labels.path(&label)?);
+                path.is_file().then(|| (label, vec![path]))
+            })
+            .collect();
+        Self::new(root, labels, files)
+    }
+
+    /// Builds the layout from a JSON array of [`MappingEntry`]s instead of the
+    /// standard tree. Listed files that do not exist are left out.
+    pub fn from_mapping_file(
+        root: impl Into<PathBuf>,
+        mapping: impl AsRef<Path>,
+        labels: Arc<LabelRegistry>,
+    ) -> Result<Self, LayoutError> {
+        let root = root.into();
+        let mapping = mapping.as_ref();
+        let file = fs::File::open(mapping).map_err(|source| LayoutError::Io {
+            path: mapping.to_path_buf(),
+            source,
+        })?;
+        let entries: Vec<MappingEntry> =
+            serde_json::from_reader(BufReader::new(file)).map_err(|source| LayoutError::Json {
+                path: mapping.to_path_buf(),
+                source,
+            })?;
+
+        let mut files: HashMap<Label, Vec<PathBuf>> = HashMap::new();
+        for entry in entries {
+            let label = labels.find(&entry.encryption, &entry.category)?;
+            let path = root.join(&entry.path);
+            if !path.is_file() {
+                log::warn!("mapped file {} does not exist", path.display());
+                continue;
+            }
+            files.entry(label).or_default().push(path);
+        }
+        Ok(Self::new(root, labels, files))
+    }
+
+    /// Reads the root from `VPN_DATASET_ROOT` (default `dataset`) and uses the
+    /// mapping file in `VPN_DATASET_MAPPING` when it is set. `VPN_DATASET_LABELS`
+    /// points at a [`LabelRegistry`] file replacing the built-in labels.
+    /// `VPN_DATASET_CACHE` moves the flow cache, or turns it off when set to
+    /// `off`. `VPN_DATASET_DIRECTION` is `negative-is-incoming` (the default)
+    /// or `negative-is-outgoing`, see [`DirectionConvention`].
+    pub fn from_env() -> Result<Self, LayoutError> {
+        let root = std::env::var_os(ROOT_VARIABLE)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DATASET_ROOT));
+        let labels = match std::env::var_os(LABELS_VARIABLE) {
+            Some(labels) => Arc::new(LabelRegistry::from_file(labels)?),
+            None => Arc::default(),
+        };
+        let layout = match std::env::var_os(MAPPING_VARIABLE) {
+            Some(mapping) => Self::from_mapping_file(root, mapping, labels)?,
+            None => Self::scan_labels(root, labels),
+        };
+        let layout = match std::env::var_os(CACHE_VARIABLE) {
+            Some(cache_dir) if cache_dir == "off" => layout.with_cache_dir(None),
+            Some(cache_dir) => layout.with_cache_dir(Some(cache_dir.into())),
+            None => layout,
+        };
+        Ok(match std::env::var(DIRECTION_VARIABLE) {
+            Ok(value) => layout.with_direction_convention(direction_convention(&value)?),
+            Err(_) => layout,
+        })
+    }
+
+    /// How JSON files without a `direction` field encode it in the sign of
+    /// `bytes`.
+    pub fn with_direction_convention(mut self, convention: DirectionConvention) -> Self {
+        self.direction_convention = convention;
+        self
+    }
+
+    pub fn direction_convention(&self) -> DirectionConvention {
+        self.direction_convention
+    }
+
+    /// Where parsed flows are cached, `None` to always parse the JSON.
+    /// Defaults to `.flow-cache` under the root.
+    pub fn with_cache_dir(mut self, cache_dir: Option<PathBuf>) -> Self {
+        self.cache_dir = cache_dir;
+        self
+    }
+
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn labels(&self) -> &Arc<LabelRegistry> {
+        &self.labels
+    }
+
+    pub fn files(&self, label: &Label) -> &[PathBuf] {
+        self.files.get(label).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Every label that has at least one file.
+    pub fn present(&self) -> impl Iterator<Item = (&Label, &[PathBuf])> {
+        self.files
+            .iter()
+            .map(|(label, files)| (label, files.as_slice()))
+    }
+
+    /// Every label of the registry without any file.
+    pub fn missing(&self) -> Vec<Label> {
+        self.labels
+            .labels()
+            .filter(|label| !self.files.contains_key(label))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_direction_conventions_by_name() {
+        assert_eq!(
+            direction_convention("negative-is-incoming").unwrap(),
+            DirectionConvention::NegativeIsIncoming
+        );
+        assert_eq!(
+            direction_convention(" Negative-Is-Outgoing ").unwrap(),
+            DirectionConvention::NegativeIsOutgoing
+        );
+        assert!(matches!(
+            direction_convention("outgoing"),
+            Err(LayoutError::Direction(value)) if value == "outgoing"
+        ));
+    }
+}
@@ -0,0 +1,630 @@
+use crate::categories::{IpProtocol, PacketDirection};
+use crate::data_structure::{BasePacket, Endpoint, MetadataWrapper, TcpPacket, UdpPacket};
+use crate::features::FeatureSet;
+use arrow_array::{
+    ArrayRef, Int64Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt16Array,
+    UInt32Array, UInt64Array, UInt8Array,
+};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const PACKET_TABLE: &str = "packets.arrow";
+pub const FLOW_TABLE: &str = "flows.arrow";
+pub const FLOW_FEATURES_CSV: &str = "flow_features.csv";
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io { path: PathBuf, source: io::Error },
+    Arrow(ArrowError),
+}
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::Io { path, source } => {
+                write!(f, "could not write {}: {source}", path.display())
+            }
+            ExportError::Arrow(err) => write!(f, "could not encode table: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<ArrowError> for ExportError {
+    fn from(err: ArrowError) -> Self {
+        ExportError::Arrow(err)
+    }
+}
+
+fn direction_label(direction: &PacketDirection) -> &'static str {
+    match direction {
+        PacketDirection::Outgoing => "outgoing",
+        PacketDirection::Incoming => "incoming",
+        PacketDirection::Unknown => "unknown",
+    }
+}
+
+fn nanoseconds(timestamp: DateTime<Utc>) -> Option<i64> {
+    timestamp.timestamp_nanos_opt()
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Nanosecond, Some("+00:00".into()))
+}
+
+fn label_fields() -> [Field; 8] {
+    [
+        Field::new("flow_id", DataType::UInt64, false),
+        Field::new("group", DataType::Utf8, false),
+        Field::new("encryption", DataType::Utf8, false),
+        Field::new("data_category", DataType::Utf8, false),
+        Field::new("protocol", DataType::Utf8, false),
+        Field::new("protocol_number", DataType::UInt8, false),
+        Field::new("port_source", DataType::UInt16, false),
+        Field::new("port_destination", DataType::UInt16, false),
+    ]
+}
+
+fn packet_schema() -> SchemaRef {
+    let mut fields = label_fields().to_vec();
+    fields.extend([
+        Field::new("direction", DataType::Utf8, false),
+        Field::new("bytes", DataType::UInt64, false),
+        Field::new("packets", DataType::UInt32, false),
+        Field::new("ip_header_length", DataType::UInt16, false),
+        Field::new("timestamp_start", timestamp_type(), true),
+        Field::new("timestamp_end", timestamp_type(), true),
+        Field::new("tcp_header_len", DataType::UInt16, true),
+        Field::new("tcp_flags", DataType::UInt8, true),
+        Field::new("tcp_acknowledgment_number", DataType::UInt32, true),
+        Field::new("tcp_sequence_number", DataType::UInt32, true),
+        Field::new("udp_length", DataType::UInt16, true),
+        Field::new("udp_payload_length", DataType::UInt16, true),
+    ]);
+    Arc::new(Schema::new(fields))
+}
+
+fn flow_schema() -> SchemaRef {
+    let mut fields = label_fields().to_vec();
+    fields.extend([
+        Field::new("ip_source", DataType::Utf8, true),
+        Field::new("ip_destination", DataType::Utf8, true),
+        Field::new("packet_count", DataType::UInt64, false),
+        Field::new("outgoing_packets", DataType::UInt64, false),
+        Field::new("incoming_packets", DataType::UInt64, false),
+        Field::new("total_bytes", DataType::UInt64, false),
+        Field::new("timestamp_start", timestamp_type(), true),
+        Field::new("duration_ns", DataType::Int64, true),
+    ]);
+    Arc::new(Schema::new(fields))
+}
+
+#[derive(Default)]
+struct PacketRows {
+    flow_id: Vec<u64>,
+    protocol: Vec<&'static str>,
+    protocol_number: Vec<u8>,
+    port_source: Vec<u16>,
+    port_destination: Vec<u16>,
+    direction: Vec<&'static str>,
+    bytes: Vec<u64>,
+    packets: Vec<u32>,
+    ip_header_length: Vec<u16>,
+    timestamp_start: Vec<Option<i64>>,
+    timestamp_end: Vec<Option<i64>>,
+    tcp_header_len: Vec<Option<u16>>,
+    tcp_flags: Vec<Option<u8>>,
+    tcp_acknowledgment_number: Vec<Option<u32>>,
+    tcp_sequence_number: Vec<Option<u32>>,
+    udp_length: Vec<Option<u16>>,
+    udp_payload_length: Vec<Option<u16>>,
+}
+
+impl PacketRows {
+    fn push(
+        &mut self,
+        flow_id: u64,
+        flow: &IpProtocol,
+        packet: &BasePacket,
+        tcp: Option<&TcpPacket>,
+        udp: Option<&UdpPacket>,
+    ) {
+        let (source, destination) = flow.endpoints();
+        self.flow_id.push(flow_id);
+        self.protocol.push(flow.name());
+        self.protocol_number.push(flow.number());
+        self.port_source.push(source.port);
+        self.port_destination.push(destination.port);
+        self.direction.push(direction_label(&packet.direction));
+        self.bytes.push(packet.bytes);
+        self.packets.push(packet.packets);
+        self.ip_header_length.push(packet.ip_header_length);
+        self.timestamp_start
+            .push(nanoseconds(packet.timestamp_start));
+        self.timestamp_end.push(nanoseconds(packet.timestamp_end));
+        self.tcp_header_len.push(tcp.map(|tcp| tcp.tcp_header_len));
+        self.tcp_flags.push(tcp.map(|tcp| tcp.tcp_flags.bits()));
+        self.tcp_acknowledgment_number
+            .push(tcp.map(|tcp| tcp.tcp_acknowledgment_number));
+        self.tcp_sequence_number
+            .push(tcp.map(|tcp| tcp.tcp_sequence_number));
+        self.udp_length.push(udp.map(|udp| udp.udp_length));
+        self.udp_payload_length
+            .push(udp.map(|udp| udp.payload_length));
+    }
+
+    fn into_batch(
+        self,
+        schema: SchemaRef,
+        wrapper: &MetadataWrapper,
+    ) -> Result<RecordBatch, ArrowError> {
+        let rows = self.flow_id.len();
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(self.flow_id)),
+            Arc::new(StringArray::from(vec![&*wrapper.label.group; rows])),
+            Arc::new(StringArray::from(vec![&*wrapper.label.tunnel; rows])),
+            Arc::new(StringArray::from(vec![&*wrapper.label.application; rows])),
+            Arc::new(StringArray::from(self.protocol)),
+            Arc::new(UInt8Array::from(self.protocol_number)),
+            Arc::new(UInt16Array::from(self.port_source)),
+            Arc::new(UInt16Array::from(self.port_destination)),
+            Arc::new(StringArray::from(self.direction)),
+            Arc::new(UInt64Array::from(self.bytes)),
+            Arc::new(UInt32Array::from(self.packets)),
+            Arc::new(UInt16Array::from(self.ip_header_length)),
+            Arc::new(TimestampNanosecondArray::from(self.timestamp_start).with_timezone_utc()),
+            Arc::new(TimestampNanosecondArray::from(self.timestamp_end).with_timezone_utc()),
+            Arc::new(UInt16Array::from(self.tcp_header_len)),
+            Arc::new(UInt8Array::from(self.tcp_flags)),
+            Arc::new(UInt32Array::from(self.tcp_acknowledgment_number)),
+            Arc::new(UInt32Array::from(self.tcp_sequence_number)),
+            Arc::new(UInt16Array::from(self.udp_length)),
+            Arc::new(UInt16Array::from(self.udp_payload_length)),
+        ];
+        RecordBatch::try_new(schema, columns)
+    }
+}
+
+#[derive(Default)]
+struct FlowRows {
+    flow_id: Vec<u64>,
+    protocol: Vec<&'static str>,
+    protocol_number: Vec<u8>,
+    port_source: Vec<u16>,
+    port_destination: Vec<u16>,
+    ip_source: Vec<Option<String>>,
+    ip_destination: Vec<Option<String>>,
+    packet_count: Vec<u64>,
+    outgoing_packets: Vec<u64>,
+    incoming_packets: Vec<u64>,
+    total_bytes: Vec<u64>,
+    timestamp_start: Vec<Option<i64>>,
+    duration_ns: Vec<Option<i64>>,
+}
+
+impl FlowRows {
+    fn push(&mut self, flow_id: u64, flow: &IpProtocol) {
+        let packets: Vec<&BasePacket> = flow.into();
+        let (source, destination) = flow.endpoints();
+        let count = |direction| {
+            packets
+                .iter()
+                .filter(|packet| packet.direction == direction)
+                .count() as u64
+        };
+        let start = packets.iter().map(|packet| packet.timestamp_start).min();
+        let end = packets.iter().map(|packet| packet.timestamp_end).max();
+        self.flow_id.push(flow_id);
+        self.protocol.push(flow.name());
+        self.protocol_number.push(flow.number());
+        self.port_source.push(source.port);
+        self.port_destination.push(destination.port);
+        self.ip_source.push(source.address.map(|ip| ip.to_string()));
+        self.ip_destination
+            .push(destination.address.map(|ip| ip.to_string()));
+        self.packet_count.push(packets.len() as u64);
+        self.outgoing_packets.push(count(PacketDirection::Outgoing));
+        self.incoming_packets.push(count(PacketDirection::Incoming));
+        self.total_bytes
+            .push(packets.iter().map(|packet| packet.bytes).sum());
+        self.timestamp_start.push(start.and_then(nanoseconds));
+        self.duration_ns.push(
+            start
+                .zip(end)
+                .and_then(|(start, end)| (end - start).num_nanoseconds()),
+        );
+    }
+
+    fn into_batch(
+        self,
+        schema: SchemaRef,
+        wrapper: &MetadataWrapper,
+    ) -> Result<RecordBatch, ArrowError> {
+        let rows = self.flow_id.len();
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(self.flow_id)),
+            Arc::new(StringArray::from(vec![&*wrapper.label.group; rows])),
+            Arc::new(StringArray::from(vec![&*wrapper.label.tunnel; rows])),
+            Arc::new(StringArray::from(vec![&*wrapper.label.application; rows])),
+            Arc::new(StringArray::from(self.protocol)),
+            Arc::new(UInt8Array::from(self.protocol_number)),
+            Arc::new(UInt16Array::from(self.port_source)),
+            Arc::new(UInt16Array::from(self.port_destination)),
+            Arc::new(StringArray::from(self.ip_source)),
+            Arc::new(StringArray::from(self.ip_destination)),
+            Arc::new(UInt64Array::from(self.packet_count)),
+            Arc::new(UInt64Array::from(self.outgoing_packets)),
+            Arc::new(UInt64Array::from(self.incoming_packets)),
+            Arc::new(UInt64Array::from(self.total_bytes)),
+            Arc::new(TimestampNanosecondArray::from(self.timestamp_start).with_timezone_utc()),
+            Arc::new(Int64Array::from(self.duration_ns)),
+        ];
+        RecordBatch::try_new(schema, columns)
+    }
+}
+
+fn create_writer(
+    path: &Path,
+    schema: &Schema,
+) -> Result<FileWriter<BufWriter<fs::File>>, ExportError> {
+    let file = fs::File::create(path).map_err(|source| ExportError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(FileWriter::try_new(BufWriter::new(file), schema)?)
+}
+
+/// Writes [`PACKET_TABLE`] (one row per packet) and [`FLOW_TABLE`] (one row
+/// per flow) as Arrow IPC files into `directory`. `flow_id` joins the two and
+/// counts flows across all wrappers. Each wrapper becomes its own record batch.
+pub fn export_arrow(
+    data: &[MetadataWrapper],
+    directory: impl AsRef<Path>,
+) -> Result<(), ExportError> {
+    let directory = directory.as_ref();
+    fs::create_dir_all(directory).map_err(|source| ExportError::Io {
+        path: directory.to_path_buf(),
+        source,
+    })?;
+    let packet_schema = packet_schema();
+    let flow_schema = flow_schema();
+    let mut packet_writer = create_writer(&directory.join(PACKET_TABLE), &packet_schema)?;
+    let mut flow_writer = create_writer(&directory.join(FLOW_TABLE), &flow_schema)?;
+
+    let mut flow_id = 0;
+    for wrapper in data {
+        let mut packet_rows = PacketRows::default();
+        let mut flow_rows = FlowRows::default();
+        for flow in &wrapper.all_packets {
+            flow_rows.push(flow_id, flow);
+            match flow {
+                IpProtocol::Tcp(data) => data.packets.iter().for_each(|packet| {
+                    packet_rows.push(flow_id, flow, &packet.base, Some(packet), None)
+                }),
+                IpProtocol::Udp(data) => data.packets.iter().for_each(|packet| {
+                    packet_rows.push(flow_id, flow, &packet.base, None, Some(packet))
+                }),
+                IpProtocol::Gre(data)
+                | IpProtocol::Icmp(data)
+                | IpProtocol::Esp(data)
+                | IpProtocol::Ah(data)
+                | IpProtocol::Icmpv6(data)
+                | IpProtocol::Other(_, data) => data
+                    .packets
+                    .iter()
+                    .for_each(|packet| packet_rows.push(flow_id, flow, packet, None, None)),
+            }
+            flow_id += 1;
+        }
+        packet_writer.write(&packet_rows.into_batch(packet_schema.clone(), wrapper)?)?;
+        flow_writer.write(&flow_rows.into_batch(flow_schema.clone(), wrapper)?)?;
+    }
+    packet_writer.finish()?;
+    flow_writer.finish()?;
+    Ok(())
+}
+
+/// Quotes `field` the way RFC 4180 asks for when it holds a separator, a
+/// quote or a line break.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+fn write_csv_row(writer: &mut impl Write, row: &[String]) -> io::Result<()> {
+    let row = row.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+    writeln!(writer, "{}", row.join(","))
+}
+
+/// Writes a CICFlowMeter-style CSV to `path`: the flow identity columns, one
+/// column per feature of `features` and our labels at the end. With
+/// [`crate::features::FlowStatistics`] alone this is one row per flow in
+/// CICFlowMeter's column order; per-packet sets give one row per packet.
+pub fn export_flow_features_csv(
+    data: &[MetadataWrapper],
+    path: impl AsRef<Path>,
+    features: &FeatureSet,
+) -> Result<(), ExportError> {
+    let path = path.as_ref();
+    let io_error = |source| ExportError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = fs::File::create(path).map_err(io_error)?;
+    let mut writer = BufWriter::new(file);
+
+    let header = [
+        "Flow ID",
+        "Src IP",
+        "Src Port",
+        "Dst IP",
+        "Dst Port",
+        "Protocol",
+        "Timestamp",
+    ]
+    .into_iter()
+    .map(String::from)
+    .chain(features.feature_names())
+    .chain(["Group", "Encryption", "Data Category", "Label"].map(String::from))
+    .collect::<Vec<_>>();
+    write_csv_row(&mut writer, &header).map_err(io_error)?;
+
+    for wrapper in data {
+        let label = &wrapper.label;
+        let (group, encryption, category) = (&label.group, &label.tunnel, &label.application);
+        for flow in &wrapper.all_packets {
+            let (source, destination) = flow.endpoints();
+            let address = |endpoint: Endpoint| {
+                endpoint
+                    .address
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default()
+            };
+            let (source_ip, destination_ip) = (address(source), address(destination));
+            let start = Vec::<&BasePacket>::from(flow)
+                .iter()
+                .map(|packet| packet.timestamp_start)
+                .min();
+            let identity = [
+                format!(
+                    "{source_ip}-{destination_ip}-{}-{}-{}",
+                    source.port,
+                    destination.port,
+                    flow.number()
+                ),
+                source_ip,
+                source.port.to_string(),
+                destination_ip,
+                destination.port.to_string(),
+                flow.number().to_string(),
+                start
+                    .map(|timestamp| timestamp.format("%d/%m/%Y %I:%M:%S %p").to_string())
+                    .unwrap_or_default(),
+            ];
+            let labels = [
+                group.to_string(),
+                encryption.to_string(),
+                category.to_string(),
+                format!("{encryption}-{category}"),
+            ];
+            for values in features.extract(flow) {
+                let row = identity
+                    .iter()
+                    .cloned()
+                    .chain(values.iter().map(f64::to_string))
+                    .chain(labels.iter().cloned())
+                    .collect::<Vec<_>>();
+                write_csv_row(&mut writer, &row).map_err(io_error)?;
+            }
+        }
+    }
+    writer.flush().map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::{DataCategory, Encryption, VPN};
+    use crate::features::{FlowStatistics, PacketFeatures};
+    use crate::flow_features::FEATURE_COLUMNS;
+    use crate::labels::Label;
+    use crate::parse_pcap::get_pcap_data;
+    use crate::test_support::{fixture, scratch_dir};
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{UInt16Type, UInt64Type};
+    use arrow_ipc::reader::FileReader;
+
+    fn wrapper(capture: &str, label: Label) -> MetadataWrapper {
+        MetadataWrapper {
+            label,
+            all_packets: get_pcap_data(fixture(capture)).unwrap().flows,
+            skipped_flows: 0,
+        }
+    }
+
+    fn read_table(path: &Path) -> (SchemaRef, Vec<RecordBatch>) {
+        let reader = FileReader::try_new(fs::File::open(path).unwrap(), None).unwrap();
+        let schema = reader.schema();
+        (schema, reader.map(Result::unwrap).collect())
+    }
+
+    fn strings(batch: &RecordBatch, column: &str) -> Vec<String> {
+        batch
+            .column_by_name(column)
+            .unwrap()
+            .as_string::<i32>()
+            .iter()
+            .map(|value| value.unwrap().to_string())
+            .collect()
+    }
+
+    fn flow_ids(batch: &RecordBatch) -> Vec<u64> {
+        batch
+            .column_by_name("flow_id")
+            .unwrap()
+            .as_primitive::<UInt64Type>()
+            .values()
+            .to_vec()
+    }
+
+    #[test]
+    fn arrow_tables_round_trip() {
+        let openvpn = Label::builtin(&Encryption::VPN(VPN::OpenVPN), DataCategory::SSH);
+        let plain = Label::builtin(&Encryption::NonVPN, DataCategory::Mail);
+        let data = [
+            wrapper("capture.pcap", openvpn.clone()),
+            wrapper("capture.pcapng", plain.clone()),
+        ];
+        let directory = scratch_dir("export-arrow");
+        export_arrow(&data, &directory).unwrap();
+
+        let (schema, batches) = read_table(&directory.join(PACKET_TABLE));
+        assert_eq!(schema, packet_schema());
+        assert_eq!(
+            batches
+                .iter()
+                .map(RecordBatch::num_rows)
+                .collect::<Vec<_>>(),
+            [6, 3]
+        );
+        assert_eq!(strings(&batches[0], "encryption"), vec!["OpenVPN"; 6]);
+        assert_eq!(strings(&batches[0], "data_category"), vec!["SSH"; 6]);
+        assert_eq!(strings(&batches[0], "group"), vec![&*openvpn.group; 6]);
+        assert_eq!(strings(&batches[1], "encryption"), vec!["NonVPN"; 3]);
+        assert_eq!(strings(&batches[1], "group"), vec![&*plain.group; 3]);
+        assert_eq!(
+            strings(&batches[0], "direction")[..4],
+            ["outgoing", "incoming", "outgoing", "incoming"]
+        );
+        assert_eq!(flow_ids(&batches[0]), [0, 0, 0, 0, 1, 2]);
+        assert_eq!(flow_ids(&batches[1]), [3, 3, 3]);
+
+        let (schema, batches) = read_table(&directory.join(FLOW_TABLE));
+        assert_eq!(schema, flow_schema());
+        assert_eq!(
+            batches
+                .iter()
+                .map(RecordBatch::num_rows)
+                .collect::<Vec<_>>(),
+            [3, 1]
+        );
+        assert_eq!(flow_ids(&batches[0]), [0, 1, 2]);
+        assert_eq!(flow_ids(&batches[1]), [3]);
+        assert_eq!(strings(&batches[0], "protocol"), ["tcp", "tcp", "udp"]);
+        let ports = |column| {
+            batches[0]
+                .column_by_name(column)
+                .unwrap()
+                .as_primitive::<UInt16Type>()
+                .values()
+                .to_vec()
+        };
+        assert_eq!(ports("port_source"), [5000, 5001, 6000]);
+        assert_eq!(ports("port_destination"), [443, 443, 53]);
+        assert_eq!(strings(&batches[1], "data_category"), ["Mail"]);
+        let packet_counts = batches[0]
+            .column_by_name("packet_count")
+            .unwrap()
+            .as_primitive::<UInt64Type>()
+            .values()
+            .to_vec();
+        assert_eq!(packet_counts, [4, 1, 1]);
+    }
+
+    #[test]
+    fn flow_features_csv_has_one_labelled_row_per_flow() {
+        let openvpn = Label::builtin(&Encryption::VPN(VPN::OpenVPN), DataCategory::SSH);
+        let data = [wrapper("capture.pcap", openvpn)];
+        let path = scratch_dir("export-csv").join(FLOW_FEATURES_CSV);
+        let features = FeatureSet::new().with(FlowStatistics::default());
+        export_flow_features_csv(&data, &path, &features).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let rows = text
+            .lines()
+            .map(|line| line.split(',').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 1 + 3);
+        assert!(rows
+            .iter()
+            .all(|row| row.len() == 7 + FEATURE_COLUMNS.len() + 4));
+        assert_eq!(rows[0][..3], ["Flow ID", "Src IP", "Src Port"]);
+        assert_eq!(rows[0][7], FEATURE_COLUMNS[0]);
+        assert_eq!(rows[0][rows[0].len() - 1], "Label");
+        assert_eq!(rows[1][0], "10.0.0.1-10.0.0.2-5000-443-6");
+        assert_eq!(rows[1][4], "443");
+        assert!(rows[1..]
+            .iter()
+            .all(|row| row[row.len() - 1] == "OpenVPN-SSH" && row[row.len() - 2] == "SSH"));
+    }
+
+    #[test]
+    fn per_packet_features_give_one_row_per_packet() {
+        let openvpn = Label::builtin(&Encryption::VPN(VPN::OpenVPN), DataCategory::SSH);
+        let data = [wrapper("capture.pcap", openvpn)];
+        let path = scratch_dir("export-csv-packets").join(FLOW_FEATURES_CSV);
+        let features = FeatureSet::new().with(PacketFeatures::default());
+        export_flow_features_csv(&data, &path, &features).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 1 + 6);
+    }
+
+    /// Splits one CSV line, undoing [`csv_field`].
+    fn csv_fields(line: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(String::new()),
+                c => fields.last_mut().unwrap().push(c),
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn csv_quotes_names_with_separators() {
+        let label = Label {
+            group: "tunnelled".into(),
+            tunnel: "Open\"VPN\"".into(),
+            application: "Mail, Chat".into(),
+        };
+        let data = [wrapper("capture.pcap", label)];
+        let path = scratch_dir("export-csv-quoted").join(FLOW_FEATURES_CSV);
+        let features = FeatureSet::new().with(FlowStatistics::default());
+        export_flow_features_csv(&data, &path, &features).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let rows = text.lines().map(csv_fields).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 1 + 3);
+        assert!(rows.iter().all(|row| row.len() == rows[0].len()));
+        assert!(text
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(r#","Open""VPN""","Mail, Chat","Open""VPN""-Mail, Chat""#));
+        let row = &rows[1];
+        assert_eq!(
+            row[row.len() - 3..],
+            ["Open\"VPN\"", "Mail, Chat", "Open\"VPN\"-Mail, Chat"]
+        );
+    }
+}
@@ -0,0 +1,556 @@
+use crate::categories::{IpProtocol, PacketDirection};
+use crate::data_structure::BasePacket;
+use crate::flow_features::{FlowFeatures, DEFAULT_ACTIVITY_TIMEOUT, FEATURE_COLUMNS};
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Granularity {
+    /// One row per packet of the flow.
+    Packet,
+    /// Exactly one row for the whole flow.
+    Flow,
+}
+
+/// Turns a flow into rows of named features. `name` and `version` identify
+/// the values in [`FeatureManifest`]s, so `version` has to change whenever
+/// an extractor starts computing anything differently.
+pub trait FeatureExtractor: Debug + Send + Sync {
+    fn name(&self) -> &str;
+    fn version(&self) -> u32;
+    fn granularity(&self) -> Granularity;
+    /// One name per column of every row [`FeatureExtractor::extract`] returns.
+    fn feature_names(&self) -> Vec<String>;
+    fn extract(&self, flow: &IpProtocol) -> Vec<Vec<f64>>;
+}
+
+/// The per-packet values the model was first trained on: both ports, the
+/// record's packet count, duration in milliseconds, IP header length,
+/// direction and bytes. The TCP and UDP columns are zero for packets of other
+/// protocols, so every row has the same width.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketFeatures {
+    tcp: bool,
+    udp: bool,
+}
+
+impl PacketFeatures {
+    /// Adds the TCP flag byte and header length.
+    pub fn with_tcp(mut self, tcp: bool) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Adds the UDP length and payload length.
+    pub fn with_udp(mut self, udp: bool) -> Self {
+        self.udp = udp;
+        self
+    }
+}
+
+/// The packet and byte counters of aggregated records span many orders of
+/// magnitude, so they enter the model log-scaled.
+fn base_values(port_source: u16, port_destination: u16, packet: &BasePacket) -> Vec<f64> {
+    vec![
+        port_source as f64,
+        port_destination as f64,
+        (packet.packets as f64).ln_1p(),
+        packet.packet_duration().num_milliseconds() as f64,
+        packet.ip_header_length as f64,
+        match packet.direction {
+            PacketDirection::Outgoing => 1.,
+            PacketDirection::Incoming => 0.,
+            PacketDirection::Unknown => 0.5,
+        },
+        (packet.bytes as f64).ln_1p(),
+    ]
+}
+
+impl FeatureExtractor for PacketFeatures {
+    fn name(&self) -> &str {
+        "packet"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Packet
+    }
+
+    fn feature_names(&self) -> Vec<String> {
+        let mut names = vec![
+            "port_source",
+            "port_destination",
+            "log_packets",
+            "duration_ms",
+            "ip_header_length",
+            "direction",
+            "log_bytes",
+        ];
+        if self.tcp {
+            names.extend(["tcp_flags", "tcp_header_length"]);
+        }
+        if self.udp {
+            names.extend(["udp_length", "udp_payload_length"]);
+        }
+        names.into_iter().map(String::from).collect()
+    }
+
+    fn extract(&self, flow: &IpProtocol) -> Vec<Vec<f64>> {
+        let tcp_width = if self.tcp { 2 } else { 0 };
+        let udp_width = if self.udp { 2 } else { 0 };
+        let row = |port_source, port_destination, packet, tcp: [f64; 2], udp: [f64; 2]| {
+            let mut row = base_values(port_source, port_destination, packet);
+            row.extend(&tcp[..tcp_width]);
+            row.extend(&udp[..udp_width]);
+            row
+        };
+        match flow {
+            IpProtocol::Tcp(data) => data
+                .packets
+                .iter()
+                .map(|packet| {
+                    let tcp = [packet.tcp_flags.bits() as f64, packet.tcp_header_len as f64];
+                    row(
+                        data.port_source,
+                        data.port_destination,
+                        &packet.base,
+                        tcp,
+                        [0.; 2],
+                    )
+                })
+                .collect(),
+            IpProtocol::Udp(data) => data
+                .packets
+                .iter()
+                .map(|packet| {
+                    let udp = [packet.udp_length as f64, packet.payload_length as f64];
+                    row(
+                        data.port_source,
+                        data.port_destination,
+                        &packet.base,
+                        [0.; 2],
+                        udp,
+                    )
+                })
+                .collect(),
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => data
+                .packets
+                .iter()
+                .map(|packet| {
+                    row(
+                        data.port_source,
+                        data.port_destination,
+                        packet,
+                        [0.; 2],
+                        [0.; 2],
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The CICFlowMeter statistics of [`FlowFeatures`], one row per flow.
+#[derive(Clone, Copy, Debug)]
+pub struct FlowStatistics {
+    activity_timeout: TimeDelta,
+}
+
+impl Default for FlowStatistics {
+    fn default() -> Self {
+        Self::new(DEFAULT_ACTIVITY_TIMEOUT)
+    }
+}
+
+impl FlowStatistics {
+    pub fn new(activity_timeout: TimeDelta) -> Self {
+        Self { activity_timeout }
+    }
+}
+
+impl FeatureExtractor for FlowStatistics {
+    fn name(&self) -> &str {
+        "flow_statistics"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Flow
+    }
+
+    fn feature_names(&self) -> Vec<String> {
+        FEATURE_COLUMNS.map(String::from).to_vec()
+    }
+
+    fn extract(&self, flow: &IpProtocol) -> Vec<Vec<f64>> {
+        vec![FlowFeatures::new(flow, self.activity_timeout).values()]
+    }
+}
+
+/// The rows of a per-packet extractor for the first `length` packets, laid
+/// out one after another in a single flow row. Shorter flows are padded with
+/// zeros. It is named after the wrapped extractor, e.g. `first_32_packet`,
+/// and shares its version.
+#[derive(Clone, Debug)]
+pub struct PacketSequence {
+    name: String,
+    packets: Arc<dyn FeatureExtractor>,
+    length: usize,
+}
+
+impl PacketSequence {
+    /// Panics unless `packets` is a per-packet extractor.
+    pub fn new(packets: Arc<dyn FeatureExtractor>, length: usize) -> Self {
+        assert_eq!(
+            packets.granularity(),
+            Granularity::Packet,
+            "{} is not a per-packet extractor",
+            packets.name()
+        );
+        Self {
+            name: format!("first_{length}_{}", packets.name()),
+            packets,
+            length,
+        }
+    }
+}
+
+impl FeatureExtractor for PacketSequence {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version(&self) -> u32 {
+        self.packets.version()
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Flow
+    }
+
+    fn feature_names(&self) -> Vec<String> {
+        let names = self.packets.feature_names();
+        (0..self.length)
+            .flat_map(|position| names.iter().map(move |name| format!("{name}[{position}]")))
+            .collect()
+    }
+
+    fn extract(&self, flow: &IpProtocol) -> Vec<Vec<f64>> {
+        let width = self.packets.feature_names().len();
+        let mut row = vec![0.; self.length * width];
+        for (position, packet) in self
+            .packets
+            .extract(flow)
+            .into_iter()
+            .take(self.length)
+            .enumerate()
+        {
+            row[position * width..][..width].copy_from_slice(&packet);
+        }
+        vec![row]
+    }
+}
+
+/// Several extractors side by side. The set is per-packet as soon as one of
+/// them is, with the per-flow columns repeated on every packet row.
+#[derive(Clone, Debug)]
+pub struct FeatureSet {
+    extractors: Vec<Arc<dyn FeatureExtractor>>,
+}
+
+impl Default for FeatureSet {
+    /// [`PacketFeatures`] without the protocol specific columns.
+    fn default() -> Self {
+        Self::new().with(PacketFeatures::default())
+    }
+}
+
+impl FeatureSet {
+    pub fn new() -> Self {
+        Self { extractors: vec![] }
+    }
+
+    pub fn with(mut self, extractor: impl FeatureExtractor + 'static) -> Self {
+        self.extractors.push(Arc::new(extractor));
+        self
+    }
+
+    pub fn granularity(&self) -> Granularity {
+        if self
+            .extractors
+            .iter()
+            .any(|extractor| extractor.granularity() == Granularity::Packet)
+        {
+            Granularity::Packet
+        } else {
+            Granularity::Flow
+        }
+    }
+
+    pub fn feature_names(&self) -> Vec<String> {
+        self.extractors
+            .iter()
+            .flat_map(|extractor| extractor.feature_names())
+            .collect()
+    }
+
+    pub fn width(&self) -> usize {
+        self.feature_names().len()
+    }
+
+    pub fn extract(&self, flow: &IpProtocol) -> Vec<Vec<f64>> {
+        let parts = self
+            .extractors
+            .iter()
+            .map(|extractor| (extractor.granularity(), extractor.extract(flow)))
+            .collect::<Vec<_>>();
+        let rows = match self.granularity() {
+            Granularity::Flow => 1,
+            Granularity::Packet => parts
+                .iter()
+                .find(|(granularity, _)| *granularity == Granularity::Packet)
+                .map_or(0, |(_, rows)| rows.len()),
+        };
+        (0..rows)
+            .map(|row| {
+                parts
+                    .iter()
+                    .flat_map(|(granularity, rows)| match granularity {
+                        Granularity::Packet => &rows[row],
+                        Granularity::Flow => &rows[0],
+                    })
+                    .copied()
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn manifest(&self) -> FeatureManifest {
+        FeatureManifest {
+            extractors: self
+                .extractors
+                .iter()
+                .map(|extractor| ExtractorVersion {
+                    name: extractor.name().to_string(),
+                    version: extractor.version(),
+                })
+                .collect(),
+            granularity: self.granularity(),
+            feature_names: self.feature_names(),
+        }
+    }
+
+    /// Fails unless `manifest` describes exactly these features, e.g. the
+    /// ones a checkpoint was trained on.
+    pub fn check(&self, manifest: &FeatureManifest) -> Result<(), FeatureError> {
+        let current = self.manifest();
+        if current == *manifest {
+            Ok(())
+        } else {
+            Err(FeatureError::Mismatch {
+                expected: manifest.describe(),
+                found: current.describe(),
+            })
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractorVersion {
+    pub name: String,
+    pub version: u32,
+}
+
+/// What a model was trained on, stored next to its checkpoints.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeatureManifest {
+    pub extractors: Vec<ExtractorVersion>,
+    pub granularity: Granularity,
+    pub feature_names: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum FeatureError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    Mismatch {
+        expected: String,
+        found: String,
+    },
+}
+
+impl Display for FeatureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeatureError::Io { path, source } => {
+                write!(f, "could not access {}: {source}", path.display())
+            }
+            FeatureError::Json { path, source } => {
+                write!(f, "invalid feature manifest {}: {source}", path.display())
+            }
+            FeatureError::Mismatch { expected, found } => {
+                write!(f, "expected features {expected}, found {found}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FeatureError {}
+
+impl FeatureManifest {
+    pub const FILE_NAME: &'static str = "features.json";
+
+    fn describe(&self) -> String {
+        let extractors = self
+            .extractors
+            .iter()
+            .map(|extractor| format!("{} v{}", extractor.name, extractor.version))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("[{extractors}] with {} columns", self.feature_names.len())
+    }
+
+    /// Writes the manifest to [`FeatureManifest::FILE_NAME`] in `directory`.
+    pub fn save(&self, directory: impl AsRef<Path>) -> Result<(), FeatureError> {
+        let path = directory.as_ref().join(Self::FILE_NAME);
+        let file = fs::File::create(&path).map_err(|source| FeatureError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .map_err(|source| FeatureError::Json { path, source })
+    }
+
+    pub fn load(directory: impl AsRef<Path>) -> Result<Self, FeatureError> {
+        let path = directory.as_ref().join(Self::FILE_NAME);
+        let file = fs::File::open(&path).map_err(|source| FeatureError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|source| FeatureError::Json { path, source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_pcap::get_pcap_data;
+    use crate::test_support::fixture;
+
+    /// Two TCP flows, the first with four packets, and a UDP flow.
+    fn capture_flows() -> Vec<IpProtocol> {
+        get_pcap_data(fixture("capture.pcap")).unwrap().flows
+    }
+
+    fn packet_features() -> PacketFeatures {
+        PacketFeatures::default().with_tcp(true).with_udp(true)
+    }
+
+    #[test]
+    fn names_match_the_row_width() {
+        let extractors: Vec<Arc<dyn FeatureExtractor>> = vec![
+            Arc::new(PacketFeatures::default()),
+            Arc::new(packet_features()),
+            Arc::new(FlowStatistics::default()),
+            Arc::new(PacketSequence::new(Arc::new(packet_features()), 3)),
+        ];
+        for flow in capture_flows() {
+            for extractor in &extractors {
+                let width = extractor.feature_names().len();
+                for row in extractor.extract(&flow) {
+                    assert_eq!(row.len(), width, "{}", extractor.name());
+                }
+            }
+            let set = FeatureSet::new()
+                .with(packet_features())
+                .with(FlowStatistics::default());
+            for row in set.extract(&flow) {
+                assert_eq!(row.len(), set.feature_names().len());
+                assert_eq!(row.len(), set.width());
+            }
+        }
+    }
+
+    #[test]
+    fn flow_columns_repeat_on_every_packet_row() {
+        let flow = &capture_flows()[0];
+        let set = FeatureSet::new()
+            .with(packet_features())
+            .with(FlowStatistics::default());
+        assert_eq!(set.granularity(), Granularity::Packet);
+
+        let rows = set.extract(flow);
+        let packets = packet_features().extract(flow);
+        let statistics = &FlowStatistics::default().extract(flow)[0];
+        assert_eq!(rows.len(), 4);
+        for (row, packet) in rows.iter().zip(&packets) {
+            let (packet_columns, flow_columns) = row.split_at(packet.len());
+            assert_eq!(packet_columns, packet.as_slice());
+            assert_eq!(flow_columns, statistics.as_slice());
+        }
+
+        // Without a per-packet extractor the set has one row per flow
+        let flow_only = FeatureSet::new().with(FlowStatistics::default());
+        assert_eq!(flow_only.granularity(), Granularity::Flow);
+        assert_eq!(flow_only.extract(flow), vec![statistics.clone()]);
+    }
+
+    #[test]
+    fn packet_sequences_pad_and_truncate() {
+        let flow = &capture_flows()[0];
+        let packets = packet_features().extract(flow);
+        let width = packets[0].len();
+
+        let padded = PacketSequence::new(Arc::new(packet_features()), 6);
+        assert_eq!(padded.name(), "first_6_packet");
+        assert_eq!(padded.granularity(), Granularity::Flow);
+        let row = &padded.extract(flow)[0];
+        assert_eq!(row.len(), 6 * width);
+        assert_eq!(row[..4 * width], packets.concat());
+        assert!(row[4 * width..].iter().all(|&value| value == 0.));
+        assert_eq!(padded.feature_names()[width], "port_source[1]");
+
+        let truncated = PacketSequence::new(Arc::new(packet_features()), 2);
+        assert_eq!(truncated.extract(flow), vec![packets[..2].concat()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "flow_statistics is not a per-packet extractor")]
+    fn packet_sequences_need_packet_rows() {
+        PacketSequence::new(Arc::new(FlowStatistics::default()), 4);
+    }
+
+    #[test]
+    fn manifests_tell_feature_sets_apart() {
+        let set = FeatureSet::default().with(FlowStatistics::default());
+        assert!(set.check(&set.manifest()).is_ok());
+        let other = FeatureSet::new().with(packet_features());
+        assert!(matches!(
+            set.check(&other.manifest()),
+            Err(FeatureError::Mismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,541 @@
+use crate::categories::{IpProtocol, PacketDirection};
+use crate::data_structure::{BasePacket, Data, TcpFlags, TcpPacket, UdpPacket};
+use crate::parse_data::{DirectionConvention, FlowReader, ParseError, ParseMode, ParsedFile};
+use crate::parse_pcap::{get_pcap_data, is_capture};
+use chrono::{DateTime, Utc};
+use memmap2::Mmap;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::Xxh3;
+
+const MAGIC: &[u8; 8] = b"VPNFLOWS";
+/// Bump whenever the column layout or the meaning of a parsed field changes,
+/// so caches written by older builds are re-parsed instead of misread.
+pub const SCHEMA_VERSION: u32 = 6;
+const HEADER_LEN: usize = 8 + 4 + 8 + 8 + 8 + 8;
+const ADDRESS_WIDTH: usize = 17;
+const TIMESTAMP_WIDTH: usize = 12;
+
+/// Parses `path` through the cache in `cache_dir`: a cache written for the
+/// same source hash and schema version is memory-mapped and decoded, anything
+/// else is parsed from JSON, or from the capture for pcap and pcapng files,
+/// and written back for next time. JSON directions depend on `convention`, so
+/// each convention gets its own cache file.
+pub fn load_or_parse(
+    path: &Path,
+    cache_dir: Option<&Path>,
+    convention: DirectionConvention,
+) -> Result<ParsedFile, ParseError> {
+    let Some(cache_dir) = cache_dir else {
+        return parse(path, convention);
+    };
+    let source_hash = hash_file(path).map_err(|source| ParseError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let cache_path = cache_path(cache_dir, path, convention);
+    if let Some(parsed) = read_cache(&cache_path, source_hash) {
+        return Ok(parsed);
+    }
+
+    let parsed = parse(path, convention)?;
+    if let Err(err) = write_cache(&cache_path, source_hash, &parsed) {
+        log::warn!("could not write flow cache {}: {err}", cache_path.display());
+    }
+    Ok(parsed)
+}
+
+fn parse(path: &Path, convention: DirectionConvention) -> Result<ParsedFile, ParseError> {
+    if is_capture(path) {
+        get_pcap_data(path)
+    } else {
+        FlowReader::open(path, ParseMode::Lenient)?
+            .with_direction_convention(convention)
+            .read_all()
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(hasher.digest());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+fn cache_path(cache_dir: &Path, source: &Path, convention: DirectionConvention) -> PathBuf {
+    let stem = source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let mut hasher = Xxh3::new();
+    hasher.update(source.as_os_str().as_encoded_bytes());
+    hasher.update(&[convention as u8]);
+    let key = hasher.digest();
+    cache_dir.join(format!("{stem}-{key:016x}.flows"))
+}
+
+fn encode_address(column: &mut Vec<u8>, address: Option<IpAddr>) {
+    let mut encoded = [0; ADDRESS_WIDTH];
+    match address {
+        None => {}
+        Some(IpAddr::V4(address)) => {
+            encoded[0] = 4;
+            encoded[1..5].copy_from_slice(&address.octets());
+        }
+        Some(IpAddr::V6(address)) => {
+            encoded[0] = 6;
+            encoded[1..].copy_from_slice(&address.octets());
+        }
+    }
+    column.extend_from_slice(&encoded);
+}
+
+fn decode_address(encoded: &[u8]) -> Option<IpAddr> {
+    match encoded[0] {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(
+            encoded[1], encoded[2], encoded[3], encoded[4],
+        ))),
+        6 => Some(IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(&encoded[1..]).ok()?,
+        ))),
+        _ => None,
+    }
+}
+
+/// Seconds and subsecond nanoseconds, since nanoseconds alone only reach
+/// the years 1677 to 2262 and the parser accepts any year chrono does.
+fn encode_timestamp(column: &mut Vec<u8>, timestamp: DateTime<Utc>) {
+    column.extend(timestamp.timestamp().to_le_bytes());
+    column.extend(timestamp.timestamp_subsec_nanos().to_le_bytes());
+}
+
+fn decode_timestamp(encoded: [u8; TIMESTAMP_WIDTH]) -> Option<DateTime<Utc>> {
+    let seconds = i64::from_le_bytes(encoded[..8].try_into().ok()?);
+    let nanoseconds = u32::from_le_bytes(encoded[8..].try_into().ok()?);
+    DateTime::from_timestamp(seconds, nanoseconds)
+}
+
+/// One growable buffer per column, written out back to back.
+#[derive(Default)]
+struct Columns {
+    protocol: Vec<u8>,
+    port_source: Vec<u8>,
+    port_destination: Vec<u8>,
+    ip_source: Vec<u8>,
+    ip_destination: Vec<u8>,
+    packet_end: Vec<u8>,
+    bytes: Vec<u8>,
+    direction: Vec<u8>,
+    ip_header_length: Vec<u8>,
+    packets: Vec<u8>,
+    timestamp_start: Vec<u8>,
+    timestamp_end: Vec<u8>,
+    tcp_header_len: Vec<u8>,
+    tcp_flags: Vec<u8>,
+    tcp_acknowledgment_number: Vec<u8>,
+    tcp_sequence_number: Vec<u8>,
+    udp_length: Vec<u8>,
+}
+
+impl Columns {
+    fn push_base(&mut self, packet: &BasePacket) {
+        self.bytes.extend(packet.bytes.to_le_bytes());
+        self.direction.push(match packet.direction {
+            PacketDirection::Outgoing => 0,
+            PacketDirection::Incoming => 1,
+            PacketDirection::Unknown => 2,
+        });
+        self.ip_header_length
+            .extend(packet.ip_header_length.to_le_bytes());
+        self.packets.extend(packet.packets.to_le_bytes());
+        encode_timestamp(&mut self.timestamp_start, packet.timestamp_start);
+        encode_timestamp(&mut self.timestamp_end, packet.timestamp_end);
+    }
+
+    fn push_tcp(&mut self, packet: Option<&TcpPacket>) {
+        let (header_len, flags, acknowledgment, sequence) = packet.map_or((0, 0, 0, 0), |tcp| {
+            (
+                tcp.tcp_header_len,
+                tcp.tcp_flags.bits(),
+                tcp.tcp_acknowledgment_number,
+                tcp.tcp_sequence_number,
+            )
+        });
+        self.tcp_header_len.extend(header_len.to_le_bytes());
+        self.tcp_flags.push(flags);
+        self.tcp_acknowledgment_number
+            .extend(acknowledgment.to_le_bytes());
+        self.tcp_sequence_number.extend(sequence.to_le_bytes());
+    }
+
+    fn push_udp(&mut self, packet: Option<&UdpPacket>) {
+        let udp_length = packet.map_or(0, |udp| udp.udp_length);
+        self.udp_length.extend(udp_length.to_le_bytes());
+    }
+
+    fn push_flow<P: Clone + std::fmt::Debug>(&mut self, protocol: u8, data: &Data<P>) {
+        self.protocol.push(protocol);
+        self.port_source.extend(data.port_source.to_le_bytes());
+        self.port_destination
+            .extend(data.port_destination.to_le_bytes());
+        encode_address(&mut self.ip_source, data.ip_source);
+        encode_address(&mut self.ip_destination, data.ip_destination);
+    }
+
+    fn in_order(&self) -> [&[u8]; 17] {
+        [
+            &self.protocol,
+            &self.port_source,
+            &self.port_destination,
+            &self.ip_source,
+            &self.ip_destination,
+            &self.packet_end,
+            &self.bytes,
+            &self.direction,
+            &self.ip_header_length,
+            &self.packets,
+            &self.timestamp_start,
+            &self.timestamp_end,
+            &self.tcp_header_len,
+            &self.tcp_flags,
+            &self.tcp_acknowledgment_number,
+            &self.tcp_sequence_number,
+            &self.udp_length,
+        ]
+    }
+}
+
+fn write_cache(cache_path: &Path, source_hash: u64, parsed: &ParsedFile) -> io::Result<()> {
+    let mut columns = Columns::default();
+    let mut packet_count = 0u64;
+    for flow in &parsed.flows {
+        let protocol = flow.number();
+        match flow {
+            IpProtocol::Tcp(data) => {
+                columns.push_flow(protocol, data);
+                for packet in &data.packets {
+                    columns.push_base(&packet.base);
+                    columns.push_tcp(Some(packet));
+                    columns.push_udp(None);
+                }
+                packet_count += data.packets.len() as u64;
+            }
+            IpProtocol::Udp(data) => {
+                columns.push_flow(protocol, data);
+                for packet in &data.packets {
+                    columns.push_base(&packet.base);
+                    columns.push_tcp(None);
+                    columns.push_udp(Some(packet));
+                }
+                packet_count += data.packets.len() as u64;
+            }
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => {
+                columns.push_flow(protocol, data);
+                for packet in &data.packets {
+                    columns.push_base(packet);
+                    columns.push_tcp(None);
+                    columns.push_udp(None);
+                }
+                packet_count += data.packets.len() as u64;
+            }
+        }
+        columns.packet_end.extend(packet_count.to_le_bytes());
+    }
+
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Written under a temporary name and renamed, so a crash never leaves a
+    // truncated cache with a valid header behind.
+    let partial_path = cache_path.with_extension("partial");
+    let mut writer = BufWriter::new(fs::File::create(&partial_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&SCHEMA_VERSION.to_le_bytes())?;
+    writer.write_all(&source_hash.to_le_bytes())?;
+    writer.write_all(&(parsed.skipped_flows as u64).to_le_bytes())?;
+    writer.write_all(&(parsed.flows.len() as u64).to_le_bytes())?;
+    writer.write_all(&packet_count.to_le_bytes())?;
+    for column in columns.in_order() {
+        writer.write_all(column)?;
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(partial_path, cache_path)
+}
+
+/// A borrowed column of `WIDTH` byte little-endian values.
+#[derive(Clone, Copy)]
+struct Column<'a, const WIDTH: usize>(&'a [u8]);
+
+impl<const WIDTH: usize> Column<'_, WIDTH> {
+    fn get(&self, row: usize) -> [u8; WIDTH] {
+        self.0[row * WIDTH..(row + 1) * WIDTH].try_into().unwrap()
+    }
+}
+
+/// Hands out consecutive fixed-width columns of a memory-mapped cache.
+struct ColumnReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ColumnReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(len)?;
+        let slice = self.data.get(self.offset..end)?;
+        self.offset = end;
+        Some(slice)
+    }
+
+    fn column<const WIDTH: usize>(&mut self, rows: usize) -> Option<Column<'a, WIDTH>> {
+        Some(Column(self.take(rows.checked_mul(WIDTH)?)?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+fn read_cache(cache_path: &Path, source_hash: u64) -> Option<ParsedFile> {
+    let file = fs::File::open(cache_path).ok()?;
+    // SAFETY: the cache directory is private to this tool and files are only
+    // ever replaced by rename, never modified in place while mapped.
+    let map = unsafe { Mmap::map(&file) }.ok()?;
+    if map.len() < HEADER_LEN || &map[..8] != MAGIC {
+        return None;
+    }
+    let mut reader = ColumnReader {
+        data: &map,
+        offset: 8,
+    };
+    let schema = u32::from_le_bytes(reader.take(4)?.try_into().ok()?);
+    if schema != SCHEMA_VERSION || reader.u64()? != source_hash {
+        return None;
+    }
+    let skipped_flows = reader.u64()? as usize;
+    let flow_count = reader.u64()? as usize;
+    let packet_count = reader.u64()? as usize;
+
+    let protocol = reader.column::<1>(flow_count)?;
+    let port_source = reader.column::<2>(flow_count)?;
+    let port_destination = reader.column::<2>(flow_count)?;
+    let ip_source = reader.column::<ADDRESS_WIDTH>(flow_count)?;
+    let ip_destination = reader.column::<ADDRESS_WIDTH>(flow_count)?;
+    let packet_end = reader.column::<8>(flow_count)?;
+    let bytes = reader.column::<8>(packet_count)?;
+    let direction = reader.column::<1>(packet_count)?;
+    let ip_header_length = reader.column::<2>(packet_count)?;
+    let packets = reader.column::<4>(packet_count)?;
+    let timestamp_start = reader.column::<TIMESTAMP_WIDTH>(packet_count)?;
+    let timestamp_end = reader.column::<TIMESTAMP_WIDTH>(packet_count)?;
+    let tcp_header_len = reader.column::<2>(packet_count)?;
+    let tcp_flags = reader.column::<1>(packet_count)?;
+    let tcp_acknowledgment_number = reader.column::<4>(packet_count)?;
+    let tcp_sequence_number = reader.column::<4>(packet_count)?;
+    let udp_length = reader.column::<2>(packet_count)?;
+
+    let base_packet = |index: usize| {
+        Some(BasePacket {
+            bytes: u64::from_le_bytes(bytes.get(index)),
+            direction: match direction.get(index)[0] {
+                0 => PacketDirection::Outgoing,
+                1 => PacketDirection::Incoming,
+                _ => PacketDirection::Unknown,
+            },
+            ip_header_length: u16::from_le_bytes(ip_header_length.get(index)),
+            packets: u32::from_le_bytes(packets.get(index)),
+            timestamp_start: decode_timestamp(timestamp_start.get(index))?,
+            timestamp_end: decode_timestamp(timestamp_end.get(index))?,
+        })
+    };
+
+    let mut flows = Vec::with_capacity(flow_count);
+    let mut packet_start = 0;
+    for flow in 0..flow_count {
+        let end = u64::from_le_bytes(packet_end.get(flow)) as usize;
+        if end < packet_start || end > packet_count {
+            return None;
+        }
+        let range = packet_start..end;
+        packet_start = end;
+        let ip_source = decode_address(&ip_source.get(flow));
+        let ip_destination = decode_address(&ip_destination.get(flow));
+        let port_source = u16::from_le_bytes(port_source.get(flow));
+        let port_destination = u16::from_le_bytes(port_destination.get(flow));
+        if protocol.get(flow)[0] == IpProtocol::UDP {
+            flows.push(IpProtocol::Udp(Data {
+                ip_destination,
+                ip_source,
+                port_destination,
+                port_source,
+                packets: range
+                    .map(|index| {
+                        Some(UdpPacket::new(
+                            base_packet(index)?,
+                            u16::from_le_bytes(udp_length.get(index)),
+                        ))
+                    })
+                    .collect::<Option<_>>()?,
+            }));
+            continue;
+        }
+        if protocol.get(flow)[0] == IpProtocol::TCP {
+            flows.push(IpProtocol::Tcp(Data {
+                ip_destination,
+                ip_source,
+                port_destination,
+                port_source,
+                packets: range
+                    .map(|index| {
+                        Some(TcpPacket {
+                            base: base_packet(index)?,
+                            tcp_header_len: u16::from_le_bytes(tcp_header_len.get(index)),
+                            tcp_flags: TcpFlags::from_bits_retain(tcp_flags.get(index)[0]),
+                            tcp_acknowledgment_number: u32::from_le_bytes(
+                                tcp_acknowledgment_number.get(index),
+                            ),
+                            tcp_sequence_number: u32::from_le_bytes(tcp_sequence_number.get(index)),
+                        })
+                    })
+                    .collect::<Option<_>>()?,
+            }));
+            continue;
+        }
+        let data = Data {
+            ip_destination,
+            ip_source,
+            port_destination,
+            port_source,
+            packets: range.map(base_packet).collect::<Option<_>>()?,
+        };
+        flows.push(IpProtocol::from_number(protocol.get(flow)[0], data));
+    }
+    Some(ParsedFile {
+        flows,
+        skipped_flows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixture, scratch_dir};
+    use chrono::Datelike;
+
+    #[test]
+    fn caches_captures_like_json() {
+        let cache = scratch_dir("flow-cache-capture");
+        let convention = DirectionConvention::default();
+        let parsed = load_or_parse(&fixture("capture.pcap"), Some(&cache), convention).unwrap();
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+        let cached = load_or_parse(&fixture("capture.pcap"), Some(&cache), convention).unwrap();
+        assert_eq!(format!("{:?}", parsed.flows), format!("{:?}", cached.flows));
+        assert_eq!(cached.flows.len(), 3);
+    }
+
+    #[test]
+    fn keeps_counters_at_their_maximum() {
+        let directory = scratch_dir("flow-cache-counters");
+        let packet = |bytes: &str| {
+            format!(
+                r#"{{"bytes": "{bytes}", "ip_header_len": "65535", "packets": "{}", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:01"}}"#,
+                u32::MAX
+            )
+        };
+        // 256 packets, one more than the old u8 counters could index.
+        let mut packets = vec![packet(&i64::MAX.to_string()); 255];
+        packets.push(packet(&i64::MIN.to_string()));
+        let source = directory.join("flows.json");
+        fs::write(
+            &source,
+            format!(
+                r#"[{{"ip_proto": "gre", "port_src": 0, "port_dst": 0, "x_packets": [{}]}}]"#,
+                packets.join(",")
+            ),
+        )
+        .unwrap();
+
+        let cache = directory.join("cache");
+        let convention = DirectionConvention::default();
+        let parsed = load_or_parse(&source, Some(&cache), convention).unwrap();
+        let cached = load_or_parse(&source, Some(&cache), convention).unwrap();
+        assert_eq!(format!("{:?}", parsed.flows), format!("{:?}", cached.flows));
+        let packets: Vec<&BasePacket> = (&cached.flows[0]).into();
+        assert_eq!(packets.len(), 256);
+        assert_eq!(packets[0].bytes, i64::MAX as u64);
+        assert_eq!(packets[255].bytes, 1 << 63);
+        assert_eq!(packets[255].direction, PacketDirection::Incoming);
+        assert_eq!(packets[0].packets, u32::MAX);
+        assert_eq!(packets[0].ip_header_length, u16::MAX);
+    }
+
+    #[test]
+    fn keeps_protocols_without_a_variant() {
+        let directory = scratch_dir("flow-cache-other");
+        let source = directory.join("flows.json");
+        let flow = |ip_proto: &str| {
+            format!(
+                r#"{{"ip_proto": {ip_proto}, "ip_src": "10.0.0.1", "ip_dst": "10.0.0.2", "port_src": 0, "port_dst": 0, "x_packets": [{{"bytes": "60", "ip_header_len": "20", "packets": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"}}]}}"#
+            )
+        };
+        fs::write(
+            &source,
+            format!(
+                "[{}, {}, {}]",
+                flow(r#""sctp""#),
+                flow("253"),
+                flow(r#""esp""#)
+            ),
+        )
+        .unwrap();
+
+        let cache = directory.join("cache");
+        let convention = DirectionConvention::default();
+        let parsed = load_or_parse(&source, Some(&cache), convention).unwrap();
+        let cached = load_or_parse(&source, Some(&cache), convention).unwrap();
+        assert_eq!(format!("{:?}", parsed.flows), format!("{:?}", cached.flows));
+        assert!(matches!(
+            cached.flows[0],
+            IpProtocol::Other(IpProtocol::SCTP, _)
+        ));
+        assert!(matches!(cached.flows[1], IpProtocol::Other(253, _)));
+        assert!(matches!(cached.flows[2], IpProtocol::Esp(_)));
+    }
+
+    #[test]
+    fn keeps_timestamps_outside_the_nanosecond_range() {
+        let directory = scratch_dir("flow-cache-timestamps");
+        let source = directory.join("flows.json");
+        // Epoch seconds in the year 5138, and before 1677
+        fs::write(
+            &source,
+            r#"[{"ip_proto": "gre", "port_src": 0, "port_dst": 0, "x_packets": [{"bytes": "60", "ip_header_len": "20", "packets": "1", "timestamp_start": "99999999999", "timestamp_end": "1500-01-01T00:00:00.5Z"}]}]"#,
+        )
+        .unwrap();
+
+        let cache = directory.join("cache");
+        let convention = DirectionConvention::default();
+        let parsed = load_or_parse(&source, Some(&cache), convention).unwrap();
+        let cached = load_or_parse(&source, Some(&cache), convention).unwrap();
+        assert_eq!(format!("{:?}", parsed.flows), format!("{:?}", cached.flows));
+        let packets: Vec<&BasePacket> = (&cached.flows[0]).into();
+        assert_eq!(packets[0].timestamp_start.timestamp(), 99_999_999_999);
+        assert_eq!(packets[0].timestamp_start.year(), 5138);
+        assert_eq!(
+            packets[0].timestamp_end,
+            "1500-01-01T00:00:00.5Z".parse::<DateTime<Utc>>().unwrap()
+        );
+    }
+}
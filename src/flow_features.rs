@@ -0,0 +1,379 @@
+use crate::categories::IpProtocol;
+use crate::data_structure::{BasePacket, Data, TcpFlags, TcpPacket, UdpPacket};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::fmt::Debug;
+
+/// CICFlowMeter's default activity timeout: a silence longer than this ends an
+/// active period and counts as idle time.
+pub const DEFAULT_ACTIVITY_TIMEOUT: TimeDelta = TimeDelta::seconds(5);
+
+/// Column names in the order [`FlowFeatures::values`] returns them, spelled
+/// the way CICFlowMeter writes them in the ISCX-VPN and CIC-IDS CSVs.
+pub const FEATURE_COLUMNS: [&str; 66] = [
+    "Flow Duration",
+    "Total Fwd Packets",
+    "Total Backward Packets",
+    "Total Length of Fwd Packets",
+    "Total Length of Bwd Packets",
+    "Fwd Packet Length Max",
+    "Fwd Packet Length Min",
+    "Fwd Packet Length Mean",
+    "Fwd Packet Length Std",
+    "Bwd Packet Length Max",
+    "Bwd Packet Length Min",
+    "Bwd Packet Length Mean",
+    "Bwd Packet Length Std",
+    "Flow Bytes/s",
+    "Flow Packets/s",
+    "Flow IAT Mean",
+    "Flow IAT Std",
+    "Flow IAT Max",
+    "Flow IAT Min",
+    "Fwd IAT Total",
+    "Fwd IAT Mean",
+    "Fwd IAT Std",
+    "Fwd IAT Max",
+    "Fwd IAT Min",
+    "Bwd IAT Total",
+    "Bwd IAT Mean",
+    "Bwd IAT Std",
+    "Bwd IAT Max",
+    "Bwd IAT Min",
+    "Fwd PSH Flags",
+    "Bwd PSH Flags",
+    "Fwd URG Flags",
+    "Bwd URG Flags",
+    "Fwd Header Length",
+    "Bwd Header Length",
+    "Fwd Packets/s",
+    "Bwd Packets/s",
+    "Min Packet Length",
+    "Max Packet Length",
+    "Packet Length Mean",
+    "Packet Length Std",
+    "Packet Length Variance",
+    "FIN Flag Count",
+    "SYN Flag Count",
+    "RST Flag Count",
+    "PSH Flag Count",
+    "ACK Flag Count",
+    "URG Flag Count",
+    "ECE Flag Count",
+    "CWE Flag Count",
+    "Down/Up Ratio",
+    "Average Packet Size",
+    "Avg Fwd Segment Size",
+    "Avg Bwd Segment Size",
+    "Subflow Fwd Packets",
+    "Subflow Fwd Bytes",
+    "Subflow Bwd Packets",
+    "Subflow Bwd Bytes",
+    "Active Mean",
+    "Active Std",
+    "Active Max",
+    "Active Min",
+    "Idle Mean",
+    "Idle Std",
+    "Idle Max",
+    "Idle Min",
+];
+
+/// Summary statistics over one series. The standard deviation is the sample
+/// one, as in CICFlowMeter, and everything is zero for an empty series.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub count: usize,
+    pub total: f64,
+    pub mean: f64,
+    pub std: f64,
+    pub max: f64,
+    pub min: f64,
+}
+
+impl Stats {
+    pub fn new(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        let count = values.len();
+        let total: f64 = values.iter().sum();
+        let mean = total / count as f64;
+        let std = if count > 1 {
+            let squares: f64 = values.iter().map(|value| (value - mean).powi(2)).sum();
+            (squares / (count - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+        Self {
+            count,
+            total,
+            mean,
+            std,
+            max: values.iter().copied().fold(f64::MIN, f64::max),
+            min: values.iter().copied().fold(f64::MAX, f64::min),
+        }
+    }
+
+    fn summary(&self) -> [f64; 4] {
+        [self.mean, self.std, self.max, self.min]
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DirectionFeatures {
+    pub packets: u64,
+    pub length: Stats,
+    /// Inter-arrival times within this direction, in microseconds.
+    pub iat: Stats,
+    pub psh_flags: u64,
+    pub urg_flags: u64,
+    pub header_length: u64,
+}
+
+/// CICFlowMeter-style statistics for one flow, split along [`Data::forward`]
+/// and [`Data::backward`]. Lengths are the IP `bytes` of each packet and
+/// times are in microseconds.
+#[derive(Clone, Debug, Default)]
+pub struct FlowFeatures {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub duration: f64,
+    pub forward: DirectionFeatures,
+    pub backward: DirectionFeatures,
+    pub flow_iat: Stats,
+    pub packet_length: Stats,
+    /// Packets with FIN, SYN, RST, PSH, ACK, URG, ECE and CWR set, in that order.
+    pub flag_counts: [u64; 8],
+    pub active: Stats,
+    pub idle: Stats,
+}
+
+struct PacketView<'a> {
+    base: &'a BasePacket,
+    tcp_flags: Option<TcpFlags>,
+    header_length: u64,
+}
+
+impl<'a> PacketView<'a> {
+    fn base(packet: &'a BasePacket) -> Self {
+        Self {
+            base: packet,
+            tcp_flags: None,
+            header_length: u64::from(packet.ip_header_length),
+        }
+    }
+
+    fn udp(packet: &'a UdpPacket) -> Self {
+        Self {
+            base: &packet.base,
+            tcp_flags: None,
+            header_length: u64::from(packet.base.ip_header_length)
+                + u64::from(UdpPacket::HEADER_LENGTH),
+        }
+    }
+
+    fn tcp(packet: &'a TcpPacket) -> Self {
+        Self {
+            base: &packet.base,
+            tcp_flags: Some(packet.tcp_flags),
+            header_length: u64::from(packet.base.ip_header_length)
+                + u64::from(packet.tcp_header_len),
+        }
+    }
+}
+
+fn sorted<'a>(packets: impl Iterator<Item = PacketView<'a>>) -> Vec<PacketView<'a>> {
+    let mut packets: Vec<_> = packets.collect();
+    packets.sort_by_key(|packet| packet.base.timestamp_start);
+    packets
+}
+
+fn microseconds(delta: TimeDelta) -> f64 {
+    delta.num_microseconds().unwrap_or(i64::MAX) as f64
+}
+
+fn iat(packets: &[PacketView]) -> Stats {
+    let gaps: Vec<f64> = packets
+        .windows(2)
+        .map(|pair| microseconds(pair[1].base.timestamp_start - pair[0].base.timestamp_start))
+        .collect();
+    Stats::new(&gaps)
+}
+
+fn per_second(amount: f64, duration: f64) -> f64 {
+    if duration > 0.0 {
+        amount * 1_000_000.0 / duration
+    } else {
+        0.0
+    }
+}
+
+impl DirectionFeatures {
+    fn new(packets: &[PacketView]) -> Self {
+        let lengths: Vec<f64> = packets
+            .iter()
+            .map(|packet| packet.base.bytes as f64)
+            .collect();
+        let flagged = |flag: TcpFlags| {
+            packets
+                .iter()
+                .filter(|packet| packet.tcp_flags.is_some_and(|flags| flags.contains(flag)))
+                .count() as u64
+        };
+        Self {
+            packets: packets
+                .iter()
+                .map(|packet| u64::from(packet.base.packets))
+                .sum(),
+            length: Stats::new(&lengths),
+            iat: iat(packets),
+            psh_flags: flagged(TcpFlags::PSH),
+            urg_flags: flagged(TcpFlags::URG),
+            header_length: packets.iter().map(|packet| packet.header_length).sum(),
+        }
+    }
+}
+
+impl FlowFeatures {
+    pub fn new(flow: &IpProtocol, activity_timeout: TimeDelta) -> Self {
+        match flow {
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => {
+                Self::from_data(data, PacketView::base, activity_timeout)
+            }
+            IpProtocol::Tcp(data) => Self::from_data(data, PacketView::tcp, activity_timeout),
+            IpProtocol::Udp(data) => Self::from_data(data, PacketView::udp, activity_timeout),
+        }
+    }
+
+    fn from_data<'a, P: AsRef<BasePacket> + Clone + Debug>(
+        data: &'a Data<P>,
+        view: fn(&'a P) -> PacketView<'a>,
+        activity_timeout: TimeDelta,
+    ) -> Self {
+        let packets = sorted(data.packets.iter().map(view));
+        let forward = sorted(data.forward().map(view));
+        let backward = sorted(data.backward().map(view));
+
+        let start = packets.first().map(|packet| packet.base.timestamp_start);
+        let end = packets.iter().map(|packet| packet.base.timestamp_end).max();
+        let lengths: Vec<f64> = packets
+            .iter()
+            .map(|packet| packet.base.bytes as f64)
+            .collect();
+        let mut flag_counts = [0; 8];
+        for flags in packets.iter().filter_map(|packet| packet.tcp_flags) {
+            for (flag, count) in TcpFlags::all().iter().zip(&mut flag_counts) {
+                *count += u64::from(flags.contains(flag));
+            }
+        }
+        let (active, idle) = activity(&packets, activity_timeout);
+
+        Self {
+            timestamp: start,
+            duration: start
+                .zip(end)
+                .map(|(start, end)| microseconds(end - start))
+                .unwrap_or_default(),
+            forward: DirectionFeatures::new(&forward),
+            backward: DirectionFeatures::new(&backward),
+            flow_iat: iat(&packets),
+            packet_length: Stats::new(&lengths),
+            flag_counts,
+            active,
+            idle,
+        }
+    }
+
+    /// One value per entry of [`FEATURE_COLUMNS`].
+    pub fn values(&self) -> Vec<f64> {
+        let forward = &self.forward;
+        let backward = &self.backward;
+        let packets = (forward.packets + backward.packets) as f64;
+        let bytes = forward.length.total + backward.length.total;
+        // Subflows are the active periods, so a flow without idle time is one subflow.
+        let subflows = (self.idle.count + 1) as f64;
+        let mut values = vec![
+            self.duration,
+            forward.packets as f64,
+            backward.packets as f64,
+            forward.length.total,
+            backward.length.total,
+            forward.length.max,
+            forward.length.min,
+            forward.length.mean,
+            forward.length.std,
+            backward.length.max,
+            backward.length.min,
+            backward.length.mean,
+            backward.length.std,
+            per_second(bytes, self.duration),
+            per_second(packets, self.duration),
+        ];
+        values.extend(self.flow_iat.summary());
+        values.push(forward.iat.total);
+        values.extend(forward.iat.summary());
+        values.push(backward.iat.total);
+        values.extend(backward.iat.summary());
+        values.extend([
+            forward.psh_flags as f64,
+            backward.psh_flags as f64,
+            forward.urg_flags as f64,
+            backward.urg_flags as f64,
+            forward.header_length as f64,
+            backward.header_length as f64,
+            per_second(forward.packets as f64, self.duration),
+            per_second(backward.packets as f64, self.duration),
+            self.packet_length.min,
+            self.packet_length.max,
+            self.packet_length.mean,
+            self.packet_length.std,
+            self.packet_length.std.powi(2),
+        ]);
+        values.extend(self.flag_counts.map(|count| count as f64));
+        values.extend([
+            if forward.packets > 0 {
+                backward.packets as f64 / forward.packets as f64
+            } else {
+                0.0
+            },
+            if packets > 0.0 { bytes / packets } else { 0.0 },
+            forward.length.mean,
+            backward.length.mean,
+            forward.packets as f64 / subflows,
+            forward.length.total / subflows,
+            backward.packets as f64 / subflows,
+            backward.length.total / subflows,
+        ]);
+        values.extend(self.active.summary());
+        values.extend(self.idle.summary());
+        values
+    }
+}
+
+/// Splits the flow at every silence longer than `timeout`. Active periods
+/// run from the first packet starting to the last one ending before such a
+/// silence; idle periods are the silences themselves.
+fn activity(packets: &[PacketView], timeout: TimeDelta) -> (Stats, Stats) {
+    let Some(first) = packets.first() else {
+        return (Stats::default(), Stats::default());
+    };
+    let mut active = vec![];
+    let mut idle = vec![];
+    let mut period_start = first.base.timestamp_start;
+    let mut period_end = first.base.timestamp_end;
+    for packet in &packets[1..] {
+        let gap = packet.base.timestamp_start - period_end;
+        if gap > timeout {
+            active.push(microseconds(period_end - period_start));
+            idle.push(microseconds(gap));
+            period_start = packet.base.timestamp_start;
+        }
+        period_end = period_end.max(packet.base.timestamp_end);
+    }
+    active.push(microseconds(period_end - period_start));
+    (Stats::new(&active), Stats::new(&idle))
+}
@@ -0,0 +1,463 @@
+use crate::categories::{DataCategory, Encryption, ToPath, VPN};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use strum::IntoEnumIterator;
+
+pub const ENCRYPTED: &str = "encrypted";
+pub const UNENCRYPTED: &str = "unencrypted";
+
+/// Where a flow sits in the label hierarchy: whether it is encrypted, the
+/// tunnel protocol it went through and the application class.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Label {
+    pub group: Arc<str>,
+    pub tunnel: Arc<str>,
+    pub application: Arc<str>,
+}
+
+impl Label {
+    /// The label of a built-in `Encryption` × `DataCategory` combination, as
+    /// it appears in [`LabelRegistry::default`].
+    pub fn builtin(encryption: &Encryption, category: DataCategory) -> Self {
+        let (group, tunnel) = match encryption {
+            Encryption::VPN(vpn) => (ENCRYPTED, vpn.to_string()),
+            Encryption::NonVPN => (UNENCRYPTED, "NonVPN".to_string()),
+        };
+        Self {
+            group: group.into(),
+            tunnel: tunnel.into(),
+            application: category.to_string().into(),
+        }
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.tunnel, self.application)
+    }
+}
+
+#[derive(Debug)]
+pub enum LabelError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// Two tunnels or two applications share a name.
+    Duplicate(String),
+    Empty,
+    UnknownTunnel(String),
+    UnknownApplication(String),
+}
+
+impl Display for LabelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LabelError::Io { path, source } => {
+                write!(f, "could not read {}: {source}", path.display())
+            }
+            LabelError::Json { path, source } => {
+                write!(f, "invalid label file {}: {source}", path.display())
+            }
+            LabelError::Duplicate(name) => write!(f, "label {name:?} is defined twice"),
+            LabelError::Empty => write!(f, "the label file defines no tunnel or no application"),
+            LabelError::UnknownTunnel(name) => write!(f, "unknown encryption {name:?}"),
+            LabelError::UnknownApplication(name) => write!(f, "unknown data category {name:?}"),
+        }
+    }
+}
+
+impl std::error::Error for LabelError {}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TunnelDefinition {
+    pub name: String,
+    /// Directory below the group directory, empty when the files sit in the
+    /// group directory itself.
+    #[serde(default)]
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GroupDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub path: PathBuf,
+    pub tunnels: Vec<TunnelDefinition>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApplicationDefinition {
+    pub name: String,
+    /// File name inside a tunnel directory.
+    pub file: PathBuf,
+}
+
+/// The labels a dataset can carry, e.g.
+///
+/// ```json
+/// {"groups": [{"name": "encrypted", "path": "VPN", "tunnels": [{"name": "IKEv2", "path": "IKEv2"}]}],
+///  "applications": [{"name": "Gaming", "file": "gaming.json"}]}
+/// ```
+///
+/// Tunnels and applications are numbered in the order they are listed, which
+/// is what [`LabelRegistry::target`] and the visualisation legends use.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LabelRegistry {
+    groups: Vec<GroupDefinition>,
+    applications: Vec<ApplicationDefinition>,
+}
+
+impl Default for LabelRegistry {
+    /// The `Encryption` and `DataCategory` enums, with `NonVPN` as tunnel 0
+    /// and the VPNs after it in declaration order.
+    fn default() -> Self {
+        Self {
+            groups: vec![
+                GroupDefinition {
+                    name: UNENCRYPTED.to_string(),
+                    path: PathBuf::from("Non VPN"),
+                    tunnels: vec![TunnelDefinition {
+                        name: "NonVPN".to_string(),
+                        path: PathBuf::new(),
+                    }],
+                },
+                GroupDefinition {
+                    name: ENCRYPTED.to_string(),
+                    path: PathBuf::from("VPN"),
+                    tunnels: VPN::iter()
+                        .map(|vpn| TunnelDefinition {
+                            name: vpn.to_string(),
+                            path: PathBuf::from(vpn.path()),
+                        })
+                        .collect(),
+                },
+            ],
+            applications: DataCategory::iter()
+                .map(|category| ApplicationDefinition {
+                    name: category.to_string(),
+                    file: PathBuf::from(category.path()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Names are compared ignoring case and whitespace, so "Non VPN" finds
+/// "NonVPN".
+pub fn name_key(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+impl LabelRegistry {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LabelError> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).map_err(|source| LabelError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let registry: Self =
+            serde_json::from_reader(BufReader::new(file)).map_err(|source| LabelError::Json {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        registry.check()?;
+        Ok(registry)
+    }
+
+    fn check(&self) -> Result<(), LabelError> {
+        if self.tunnels().next().is_none() || self.applications.is_empty() {
+            return Err(LabelError::Empty);
+        }
+        let mut tunnels = HashSet::new();
+        for (_, tunnel) in self.tunnels() {
+            if !tunnels.insert(name_key(&tunnel.name)) {
+                return Err(LabelError::Duplicate(tunnel.name.clone()));
+            }
+        }
+        let mut applications = HashSet::new();
+        for application in &self.applications {
+            if !applications.insert(name_key(&application.name)) {
+                return Err(LabelError::Duplicate(application.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn tunnels(&self) -> impl Iterator<Item = (&GroupDefinition, &TunnelDefinition)> {
+        self.groups
+            .iter()
+            .flat_map(|group| group.tunnels.iter().map(move |tunnel| (group, tunnel)))
+    }
+
+    pub fn tunnel_names(&self) -> impl Iterator<Item = &str> {
+        self.tunnels().map(|(_, tunnel)| tunnel.name.as_str())
+    }
+
+    pub fn application_names(&self) -> impl Iterator<Item = &str> {
+        self.applications
+            .iter()
+            .map(|application| application.name.as_str())
+    }
+
+    pub fn tunnel_count(&self) -> usize {
+        self.tunnels().count()
+    }
+
+    pub fn application_count(&self) -> usize {
+        self.applications.len()
+    }
+
+    /// Every tunnel × application combination.
+    pub fn labels(&self) -> impl Iterator<Item = Label> + '_ {
+        self.tunnels().flat_map(move |(group, tunnel)| {
+            self.applications.iter().map(move |application| Label {
+                group: group.name.as_str().into(),
+                tunnel: tunnel.name.as_str().into(),
+                application: application.name.as_str().into(),
+            })
+        })
+    }
+
+    /// Looks up a tunnel and an application by name, as written in a mapping
+    /// file.
+    pub fn find(&self, tunnel: &str, application: &str) -> Result<Label, LabelError> {
+        let (group, tunnel) = self
+            .tunnels()
+            .find(|(_, definition)| name_key(&definition.name) == name_key(tunnel))
+            .ok_or_else(|| LabelError::UnknownTunnel(tunnel.to_string()))?;
+        let application = self
+            .applications
+            .iter()
+            .find(|definition| name_key(&definition.name) == name_key(application))
+            .ok_or_else(|| LabelError::UnknownApplication(application.to_string()))?;
+        Ok(Label {
+            group: group.name.as_str().into(),
+            tunnel: tunnel.name.as_str().into(),
+            application: application.name.as_str().into(),
+        })
+    }
+
+    /// Every application of one tunnel, which is looked up like in
+    /// [`LabelRegistry::find`].
+    pub fn tunnel_labels(&self, tunnel: &str) -> Result<Vec<Label>, LabelError> {
+        self.application_names()
+            .map(|application| self.find(tunnel, application))
+            .collect()
+    }
+
+    pub fn tunnel_index(&self, label: &Label) -> Option<usize> {
+        self.tunnels()
+            .position(|(_, tunnel)| *tunnel.name == *label.tunnel)
+    }
+
+    pub fn application_index(&self, label: &Label) -> Option<usize> {
+        self.applications
+            .iter()
+            .position(|application| *application.name == *label.application)
+    }
+
+    /// Class indices for the model, application first and tunnel second.
+    pub fn target(&self, label: &Label) -> Result<[usize; 2], LabelError> {
+        let application = self
+            .application_index(label)
+            .ok_or_else(|| LabelError::UnknownApplication(label.application.to_string()))?;
+        let tunnel = self
+            .tunnel_index(label)
+            .ok_or_else(|| LabelError::UnknownTunnel(label.tunnel.to_string()))?;
+        Ok([application, tunnel])
+    }
+
+    /// Where the standard tree keeps `label`'s file, relative to the root.
+    pub fn path(&self, label: &Label) -> Option<PathBuf> {
+        let (group, tunnel) = self
+            .tunnels()
+            .find(|(_, tunnel)| *tunnel.name == *label.tunnel)?;
+        let application = self
+            .applications
+            .iter()
+            .find(|application| *application.name == *label.application)?;
+        Some(group.path.join(&tunnel.path).join(&application.file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    const REGISTRY: &str = r#"{
+        "groups": [
+            {"name": "plain", "tunnels": [{"name": "None"}]},
+            {"name": "tunnelled", "path": "VPN", "tunnels": [
+                {"name": "IKEv2", "path": "ikev2"},
+                {"name": "Open VPN", "path": "openvpn"}
+            ]}
+        ],
+        "applications": [
+            {"name": "Gaming", "file": "gaming.json"},
+            {"name": "Video Call", "file": "video.json"}
+        ]
+    }"#;
+
+    fn registry(json: &str) -> Result<LabelRegistry, LabelError> {
+        let registry: LabelRegistry = serde_json::from_str(json).unwrap();
+        registry.check().map(|()| registry)
+    }
+
+    #[test]
+    fn rejects_duplicates_and_empty_registries() {
+        let duplicate_tunnel = REGISTRY.replace(r#""name": "IKEv2""#, r#""name": "openvpn""#);
+        assert!(matches!(
+            registry(&duplicate_tunnel),
+            Err(LabelError::Duplicate(name)) if name == "Open VPN"
+        ));
+        let duplicate_application = REGISTRY.replace("Video Call", "gaming");
+        assert!(matches!(
+            registry(&duplicate_application),
+            Err(LabelError::Duplicate(name)) if name == "gaming"
+        ));
+        assert!(matches!(
+            registry(r#"{"groups": [], "applications": [{"name": "Gaming", "file": "g.json"}]}"#),
+            Err(LabelError::Empty)
+        ));
+        assert!(matches!(
+            registry(r#"{"groups": [{"name": "plain", "tunnels": []}], "applications": []}"#),
+            Err(LabelError::Empty)
+        ));
+        LabelRegistry::default().check().unwrap();
+    }
+
+    #[test]
+    fn from_file_checks_the_registry() {
+        let path = scratch_dir("labels-from-file").join("labels.json");
+        fs::write(&path, REGISTRY.replace("Video Call", "Gaming")).unwrap();
+        assert!(matches!(
+            LabelRegistry::from_file(&path),
+            Err(LabelError::Duplicate(_))
+        ));
+        fs::write(&path, REGISTRY).unwrap();
+        assert_eq!(LabelRegistry::from_file(&path).unwrap().tunnel_count(), 3);
+    }
+
+    #[test]
+    fn find_ignores_case_and_whitespace() {
+        let registry = registry(REGISTRY).unwrap();
+        let label = registry.find("openvpn", " videocall ").unwrap();
+        assert_eq!(&*label.group, "tunnelled");
+        assert_eq!(&*label.tunnel, "Open VPN");
+        assert_eq!(&*label.application, "Video Call");
+        assert_eq!(registry.find("OPEN VPN", "Video\tCall").unwrap(), label);
+        assert!(matches!(
+            registry.find("WireGuard", "Gaming"),
+            Err(LabelError::UnknownTunnel(name)) if name == "WireGuard"
+        ));
+        assert!(matches!(
+            registry.find("IKEv2", "Mail"),
+            Err(LabelError::UnknownApplication(name)) if name == "Mail"
+        ));
+
+        let builtin = LabelRegistry::default();
+        assert_eq!(
+            builtin.find("Non VPN", "non streaming").unwrap(),
+            Label::builtin(&Encryption::NonVPN, DataCategory::NonStreaming)
+        );
+    }
+
+    #[test]
+    fn paths_follow_group_tunnel_and_application() {
+        let registry = registry(REGISTRY).unwrap();
+        let path =
+            |tunnel, application| registry.path(&registry.find(tunnel, application).unwrap());
+        assert_eq!(
+            path("IKEv2", "Gaming"),
+            Some(PathBuf::from("VPN/ikev2/gaming.json"))
+        );
+        // Without paths the files sit at the root.
+        assert_eq!(
+            path("None", "Video Call"),
+            Some(PathBuf::from("video.json"))
+        );
+
+        let builtin = LabelRegistry::default();
+        assert_eq!(
+            builtin.path(&Label::builtin(
+                &Encryption::VPN(VPN::L2TPIP),
+                DataCategory::SSH
+            )),
+            Some(PathBuf::from("VPN/L2TP IPsec/ssh.json"))
+        );
+        assert_eq!(
+            builtin.path(&Label::builtin(&Encryption::NonVPN, DataCategory::Mail)),
+            Some(PathBuf::from("Non VPN/mail.json"))
+        );
+    }
+
+    #[test]
+    fn targets_number_in_listed_order() {
+        let registry = registry(REGISTRY).unwrap();
+        let target = |tunnel, application| {
+            registry
+                .target(&registry.find(tunnel, application).unwrap())
+                .unwrap()
+        };
+        assert_eq!(target("None", "Gaming"), [0, 0]);
+        assert_eq!(target("IKEv2", "Video Call"), [1, 1]);
+        assert_eq!(target("Open VPN", "Gaming"), [0, 2]);
+
+        let stranger = Label {
+            group: "tunnelled".into(),
+            tunnel: "IKEv2".into(),
+            application: "Mail".into(),
+        };
+        assert!(matches!(
+            registry.target(&stranger),
+            Err(LabelError::UnknownApplication(name)) if name == "Mail"
+        ));
+
+        let builtin = LabelRegistry::default();
+        assert_eq!(
+            builtin
+                .target(&Label::builtin(&Encryption::NonVPN, DataCategory::Mail))
+                .unwrap(),
+            [0, 0]
+        );
+        assert_eq!(
+            builtin
+                .target(&Label::builtin(
+                    &Encryption::VPN(VPN::L2TP),
+                    DataCategory::SSH
+                ))
+                .unwrap(),
+            [DataCategory::SSH as usize, 1]
+        );
+    }
+
+    #[test]
+    fn tunnel_labels_cover_every_application() {
+        let registry = registry(REGISTRY).unwrap();
+        let labels = registry.tunnel_labels("open vpn").unwrap();
+        assert_eq!(
+            labels
+                .iter()
+                .map(|label| &*label.application)
+                .collect::<Vec<_>>(),
+            ["Gaming", "Video Call"]
+        );
+        assert!(labels.iter().all(|label| &*label.tunnel == "Open VPN"));
+        assert!(registry.tunnel_labels("L2TP").is_err());
+        assert_eq!(registry.labels().count(), 3 * 2);
+    }
+}
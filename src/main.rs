@@ -2,25 +2,178 @@
 
 mod burn_dataset;
 mod categories;
+mod dataset_layout;
+mod export;
+mod features;
+mod flow_cache;
+mod flow_features;
 pub mod data_structure;
+mod labels;
 mod model;
+mod normalization;
 mod parse_data;
+mod parse_pcap;
+mod query;
+mod tcp_analysis;
+#[cfg(test)]
+mod test_support;
 mod training;
+mod validate;
 mod visualise;
 
-use crate::categories::{Encryption, VPN};
+use crate::data_structure::get_all_data;
+use crate::dataset_layout::DatasetLayout;
+use crate::features::{FeatureSet, FlowStatistics, PacketFeatures, PacketSequence};
+use crate::query::FlowQuery;
 use burn::backend::{
     wgpu::{Wgpu, WgpuDevice},
     Autodiff,
 };
+use std::iter::Peekable;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-pub fn run() {
+pub fn run(layout: &DatasetLayout, query: &FlowQuery) {
     let device = WgpuDevice::BestAvailable;
 
-    training::train::<Autodiff<Wgpu>>(device);
+    training::train::<Autodiff<Wgpu>>(device, layout, query);
 }
-fn main() {
-    let hash = visualise::collect_data_specific_encryption(Encryption::NonVPN);
-    //let hash = visualise::collect_data_specific_encryption(Encryption::NonVPN);
-    visualise::run_chart(hash).unwrap();
+
+/// The dataset at `root`, or the one configured through the environment.
+/// Configuration errors are printed, so callers only need to fail.
+fn load_layout(root: Option<String>) -> Option<DatasetLayout> {
+    match root {
+        Some(root) => Some(DatasetLayout::scan(root)),
+        None => DatasetLayout::from_env()
+            .inspect_err(|err| eprintln!("{err}"))
+            .ok(),
+    }
+}
+
+/// `evaluate [QUERY...]` restores the trained model and prints how many of
+/// the flows the query options select it classifies correctly.
+fn evaluate_command(args: impl Iterator<Item = String>) -> ExitCode {
+    let query = match FlowQuery::from_args(args) {
+        Ok(query) => query,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(layout) = load_layout(None) else {
+        return ExitCode::FAILURE;
+    };
+    match training::evaluate_saved::<Wgpu>(WgpuDevice::BestAvailable, &layout, &query) {
+        Ok(evaluation) => {
+            println!("{evaluation}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `train [QUERY...]` trains on the flows of the dataset configured through
+/// the environment that the query options select, see
+/// [`FlowQuery::from_args`].
+fn train_command(args: impl Iterator<Item = String>) -> ExitCode {
+    let query = match FlowQuery::from_args(args) {
+        Ok(query) => query,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(layout) = load_layout(None) else {
+        return ExitCode::FAILURE;
+    };
+    run(&layout, &query);
+    ExitCode::SUCCESS
+}
+/// `validate [ROOT]` prints a JSON health report for the dataset at `ROOT`,
+/// or the one configured through the environment, and fails when it finds
+/// problems.
+fn validate_command(root: Option<String>) -> ExitCode {
+    let Some(layout) = load_layout(root) else {
+        return ExitCode::FAILURE;
+    };
+    let report = validate::validate_dataset(&layout);
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    if report.is_clean() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// `export OUT_DIR [ROOT]` writes the packet and flow tables of every
+/// labelled file of the dataset at `ROOT`, or the one configured through the
+/// environment, into `OUT_DIR`. The flow features are the CICFlowMeter-style
+/// statistics followed by the first 32 packets of each flow.
+fn export_command(out_dir: Option<String>, root: Option<String>) -> ExitCode {
+    let Some(out_dir) = out_dir else {
+        eprintln!("usage: export OUT_DIR [ROOT]");
+        return ExitCode::FAILURE;
+    };
+    let Some(layout) = load_layout(root) else {
+        return ExitCode::FAILURE;
+    };
+    let data = get_all_data(&layout);
+    let features = FeatureSet::new()
+        .with(FlowStatistics::default())
+        .with(PacketSequence::new(Arc::new(PacketFeatures::default()), 32));
+    let csv = Path::new(&out_dir).join(export::FLOW_FEATURES_CSV);
+    let result = export::export_arrow(&data, &out_dir)
+        .and_then(|()| export::export_flow_features_csv(&data, csv, &features));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `tcp [ROOT] [QUERY...]` prints the TCP health of every combination of the
+/// dataset at `ROOT`, or the one configured through the environment, over
+/// the flows the query options select.
+fn tcp_command(mut args: Peekable<impl Iterator<Item = String>>) -> ExitCode {
+    let root = args.next_if(|arg| !arg.starts_with("--"));
+    let query = match FlowQuery::from_args(args) {
+        Ok(query) => query,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(layout) = load_layout(root) else {
+        return ExitCode::FAILURE;
+    };
+    let data = get_all_data(&layout);
+    let hash = visualise::collect_tcp_health_hash(&data, &query);
+    visualise::print_tcp_health(&hash);
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).peekable();
+    match args.next().as_deref() {
+        Some("validate") => return validate_command(args.next()),
+        Some("export") => return export_command(args.next(), args.next()),
+        Some("tcp") => return tcp_command(args),
+        Some("train") => return train_command(args),
+        Some("evaluate") => return evaluate_command(args),
+        _ => {}
+    }
+    let Some(layout) = load_layout(None) else {
+        return ExitCode::FAILURE;
+    };
+    let labels = layout.labels().tunnel_labels("NonVPN").unwrap();
+    let hash = visualise::collect_data_specific_encryption(&layout, &labels).unwrap();
+    //let hash = visualise::collect_data_specific_encryption(&layout, Encryption::NonVPN);
+    visualise::run_chart(hash, layout.labels().clone()).unwrap();
+    ExitCode::SUCCESS
 }
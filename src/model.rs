@@ -1,22 +1,13 @@
-use crate::burn_dataset::NetworkTrafficBatch;
-use crate::categories::DataCategory;
-use burn::nn::conv::{Conv2d, Conv2dConfig};
-use burn::nn::loss::{CrossEntropyLoss, CrossEntropyLossConfig};
-use burn::nn::pool::{AdaptiveAvgPool2d, AdaptiveAvgPool2dConfig};
-use burn::nn::{Dropout, DropoutConfig, LayerNorm, LayerNormConfig};
+use crate::burn_dataset::FlowSequenceBatch;
+use burn::nn::loss::CrossEntropyLoss;
+use burn::nn::{LayerNorm, LayerNormConfig};
 use burn::prelude::*;
-use burn::tensor::activation::softmax;
 use burn::train::ClassificationOutput;
 use burn::{
-    nn::{
-        loss::{MseLoss, Reduction::Mean},
-        Linear, LinearConfig, Relu,
-    },
-    prelude::*,
+    nn::{Linear, LinearConfig, Relu},
     tensor::backend::AutodiffBackend,
-    train::{RegressionOutput, TrainOutput, TrainStep, ValidStep},
+    train::{TrainOutput, TrainStep, ValidStep},
 };
-use log::{error, log};
 #[derive(Module, Debug)]
 pub struct Model<B: Backend> {
     linear1: Linear<B>,
@@ -44,8 +35,23 @@ impl<B: Backend> Model<B> {
         }
     }
 
-    pub fn forward(&self, input: Tensor<B, 1>) -> Tensor<B, 1> {
-        let hidden = self.linear1.forward(input);
+    /// The input size [`Model::forward_sequence`] needs for `length` packets
+    /// of `width` features each.
+    pub fn sequence_input_size(length: usize, width: usize) -> usize {
+        length * (width + 1)
+    }
+
+    /// Classifies `[B, N, F]` packet sequences, see
+    /// [`Model::sequence_input_size`]. Padded packets are zeroed through
+    /// `mask`, which is also appended to every packet as one more feature, so
+    /// the model can tell a missing packet from one whose features are zero.
+    pub fn forward_sequence(&self, inputs: Tensor<B, 3>, mask: Tensor<B, 2, Bool>) -> Tensor<B, 2> {
+        let [batch, length, width] = inputs.dims();
+        let mask = mask.float().unsqueeze_dim::<3>(2);
+        let inputs = Tensor::cat(vec![inputs * mask.clone(), mask], 2);
+        let hidden = self
+            .linear1
+            .forward(inputs.reshape([batch, Self::sequence_input_size(length, width)]));
         let hidden = self.relu.forward(hidden);
         let hidden = self.layer_norm1.forward(hidden);
 
@@ -53,31 +59,71 @@ impl<B: Backend> Model<B> {
         let hidden = self.relu.forward(hidden);
         let hidden = self.layer_norm2.forward(hidden);
 
-        self.linear3.forward(hidden).squeeze(1)
+        self.linear3.forward(hidden)
     }
 
-    pub fn forward_classification(
+    pub fn forward_sequence_classification(
         &self,
-        input: Tensor<B, 1>,
-        targets: Tensor<B, 1, Int>,
+        batch: FlowSequenceBatch<B>,
     ) -> ClassificationOutput<B> {
-        let output = self.forward(input).unsqueeze();
-        let loss =
-            CrossEntropyLoss::new(None, &output.device()).forward(output.clone(), targets.clone());
-        ClassificationOutput::new(loss, output, targets)
+        let output = self.forward_sequence(batch.inputs, batch.mask);
+        let loss = CrossEntropyLoss::new(None, &output.device())
+            .forward(output.clone(), batch.targets.clone());
+        ClassificationOutput::new(loss, output, batch.targets)
     }
 }
 
-impl<B: AutodiffBackend> TrainStep<NetworkTrafficBatch<B>, ClassificationOutput<B>> for Model<B> {
-    fn step(&self, batch: NetworkTrafficBatch<B>) -> TrainOutput<ClassificationOutput<B>> {
-        let item = self.forward_classification(batch.inputs, batch.targets);
+impl<B: AutodiffBackend> TrainStep<FlowSequenceBatch<B>, ClassificationOutput<B>> for Model<B> {
+    fn step(&self, batch: FlowSequenceBatch<B>) -> TrainOutput<ClassificationOutput<B>> {
+        let item = self.forward_sequence_classification(batch);
         TrainOutput::new(self, item.loss.backward(), item)
     }
 }
 
-impl<B: Backend> ValidStep<NetworkTrafficBatch<B>, ClassificationOutput<B>> for Model<B> {
-    fn step(&self, batch: NetworkTrafficBatch<B>) -> ClassificationOutput<B> {
-        error!("Batch inputs shape: {:?}", &batch.inputs.dims());
-        self.forward_classification(batch.inputs, batch.targets)
+impl<B: Backend> ValidStep<FlowSequenceBatch<B>, ClassificationOutput<B>> for Model<B> {
+    fn step(&self, batch: FlowSequenceBatch<B>) -> ClassificationOutput<B> {
+        self.forward_sequence_classification(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+
+    fn sequence(
+        values: [[f32; 2]; 3],
+        mask: [bool; 3],
+    ) -> (Tensor<NdArray, 3>, Tensor<NdArray, 2, Bool>) {
+        let device = Default::default();
+        (
+            Tensor::from_data(TensorData::new(values.concat(), [1, 3, 2]), &device),
+            Tensor::from_data(TensorData::new(mask.to_vec(), [1, 3]), &device),
+        )
+    }
+
+    #[test]
+    fn sequences_are_classified_with_their_mask() {
+        let model = Model::<NdArray>::new(
+            &Default::default(),
+            Model::<NdArray>::sequence_input_size(3, 2),
+            8,
+            2,
+        );
+        let classify = |values, mask| {
+            let (inputs, mask) = sequence(values, mask);
+            let output = model.forward_sequence(inputs, mask);
+            assert_eq!(output.dims(), [1, 2]);
+            output.into_data().to_vec::<f32>().unwrap()
+        };
+
+        // Whatever sits in padded positions is ignored
+        let first = classify([[1., 2.], [0., 0.], [0., 0.]], [true, false, false]);
+        let noisy = classify([[1., 2.], [5., 6.], [7., 8.]], [true, false, false]);
+        assert_eq!(first, noisy);
+
+        // A packet of zeros is not the same as no packet
+        let zeros = classify([[1., 2.], [0., 0.], [0., 0.]], [true, true, true]);
+        assert_ne!(first, zeros);
     }
 }
@@ -0,0 +1,358 @@
+use crate::burn_dataset::NetworkDataset;
+use crate::features::FeatureSet;
+use burn::data::dataset::Dataset;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scaling {
+    /// Maps the training range onto `0..=1`.
+    #[default]
+    MinMax,
+    /// Zero mean and unit standard deviation over the training split.
+    Standard,
+    /// `ln(1 + x)` first, for raw counters like bytes that span many orders
+    /// of magnitude, then like [`Scaling::MinMax`] on the logged values.
+    /// Columns named `log_*` are logged by their extractor already and are
+    /// rejected by [`Normalization::fit`].
+    Log,
+}
+
+/// What one feature column looked like in the training split.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub name: String,
+    pub scaling: Scaling,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std: f64,
+}
+
+/// Running statistics, see Welford's online algorithm.
+#[derive(Clone, Copy)]
+struct Accumulator {
+    count: usize,
+    mean: f64,
+    squares: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            squares: 0.0,
+            min: f64::MAX,
+            max: f64::MIN,
+        }
+    }
+}
+
+impl Accumulator {
+    fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squares += delta * (value - self.mean);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn finish(self, name: String, scaling: Scaling) -> ColumnStats {
+        if self.count == 0 {
+            return ColumnStats {
+                name,
+                scaling,
+                min: 0.0,
+                max: 0.0,
+                mean: 0.0,
+                std: 0.0,
+            };
+        }
+        ColumnStats {
+            name,
+            scaling,
+            min: self.min,
+            max: self.max,
+            mean: self.mean,
+            std: (self.squares / self.count as f64).sqrt(),
+        }
+    }
+}
+
+fn log(value: f64) -> f64 {
+    value.max(0.0).ln_1p()
+}
+
+impl ColumnStats {
+    /// Columns that were constant in training come out as zero instead of
+    /// dividing by zero.
+    pub fn apply(&self, value: f64) -> f64 {
+        let scale = |value: f64, offset: f64, range: f64| {
+            if range > 0.0 {
+                (value - offset) / range
+            } else {
+                0.0
+            }
+        };
+        match self.scaling {
+            Scaling::MinMax => scale(value, self.min, self.max - self.min),
+            Scaling::Standard => scale(value, self.mean, self.std),
+            Scaling::Log => scale(log(value), self.min, self.max - self.min),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NormalizationError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// The statistics were fitted on other feature columns.
+    Mismatch {
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+    /// [`Scaling::Log`] was picked for a column that is logged already.
+    LoggedTwice(String),
+}
+
+impl Display for NormalizationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NormalizationError::Io { path, source } => {
+                write!(f, "could not access {}: {source}", path.display())
+            }
+            NormalizationError::Json { path, source } => {
+                write!(f, "invalid normalization file {}: {source}", path.display())
+            }
+            NormalizationError::Mismatch { expected, found } => {
+                match expected.iter().zip(found).find(|(expected, found)| expected != found) {
+                    Some((expected, found)) => write!(
+                        f,
+                        "normalization was fitted on column {expected:?}, the features have {found:?}"
+                    ),
+                    None => write!(
+                        f,
+                        "normalization was fitted on {} columns, the features have {}",
+                        expected.len(),
+                        found.len()
+                    ),
+                }
+            }
+            NormalizationError::LoggedTwice(name) => {
+                write!(f, "column {name:?} is log-scaled already")
+            }
+        }
+    }
+}
+
+impl std::error::Error for NormalizationError {}
+
+/// Per-column statistics fitted once on the training split, so training,
+/// validation and inference all scale their inputs the same way. They are
+/// stored next to the model record in [`Normalization::FILE_NAME`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Normalization {
+    columns: Vec<ColumnStats>,
+}
+
+impl Normalization {
+    pub const FILE_NAME: &'static str = "normalization.json";
+
+    /// Extracts `features` from every flow of `dataset`, scaling each column
+    /// the way `scaling` picks by its name. Only rows that exist count, so
+    /// the padding of packet sequences does not skew the statistics.
+    pub fn fit(
+        features: &FeatureSet,
+        dataset: &NetworkDataset,
+        scaling: impl Fn(&str) -> Scaling,
+    ) -> Result<Self, NormalizationError> {
+        let names = features.feature_names();
+        let scalings = names.iter().map(|name| scaling(name)).collect::<Vec<_>>();
+        if let Some((name, _)) = names
+            .iter()
+            .zip(&scalings)
+            .find(|(name, scaling)| **scaling == Scaling::Log && name.starts_with("log_"))
+        {
+            return Err(NormalizationError::LoggedTwice(name.clone()));
+        }
+        let mut accumulators = vec![Accumulator::default(); names.len()];
+        for item in dataset.iter() {
+            for row in features.extract(item.flow()) {
+                for ((accumulator, scaling), value) in
+                    accumulators.iter_mut().zip(&scalings).zip(row)
+                {
+                    accumulator.push(match scaling {
+                        Scaling::Log => log(value),
+                        Scaling::MinMax | Scaling::Standard => value,
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            columns: accumulators
+                .into_iter()
+                .zip(names)
+                .zip(scalings)
+                .map(|((accumulator, name), scaling)| accumulator.finish(name, scaling))
+                .collect(),
+        })
+    }
+
+    pub fn columns(&self) -> &[ColumnStats] {
+        &self.columns
+    }
+
+    pub fn apply(&self, row: &mut [f64]) {
+        for (value, column) in row.iter_mut().zip(&self.columns) {
+            *value = column.apply(*value);
+        }
+    }
+
+    /// Fails unless the statistics were fitted on the columns of `features`.
+    pub fn check(&self, features: &FeatureSet) -> Result<(), NormalizationError> {
+        let expected = self
+            .columns
+            .iter()
+            .map(|column| column.name.clone())
+            .collect::<Vec<_>>();
+        let found = features.feature_names();
+        if expected == found {
+            Ok(())
+        } else {
+            Err(NormalizationError::Mismatch { expected, found })
+        }
+    }
+
+    /// Writes the statistics to [`Normalization::FILE_NAME`] in `directory`.
+    pub fn save(&self, directory: impl AsRef<Path>) -> Result<(), NormalizationError> {
+        let path = directory.as_ref().join(Self::FILE_NAME);
+        let file = fs::File::create(&path).map_err(|source| NormalizationError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .map_err(|source| NormalizationError::Json { path, source })
+    }
+
+    pub fn load(directory: impl AsRef<Path>) -> Result<Self, NormalizationError> {
+        let path = directory.as_ref().join(Self::FILE_NAME);
+        let file = fs::File::open(&path).map_err(|source| NormalizationError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|source| NormalizationError::Json { path, source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::get_all_data;
+    use crate::features::FlowStatistics;
+    use crate::test_support::{fixture_layout, scratch_dir};
+
+    fn fixture_dataset(name: &str) -> NetworkDataset {
+        NetworkDataset::new(get_all_data(&fixture_layout(name)))
+    }
+
+    fn column<'a>(normalization: &'a Normalization, name: &str) -> &'a ColumnStats {
+        normalization
+            .columns()
+            .iter()
+            .find(|column| column.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn constant_columns_scale_to_zero() {
+        let dataset = fixture_dataset("normalization-constant");
+        let features = FeatureSet::default();
+        // Every packet of the fixtures has a 20 byte IP header
+        for scaling in [Scaling::MinMax, Scaling::Standard, Scaling::Log] {
+            let normalization = Normalization::fit(&features, &dataset, |name| match name {
+                "ip_header_length" => scaling,
+                _ => Scaling::MinMax,
+            })
+            .unwrap();
+            let header = column(&normalization, "ip_header_length");
+            assert_eq!(header.scaling, scaling);
+            assert_eq!(header.std, 0.0);
+            for value in [0.0, 20.0, 60.0] {
+                assert_eq!(header.apply(value), 0.0, "{scaling:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn scales_by_the_training_statistics() {
+        let dataset = fixture_dataset("normalization-scaling");
+        let features = FeatureSet::new().with(FlowStatistics::default());
+        let normalization = Normalization::fit(&features, &dataset, |name| match name {
+            "Total Length of Fwd Packets" => Scaling::Log,
+            "Total Fwd Packets" => Scaling::Standard,
+            _ => Scaling::MinMax,
+        })
+        .unwrap();
+
+        let bytes = column(&normalization, "Total Length of Fwd Packets");
+        assert_eq!(bytes.apply(bytes.max.exp_m1()), 1.0);
+        assert_eq!(bytes.apply(bytes.min.exp_m1()), 0.0);
+        let packets = column(&normalization, "Total Fwd Packets");
+        assert_eq!(packets.apply(packets.mean), 0.0);
+        assert!((packets.apply(packets.mean + packets.std) - 1.0).abs() < 1e-9);
+
+        let mut rows = features.extract(dataset.get(0).unwrap().flow());
+        normalization.apply(&mut rows[0]);
+        assert!(rows[0].iter().all(|value| value.is_finite()));
+    }
+
+    #[test]
+    fn refuses_to_log_logged_columns() {
+        let dataset = fixture_dataset("normalization-log");
+        let result = Normalization::fit(&FeatureSet::default(), &dataset, |name| {
+            if name.ends_with("bytes") {
+                Scaling::Log
+            } else {
+                Scaling::MinMax
+            }
+        });
+        assert!(
+            matches!(result, Err(NormalizationError::LoggedTwice(name)) if name == "log_bytes")
+        );
+    }
+
+    #[test]
+    fn saved_statistics_load_unchanged() {
+        let dataset = fixture_dataset("normalization-saved");
+        let directory = scratch_dir("normalization-saved");
+        let features = FeatureSet::default().with(FlowStatistics::default());
+        let normalization = Normalization::fit(&features, &dataset, |_| Scaling::Standard).unwrap();
+        normalization.save(&directory).unwrap();
+
+        let loaded = Normalization::load(&directory).unwrap();
+        assert_eq!(loaded, normalization);
+        loaded.check(&features).unwrap();
+        assert!(matches!(
+            loaded.check(&FeatureSet::default()),
+            Err(NormalizationError::Mismatch { .. })
+        ));
+        assert!(matches!(
+            Normalization::load(directory.join("missing")),
+            Err(NormalizationError::Io { .. })
+        ));
+    }
+}
@@ -1,15 +1,22 @@
 use crate::categories;
 use crate::categories::PacketDirection;
-use crate::data_structure::{BasePacket, Data, TcpPacket};
-use chrono::NaiveDateTime;
-use serde::Deserialize;
-use std::fs;
-use std::io::BufReader;
+use crate::data_structure::{BasePacket, Data, TcpFlags, TcpPacket, UdpPacket};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader};
+use std::net::{AddrParseError, IpAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
 
 #[derive(Deserialize, Debug)]
 pub struct RawData {
     #[serde(rename = "ip_proto")]
-    ip_protocol: IpProtocol,
+    ip_protocol: RawProtocol,
+    #[serde(rename = "ip_dst", alias = "dst_ip")]
+    ip_destination: Option<String>,
+    #[serde(rename = "ip_src", alias = "src_ip")]
+    ip_source: Option<String>,
     #[serde(rename = "port_dst")]
     port_destination: u16,
     #[serde(rename = "port_src")]
@@ -21,6 +28,7 @@ pub struct RawData {
 #[derive(Deserialize, Debug)]
 struct Packet {
     bytes: String,
+    direction: Option<String>,
     #[serde(rename = "ip_header_len")]
     ip_header_length: Option<String>,
     packets: String,
@@ -28,141 +36,1101 @@ struct Packet {
     tcp_header_len: Option<String>,
     tcp_flags: Option<String>,
     tcp_seq_number: Option<String>,
-    #[serde(with = "custom_datetime_format")]
-    timestamp_start: NaiveDateTime,
-    #[serde(with = "custom_datetime_format")]
-    timestamp_end: NaiveDateTime,
+    #[serde(rename = "udp_len")]
+    udp_length: Option<String>,
+    #[serde(deserialize_with = "custom_datetime_format::deserialize")]
+    timestamp_start: String,
+    #[serde(deserialize_with = "custom_datetime_format::deserialize")]
+    timestamp_end: String,
 }
 
+/// Timestamps are accepted as naive `%Y-%m-%d %H:%M:%S%.f` (taken as UTC),
+/// RFC 3339 with an offset, or epoch seconds, milliseconds or microseconds,
+/// and normalized to UTC so captures from different sensors share one
+/// timeline.
 mod custom_datetime_format {
-    use chrono::NaiveDateTime;
-    use serde::{self, Deserialize, Deserializer};
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use serde::{Deserialize, Deserializer};
 
-    const FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+    const NAIVE_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+    /// Epoch values at least this large are milliseconds. As seconds they
+    /// would lie after the year 5000, as milliseconds they start in 1973.
+    const MILLISECONDS_FROM: u64 = 100_000_000_000;
+    /// Epoch values at least this large are microseconds, by the same
+    /// reasoning one step down.
+    const MICROSECONDS_FROM: u64 = 100_000_000_000_000;
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        NaiveDateTime::parse_from_str(&s, FORMAT).map_err(serde::de::Error::custom)
+    pub fn parse(s: &str) -> Result<DateTime<Utc>, String> {
+        let s = s.trim();
+        let naive = match NaiveDateTime::parse_from_str(s, NAIVE_FORMAT) {
+            Ok(timestamp) => return Ok(timestamp.and_utc()),
+            Err(err) => err,
+        };
+        let rfc3339 = match DateTime::parse_from_rfc3339(s) {
+            Ok(timestamp) => return Ok(timestamp.with_timezone(&Utc)),
+            Err(err) => err,
+        };
+        let epoch = match parse_epoch(s) {
+            Ok(timestamp) => return Ok(timestamp),
+            Err(err) => err,
+        };
+        Err(format!(
+            "tried `{NAIVE_FORMAT}` ({naive}), RFC 3339 ({rfc3339}) and epoch seconds, milliseconds or microseconds ({epoch})"
+        ))
+    }
+
+    /// The unit is told apart by magnitude, see [`MILLISECONDS_FROM`] and
+    /// [`MICROSECONDS_FROM`].
+    fn parse_epoch(s: &str) -> Result<DateTime<Utc>, &'static str> {
+        let timestamp = match s.parse::<i64>() {
+            Ok(micros) if micros.unsigned_abs() >= MICROSECONDS_FROM => {
+                DateTime::from_timestamp_micros(micros)
+            }
+            Ok(millis) if millis.unsigned_abs() >= MILLISECONDS_FROM => {
+                DateTime::from_timestamp_millis(millis)
+            }
+            Ok(seconds) => DateTime::from_timestamp(seconds, 0),
+            Err(_) => {
+                let value: f64 = s.parse().map_err(|_| "not a number")?;
+                if !value.is_finite() {
+                    return Err("not a number");
+                }
+                let micros = if value.abs() >= MICROSECONDS_FROM as f64 {
+                    value
+                } else if value.abs() >= MILLISECONDS_FROM as f64 {
+                    value * 1e3
+                } else {
+                    value * 1e6
+                };
+                DateTime::from_timestamp_micros(micros.round() as i64)
+            }
+        };
+        timestamp.ok_or("out of range")
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Text(String),
+        Number(serde_json::Number),
+    }
+
+    /// Epoch timestamps may be written as JSON numbers. They are kept as text
+    /// like every other raw field and converted by [`parse`].
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => text,
+            Raw::Number(number) => number.to_string(),
+        })
     }
 }
 
+/// `ip_proto` is a protocol name or its IANA number, either as a JSON number
+/// or as a string.
 #[derive(Deserialize, Debug)]
-enum IpProtocol {
-    #[serde(rename = "gre")]
-    Gre,
-    #[serde(rename = "tcp")]
-    Tcp,
-    #[serde(rename = "udp")]
-    Udp,
-    #[serde(rename = "icmp")]
-    Icmp,
-}
-
-impl From<RawData> for categories::IpProtocol {
-    fn from(raw: RawData) -> Self {
-        match raw.ip_protocol {
-            IpProtocol::Tcp => {
+#[serde(untagged)]
+enum RawProtocol {
+    Number(u8),
+    Name(String),
+}
+
+impl RawProtocol {
+    fn number(&self) -> Result<u8, FieldError> {
+        match self {
+            RawProtocol::Number(number) => Ok(*number),
+            RawProtocol::Name(name) => name
+                .parse()
+                .ok()
+                .or_else(|| categories::IpProtocol::number_from_name(name))
+                .ok_or_else(|| FieldError {
+                    packet: None,
+                    field: "ip_proto",
+                    kind: FieldErrorKind::Invalid {
+                        value: name.clone(),
+                        reason: "unknown IP protocol".to_string(),
+                    },
+                }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// The first malformed flow fails the whole file.
+    #[default]
+    Strict,
+    /// Malformed flows are skipped and counted in [`ParsedFile::skipped_flows`].
+    Lenient,
+}
+
+/// How to read direction from the sign of `bytes` when a packet has no
+/// `direction` field. The sign is taken from the text, so `-0` is negative.
+/// An unsigned zero carries no sign and gives [`PacketDirection::Unknown`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectionConvention {
+    /// The layout of the published dataset: negative byte counts were
+    /// received by the capturing host.
+    #[default]
+    NegativeIsIncoming,
+    NegativeIsOutgoing,
+}
+
+impl DirectionConvention {
+    fn direction(self, bytes: &str, magnitude: u64) -> PacketDirection {
+        let bytes = bytes.trim_start();
+        if magnitude == 0 && !bytes.starts_with(['-', '+']) {
+            return PacketDirection::Unknown;
+        }
+        match (self, bytes.starts_with('-')) {
+            (DirectionConvention::NegativeIsIncoming, true)
+            | (DirectionConvention::NegativeIsOutgoing, false) => PacketDirection::Incoming,
+            (DirectionConvention::NegativeIsIncoming, false)
+            | (DirectionConvention::NegativeIsOutgoing, true) => PacketDirection::Outgoing,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FieldErrorKind {
+    Missing,
+    Invalid { value: String, reason: String },
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    Field {
+        path: PathBuf,
+        flow: usize,
+        packet: Option<usize>,
+        field: &'static str,
+        kind: FieldErrorKind,
+    },
+    Pcap {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Io { path, source } => {
+                write!(f, "could not read {}: {source}", path.display())
+            }
+            ParseError::Json { path, source } => {
+                write!(f, "invalid JSON in {}: {source}", path.display())
+            }
+            ParseError::Field {
+                path,
+                flow,
+                packet,
+                field,
+                kind,
+            } => {
+                write!(f, "{} flow {flow}", path.display())?;
+                if let Some(packet) = packet {
+                    write!(f, " packet {packet}")?;
+                }
+                match kind {
+                    FieldErrorKind::Missing => write!(f, ": `{field}` is missing"),
+                    FieldErrorKind::Invalid { value, reason } => {
+                        write!(f, ": `{field}` has invalid value {value:?} ({reason})")
+                    }
+                }
+            }
+            ParseError::Pcap { path, message } => {
+                write!(f, "invalid capture {}: {message}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io { source, .. } => Some(source),
+            ParseError::Json { source, .. } => Some(source),
+            ParseError::Field { .. } | ParseError::Pcap { .. } => None,
+        }
+    }
+}
+
+/// A field level failure inside one flow, before the file path and flow index are known.
+#[derive(Debug)]
+struct FieldError {
+    packet: Option<usize>,
+    field: &'static str,
+    kind: FieldErrorKind,
+}
+
+impl FieldError {
+    fn missing(packet: usize, field: &'static str) -> Self {
+        Self {
+            packet: Some(packet),
+            field,
+            kind: FieldErrorKind::Missing,
+        }
+    }
+
+    fn invalid(packet: usize, field: &'static str, value: &str, reason: impl Display) -> Self {
+        Self {
+            packet: Some(packet),
+            field,
+            kind: FieldErrorKind::Invalid {
+                value: value.to_string(),
+                reason: reason.to_string(),
+            },
+        }
+    }
+
+    fn in_flow(self, path: &Path, flow: usize) -> ParseError {
+        ParseError::Field {
+            path: path.to_path_buf(),
+            flow,
+            packet: self.packet,
+            field: self.field,
+            kind: self.kind,
+        }
+    }
+}
+
+fn required<'a>(
+    value: &'a Option<String>,
+    packet: usize,
+    field: &'static str,
+) -> Result<&'a str, FieldError> {
+    value
+        .as_deref()
+        .ok_or_else(|| FieldError::missing(packet, field))
+}
+
+fn parse_field<T>(value: &str, packet: usize, field: &'static str) -> Result<T, FieldError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|err| FieldError::invalid(packet, field, value, err))
+}
+
+/// Counters are read wide first, so a value too large for its field is
+/// reported as out of range rather than as malformed.
+fn parse_counter<T: TryFrom<i128>>(
+    value: &str,
+    packet: usize,
+    field: &'static str,
+) -> Result<T, FieldError> {
+    let number: i128 = parse_field(value, packet, field)?;
+    T::try_from(number).map_err(|_| {
+        FieldError::invalid(
+            packet,
+            field,
+            value,
+            format!("out of range for {}", std::any::type_name::<T>()),
+        )
+    })
+}
+
+/// `None` when `value` is not a number at all, `Some(false)` when it does not
+/// fit `T`.
+fn counter_fits<T: TryFrom<i128>>(value: &str) -> Option<bool> {
+    let number: i128 = value.parse().ok()?;
+    Some(T::try_from(number).is_ok())
+}
+
+/// Addresses are optional in the dataset, but one that is present has to parse.
+fn parse_address(
+    value: &Option<String>,
+    field: &'static str,
+) -> Result<Option<IpAddr>, FieldError> {
+    value
+        .as_deref()
+        .map(|value| {
+            value.parse().map_err(|err: AddrParseError| FieldError {
+                packet: None,
+                field,
+                kind: FieldErrorKind::Invalid {
+                    value: value.to_string(),
+                    reason: err.to_string(),
+                },
+            })
+        })
+        .transpose()
+}
+
+/// A problem [`RawData::issues`] found in a flow. Packet level issues are
+/// reported once per packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowIssue {
+    UnknownProtocol,
+    InvalidAddress,
+    /// No packet the parser keeps, because there are none or because all of
+    /// them lack `ip_header_len`.
+    EmptyFlow,
+    InvalidIpHeaderLength,
+    InvalidDirection,
+    InvalidBytes,
+    BytesOverflow,
+    InvalidPackets,
+    PacketsOverflow,
+    InvalidTimestamp,
+    NegativeDuration,
+    MissingTcpFlags,
+    InvalidTcpFlags,
+    MissingTcpSequenceNumber,
+    MissingTcpAcknowledgmentNumber,
+    MissingTcpHeaderLength,
+    InvalidTcpField,
+    InvalidUdpLength,
+}
+
+impl Packet {
+    /// Packets without `ip_header_len` are dropped by the parser, so they
+    /// have no issues, see [`RawData::dropped_packets`].
+    fn issues(&self, protocol: Option<u8>, issues: &mut Vec<FlowIssue>) {
+        let Some(ip_header_length) = &self.ip_header_length else {
+            return;
+        };
+        if counter_fits::<u16>(ip_header_length) != Some(true) {
+            issues.push(FlowIssue::InvalidIpHeaderLength);
+        }
+        if let Some(direction) = &self.direction {
+            if direction.parse::<PacketDirection>().is_err() {
+                issues.push(FlowIssue::InvalidDirection);
+            }
+        }
+        match counter_fits::<i64>(&self.bytes) {
+            None => issues.push(FlowIssue::InvalidBytes),
+            Some(false) => issues.push(FlowIssue::BytesOverflow),
+            Some(true) => {}
+        }
+        match counter_fits::<u32>(&self.packets) {
+            None => issues.push(FlowIssue::InvalidPackets),
+            Some(false) => issues.push(FlowIssue::PacketsOverflow),
+            Some(true) => {}
+        }
+        match (
+            custom_datetime_format::parse(&self.timestamp_start),
+            custom_datetime_format::parse(&self.timestamp_end),
+        ) {
+            (Ok(start), Ok(end)) if end < start => issues.push(FlowIssue::NegativeDuration),
+            (Ok(_), Ok(_)) => {}
+            _ => issues.push(FlowIssue::InvalidTimestamp),
+        }
+        if protocol == Some(categories::IpProtocol::UDP) {
+            if let Some(length) = &self.udp_length {
+                if length.parse::<u16>().is_err() {
+                    issues.push(FlowIssue::InvalidUdpLength);
+                }
+            }
+        }
+        if protocol != Some(categories::IpProtocol::TCP) {
+            return;
+        }
+        match &self.tcp_flags {
+            None => issues.push(FlowIssue::MissingTcpFlags),
+            Some(flags) if u8::from_str_radix(flags, 2).is_err() => {
+                issues.push(FlowIssue::InvalidTcpFlags)
+            }
+            Some(_) => {}
+        }
+        for (value, missing) in [
+            (&self.tcp_seq_number, FlowIssue::MissingTcpSequenceNumber),
+            (
+                &self.tcp_ack_number,
+                FlowIssue::MissingTcpAcknowledgmentNumber,
+            ),
+        ] {
+            match value {
+                None => issues.push(missing),
+                Some(number) if number.parse::<u32>().is_err() => {
+                    issues.push(FlowIssue::InvalidTcpField)
+                }
+                Some(_) => {}
+            }
+        }
+        match &self.tcp_header_len {
+            None => issues.push(FlowIssue::MissingTcpHeaderLength),
+            Some(length) if length.parse::<u16>().is_err() => {
+                issues.push(FlowIssue::InvalidTcpField)
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+impl RawData {
+    /// Checks every field against what [`FlowReader`] needs to convert the
+    /// flow, without stopping at the first problem.
+    pub fn issues(&self) -> Vec<FlowIssue> {
+        let mut issues = vec![];
+        let protocol = self.ip_protocol.number().ok();
+        if protocol.is_none() {
+            issues.push(FlowIssue::UnknownProtocol);
+        }
+        if parse_address(&self.ip_source, "ip_src").is_err()
+            || parse_address(&self.ip_destination, "ip_dst").is_err()
+        {
+            issues.push(FlowIssue::InvalidAddress);
+        }
+        if self.packet_count() == self.dropped_packets() {
+            issues.push(FlowIssue::EmptyFlow);
+        }
+        for packet in &self.packets {
+            packet.issues(protocol, &mut issues);
+        }
+        issues
+    }
+
+    pub fn packet_count(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Packets without `ip_header_len`, which [`FlowReader`] leaves out.
+    pub fn dropped_packets(&self) -> usize {
+        self.packets
+            .iter()
+            .filter(|packet| packet.ip_header_length.is_none())
+            .count()
+    }
+
+    fn into_flow(
+        self,
+        convention: DirectionConvention,
+    ) -> Result<categories::IpProtocol, FieldError> {
+        let ip_destination = parse_address(&self.ip_destination, "ip_dst")?;
+        let ip_source = parse_address(&self.ip_source, "ip_src")?;
+        Ok(match self.ip_protocol.number()? {
+            categories::IpProtocol::TCP => {
                 let data = Data {
-                    port_destination: raw.port_destination,
-                    port_source: raw.port_source,
-                    packets: generate_tcp_packets(raw.packets),
+                    ip_destination,
+                    ip_source,
+                    port_destination: self.port_destination,
+                    port_source: self.port_source,
+                    packets: generate_tcp_packets(self.packets, convention)?,
                 };
                 categories::IpProtocol::Tcp(data)
             }
-            IpProtocol::Icmp | IpProtocol::Gre | IpProtocol::Udp => {
+            categories::IpProtocol::UDP => {
                 let data = Data {
-                    port_destination: raw.port_destination,
-                    port_source: raw.port_source,
-                    packets: generate_packets(&raw.packets),
+                    ip_destination,
+                    ip_source,
+                    port_destination: self.port_destination,
+                    port_source: self.port_source,
+                    packets: generate_udp_packets(&self.packets, convention)?,
                 };
-                match raw.ip_protocol {
-                    IpProtocol::Icmp => categories::IpProtocol::Icmp(data),
-                    IpProtocol::Gre => categories::IpProtocol::Gre(data),
-                    IpProtocol::Udp => categories::IpProtocol::Udp(data),
-                    IpProtocol::Tcp => unreachable!(),
-                }
+                categories::IpProtocol::Udp(data)
             }
-        }
+            number => {
+                let data = Data {
+                    ip_destination,
+                    ip_source,
+                    port_destination: self.port_destination,
+                    port_source: self.port_source,
+                    packets: generate_packets(&self.packets, convention)?,
+                };
+                categories::IpProtocol::from_number(number, data)
+            }
+        })
     }
 }
 
-fn generate_packets(raw_packets: &[Packet]) -> Vec<BasePacket> {
+fn generate_packets(
+    raw_packets: &[Packet],
+    convention: DirectionConvention,
+) -> Result<Vec<BasePacket>, FieldError> {
     raw_packets
         .iter()
-        .filter(|packet| packet.ip_header_length.is_some())
-        .map(generate_packet)
-        .collect::<Vec<_>>()
+        .enumerate()
+        .filter(|(_, packet)| packet.ip_header_length.is_some())
+        .map(|(index, packet)| generate_packet(index, packet, convention))
+        .collect()
 }
 
-fn generate_packet(packet: &Packet) -> BasePacket {
-    let bytes: i32 = packet.bytes.parse().unwrap();
-    let packet_direction = if bytes.is_negative() {
-        PacketDirection::Incoming
-    } else {
-        PacketDirection::Outgoing
+/// An explicit `direction` field wins over the sign of `bytes`.
+fn generate_packet(
+    index: usize,
+    packet: &Packet,
+    convention: DirectionConvention,
+) -> Result<BasePacket, FieldError> {
+    let bytes = parse_counter::<i64>(&packet.bytes, index, "bytes")?.unsigned_abs();
+    let packet_direction = match &packet.direction {
+        Some(direction) => parse_field(direction, index, "direction")?,
+        None => convention.direction(&packet.bytes, bytes),
     };
-    let ip_header_length = packet
-        .ip_header_length
-        .as_ref()
-        .unwrap()
-        .parse::<u8>()
-        .unwrap();
-    let packets: u8 = packet.packets.parse().unwrap();
-    let duration = packet.timestamp_end - packet.timestamp_start;
-    BasePacket {
-        bytes: bytes.unsigned_abs(),
+    let ip_header_length = parse_counter(
+        required(&packet.ip_header_length, index, "ip_header_len")?,
+        index,
+        "ip_header_len",
+    )?;
+    let packets = parse_counter(&packet.packets, index, "packets")?;
+    let timestamp_start =
+        custom_datetime_format::parse(&packet.timestamp_start).map_err(|err| {
+            FieldError::invalid(index, "timestamp_start", &packet.timestamp_start, err)
+        })?;
+    let timestamp_end = custom_datetime_format::parse(&packet.timestamp_end)
+        .map_err(|err| FieldError::invalid(index, "timestamp_end", &packet.timestamp_end, err))?;
+    Ok(BasePacket {
+        bytes,
         direction: packet_direction,
         ip_header_length,
         packets,
-        packet_duration: duration,
-    }
+        timestamp_start,
+        timestamp_end,
+    })
+}
+
+/// Captures without `udp_len` get it derived from the IP lengths.
+fn generate_udp_packets(
+    raw_packets: &[Packet],
+    convention: DirectionConvention,
+) -> Result<Vec<UdpPacket>, FieldError> {
+    raw_packets
+        .iter()
+        .enumerate()
+        .filter(|(_, packet)| packet.ip_header_length.is_some())
+        .map(|(index, packet)| {
+            let base_packet = generate_packet(index, packet, convention)?;
+            Ok(match &packet.udp_length {
+                Some(udp_length) => {
+                    UdpPacket::new(base_packet, parse_field(udp_length, index, "udp_len")?)
+                }
+                None => UdpPacket::from_base(base_packet),
+            })
+        })
+        .collect()
 }
 
-fn generate_tcp_packets(raw_packets: Vec<Packet>) -> Vec<TcpPacket> {
+fn generate_tcp_packets(
+    raw_packets: Vec<Packet>,
+    convention: DirectionConvention,
+) -> Result<Vec<TcpPacket>, FieldError> {
     raw_packets
         .into_iter()
-        .filter(|packet| packet.ip_header_length.is_some())
-        .map(|packet| {
-            let base_packet = generate_packet(&packet);
-            TcpPacket {
+        .enumerate()
+        .filter(|(_, packet)| packet.ip_header_length.is_some())
+        .map(|(index, packet)| {
+            let base_packet = generate_packet(index, &packet, convention)?;
+            let tcp_flags = required(&packet.tcp_flags, index, "tcp_flags")?;
+            Ok(TcpPacket {
                 base: base_packet,
-                tcp_header_len: packet
-                    .tcp_header_len
-                    .expect("TCP Header len should exist on all TCP packets")
-                    .parse()
-                    .unwrap(),
-                tcp_flags: u8::from_str_radix(
-                    packet
-                        .tcp_flags
-                        .expect("TCP Flags should be present on all TCP packets")
-                        .as_str(),
-                    2,
-                )
-                .unwrap(),
-                tcp_acknowledgment_number: packet
-                    .tcp_ack_number
-                    .expect("TCP Acknowledgment number should be present on all TCP packets")
-                    .parse()
-                    .unwrap(),
-                tcp_sequence_number: packet
-                    .tcp_seq_number
-                    .expect("TCP Sequence number should be present on all TCP packets")
-                    .parse()
-                    .unwrap(),
+                tcp_header_len: parse_field(
+                    required(&packet.tcp_header_len, index, "tcp_header_len")?,
+                    index,
+                    "tcp_header_len",
+                )?,
+                tcp_flags: u8::from_str_radix(tcp_flags, 2)
+                    .map(TcpFlags::from_bits_retain)
+                    .map_err(|err| FieldError::invalid(index, "tcp_flags", tcp_flags, err))?,
+                tcp_acknowledgment_number: parse_field(
+                    required(&packet.tcp_ack_number, index, "tcp_ack_number")?,
+                    index,
+                    "tcp_ack_number",
+                )?,
+                tcp_sequence_number: parse_field(
+                    required(&packet.tcp_seq_number, index, "tcp_seq_number")?,
+                    index,
+                    "tcp_seq_number",
+                )?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedFile {
+    pub flows: Vec<categories::IpProtocol>,
+    pub skipped_flows: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReaderState {
+    Start,
+    FirstFlow,
+    NextFlow,
+    Done,
+}
+
+/// Reads the top-level flow array one element at a time, so only a single
+/// [`RawData`] is held in memory no matter how large the file is.
+pub struct FlowReader<R: BufRead> {
+    reader: R,
+    path: PathBuf,
+    mode: ParseMode,
+    convention: DirectionConvention,
+    state: ReaderState,
+    next_flow: usize,
+    skipped_flows: usize,
+}
+
+impl FlowReader<BufReader<fs::File>> {
+    pub fn open(path: impl AsRef<Path>, mode: ParseMode) -> Result<Self, ParseError> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).map_err(|source| ParseError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(Self::new(BufReader::new(file), path, mode))
+    }
+}
+
+impl<R: BufRead> FlowReader<R> {
+    pub fn new(reader: R, path: impl AsRef<Path>, mode: ParseMode) -> Self {
+        Self {
+            reader,
+            path: path.as_ref().to_path_buf(),
+            mode,
+            convention: DirectionConvention::default(),
+            state: ReaderState::Start,
+            next_flow: 0,
+            skipped_flows: 0,
+        }
+    }
+
+    pub fn with_direction_convention(mut self, convention: DirectionConvention) -> Self {
+        self.convention = convention;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flows skipped so far in [`ParseMode::Lenient`].
+    pub fn skipped_flows(&self) -> usize {
+        self.skipped_flows
+    }
+
+    /// Collects every remaining flow.
+    pub fn read_all(mut self) -> Result<ParsedFile, ParseError> {
+        let flows = self.by_ref().collect::<Result<Vec<_>, _>>()?;
+        Ok(ParsedFile {
+            flows,
+            skipped_flows: self.skipped_flows,
+        })
+    }
+
+    fn io_error(&self, source: io::Error) -> ParseError {
+        ParseError::Io {
+            path: self.path.clone(),
+            source,
+        }
+    }
+
+    fn syntax_error(&self, expected: &str, found: Option<u8>) -> ParseError {
+        let found = match found {
+            Some(byte) => format!("`{}`", byte as char),
+            None => "end of file".to_string(),
+        };
+        ParseError::Json {
+            path: self.path.clone(),
+            source: serde::de::Error::custom(format!(
+                "expected {expected} before flow {}, found {found}",
+                self.next_flow
+            )),
+        }
+    }
+
+    /// Skips whitespace and returns the next byte without consuming it.
+    fn peek_byte(&mut self) -> Result<Option<u8>, ParseError> {
+        loop {
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(self.io_error(err)),
+            };
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            let whitespace = buf
+                .iter()
+                .take_while(|byte| byte.is_ascii_whitespace())
+                .count();
+            let next = buf.get(whitespace).copied();
+            self.reader.consume(whitespace);
+            if next.is_some() {
+                return Ok(next);
+            }
+        }
+    }
+
+    /// Moves past the array punctuation in front of the next flow, returning
+    /// `false` once the closing `]` has been read.
+    fn advance(&mut self) -> Result<bool, ParseError> {
+        let byte = self.peek_byte()?;
+        match (self.state, byte) {
+            (ReaderState::Start, Some(b'[')) => {
+                self.reader.consume(1);
+                self.state = ReaderState::FirstFlow;
+                self.advance()
+            }
+            (ReaderState::Start, found) => Err(self.syntax_error("`[`", found)),
+            (ReaderState::FirstFlow | ReaderState::NextFlow, Some(b']')) => {
+                self.reader.consume(1);
+                self.state = ReaderState::Done;
+                Ok(false)
+            }
+            (ReaderState::FirstFlow, Some(_)) => {
+                self.state = ReaderState::NextFlow;
+                Ok(true)
             }
+            (ReaderState::NextFlow, Some(b',')) => {
+                self.reader.consume(1);
+                Ok(true)
+            }
+            (ReaderState::FirstFlow | ReaderState::NextFlow, found) => {
+                Err(self.syntax_error("`,` or `]`", found))
+            }
+            (ReaderState::Done, _) => Ok(false),
+        }
+    }
+
+    /// Reads the next flow as it is in the file, without converting it. A
+    /// syntax error ends the file.
+    pub fn next_raw(&mut self) -> Option<Result<RawData, ParseError>> {
+        let raw = match self.advance() {
+            Ok(false) => return None,
+            Ok(true) => self.read_flow(),
+            Err(err) => Err(err),
+        };
+        match raw {
+            Ok(raw) => {
+                self.next_flow += 1;
+                Some(Ok(raw))
+            }
+            Err(err) => {
+                self.state = ReaderState::Done;
+                Some(Err(err))
+            }
+        }
+    }
+
+    fn read_flow(&mut self) -> Result<RawData, ParseError> {
+        let mut deserializer = serde_json::Deserializer::from_reader(&mut self.reader);
+        RawData::deserialize(&mut deserializer).map_err(|source| ParseError::Json {
+            path: self.path.clone(),
+            source,
         })
-        .collect::<Vec<_>>()
+    }
 }
 
-pub fn get_data(folder: String) -> Vec<categories::IpProtocol> {
-    let file = fs::File::open(folder).unwrap();
-    let buf = BufReader::new(file);
-    let raw_data: Vec<RawData> = serde_json::from_reader(buf).unwrap();
-    raw_data
-        .into_iter()
-        .map(categories::IpProtocol::from)
-        .collect::<Vec<_>>()
+impl<R: BufRead> Iterator for FlowReader<R> {
+    type Item = Result<categories::IpProtocol, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let raw = match self.next_raw()? {
+                Ok(raw) => raw,
+                Err(err) => return Some(Err(err)),
+            };
+            let flow = self.next_flow - 1;
+            match raw.into_flow(self.convention) {
+                Ok(data) => return Some(Ok(data)),
+                Err(err) => {
+                    let err = err.in_flow(&self.path, flow);
+                    match self.mode {
+                        ParseMode::Strict => {
+                            self.state = ReaderState::Done;
+                            return Some(Err(err));
+                        }
+                        ParseMode::Lenient => {
+                            log::warn!("skipping flow: {err}");
+                            self.skipped_flows += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn get_data(path: impl AsRef<Path>, mode: ParseMode) -> Result<ParsedFile, ParseError> {
+    FlowReader::open(path, mode)?.read_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_flow(packets: &[&str]) -> String {
+        format!(
+            r#"{{"ip_proto": "tcp", "port_src": 50000, "port_dst": 443, "x_packets": [{}]}}"#,
+            packets.join(",")
+        )
+    }
+
+    fn tcp_packet(extra: &str) -> String {
+        format!(
+            r#"{{"bytes": "60", "ip_header_len": "20", "packets": "1", "tcp_ack_number": "0", "tcp_header_len": "40", "tcp_flags": "00000010", "tcp_seq_number": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"{extra}}}"#
+        )
+    }
+
+    /// The issues of the only flow in `json`, and whether a strict reader
+    /// accepts it.
+    fn check(json: &str) -> (Vec<FlowIssue>, RawData, bool) {
+        let raw: RawData = serde_json::from_str(json).unwrap();
+        let issues = raw.issues();
+        let file = format!("[{json}]");
+        let parsed = FlowReader::new(file.as_bytes(), "test.json", ParseMode::Strict)
+            .read_all()
+            .is_ok();
+        (issues, raw, parsed)
+    }
+
+    #[test]
+    fn dropped_packets_are_not_issues() {
+        // No IP header length and no TCP fields: the parser drops it, so the
+        // TCP checks must not complain about it either.
+        let dropped = r#"{"bytes": "0", "packets": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"}"#;
+        let (issues, raw, parsed) = check(&tcp_flow(&[&tcp_packet(""), dropped]));
+        assert_eq!(issues, []);
+        assert_eq!(raw.packet_count(), 2);
+        assert_eq!(raw.dropped_packets(), 1);
+        assert!(parsed);
+
+        // Without any packet left the flow parses, but to nothing
+        let (issues, raw, _) = check(&tcp_flow(&[dropped, dropped]));
+        assert_eq!(issues, [FlowIssue::EmptyFlow]);
+        assert_eq!(raw.dropped_packets(), 2);
+        let (issues, _, _) = check(&tcp_flow(&[]));
+        assert_eq!(issues, [FlowIssue::EmptyFlow]);
+    }
+
+    #[test]
+    fn direction_is_checked_like_the_parser() {
+        for (direction, valid) in [
+            ("outgoing", true),
+            ("IN", true),
+            ("backward", true),
+            ("unknown", true),
+            ("sideways", false),
+            ("", false),
+        ] {
+            let packet = tcp_packet(&format!(r#", "direction": "{direction}""#));
+            let (issues, _, parsed) = check(&tcp_flow(&[&packet]));
+            assert_eq!(parsed, valid, "{direction:?}");
+            if valid {
+                assert_eq!(issues, [], "{direction:?}");
+            } else {
+                assert_eq!(issues, [FlowIssue::InvalidDirection], "{direction:?}");
+            }
+        }
+    }
+
+    fn gre_flow(bytes: &str, packets: &str, ip_header_length: &str) -> String {
+        format!(
+            r#"{{"ip_proto": "gre", "port_src": 0, "port_dst": 0, "x_packets": [{{"bytes": "{bytes}", "ip_header_len": "{ip_header_length}", "packets": "{packets}", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:01"}}]}}"#
+        )
+    }
+
+    fn base_packet(json: &str) -> BasePacket {
+        let file = format!("[{json}]");
+        let parsed = FlowReader::new(file.as_bytes(), "test.json", ParseMode::Strict)
+            .read_all()
+            .unwrap();
+        let packets: Vec<&BasePacket> = (&parsed.flows[0]).into();
+        packets[0].clone()
+    }
+
+    #[test]
+    fn counters_take_their_full_range() {
+        let max = i64::MAX.to_string();
+        let packet = base_packet(&gre_flow(&max, &u32::MAX.to_string(), "65535"));
+        assert_eq!(packet.bytes, i64::MAX as u64);
+        assert_eq!(packet.packets, u32::MAX);
+        assert_eq!(packet.ip_header_length, u16::MAX);
+
+        // The old u8 fields overflowed at 256.
+        let packet = base_packet(&gre_flow("256", "256", "256"));
+        assert_eq!(
+            (packet.bytes, packet.packets, packet.ip_header_length),
+            (256, 256, 256)
+        );
+
+        // Negative byte counts only carry the direction.
+        let packet = base_packet(&gre_flow(&i64::MIN.to_string(), "1", "20"));
+        assert_eq!(packet.bytes, 1 << 63);
+        assert_eq!(packet.direction, PacketDirection::Incoming);
+        let packet = base_packet(&gre_flow("-4294967296", "1", "20"));
+        assert_eq!(packet.bytes, u64::from(u32::MAX) + 1);
+    }
+
+    #[test]
+    fn counters_past_their_range_are_rejected() {
+        let past_i64 = (i128::from(i64::MAX) + 1).to_string();
+        let past_u32 = (u64::from(u32::MAX) + 1).to_string();
+        for (json, field, issue) in [
+            (
+                gre_flow(&past_i64, "1", "20"),
+                "bytes",
+                FlowIssue::BytesOverflow,
+            ),
+            (
+                gre_flow(&(i128::from(i64::MIN) - 1).to_string(), "1", "20"),
+                "bytes",
+                FlowIssue::BytesOverflow,
+            ),
+            (
+                gre_flow("1", &past_u32, "20"),
+                "packets",
+                FlowIssue::PacketsOverflow,
+            ),
+            (
+                gre_flow("1", "-1", "20"),
+                "packets",
+                FlowIssue::PacketsOverflow,
+            ),
+            (
+                gre_flow("1", "1", "65536"),
+                "ip_header_len",
+                FlowIssue::InvalidIpHeaderLength,
+            ),
+            (
+                gre_flow("1", "1", "-1"),
+                "ip_header_len",
+                FlowIssue::InvalidIpHeaderLength,
+            ),
+        ] {
+            let (issues, _, parsed) = check(&json);
+            assert_eq!(issues, [issue], "{json}");
+            assert!(!parsed, "{json}");
+            let file = format!("[{json}]");
+            let err = FlowReader::new(file.as_bytes(), "test.json", ParseMode::Strict)
+                .read_all()
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(field) && err.contains("out of range"), "{err}");
+        }
+
+        let packet =
+            tcp_packet("").replace(r#""tcp_header_len": "40""#, r#""tcp_header_len": "65536""#);
+        let (issues, _, parsed) = check(&tcp_flow(&[&packet]));
+        assert_eq!(issues, [FlowIssue::InvalidTcpField]);
+        assert!(!parsed);
+    }
+
+    /// The flow a file with one single packet flow of `ip_proto` parses to,
+    /// `ip_proto` given as raw JSON.
+    fn protocol_flow(ip_proto: &str) -> Result<categories::IpProtocol, ParseError> {
+        let file = format!(
+            r#"[{{"ip_proto": {ip_proto}, "port_src": 0, "port_dst": 0, "x_packets": [{{"bytes": "60", "ip_header_len": "20", "packets": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"}}]}}]"#
+        );
+        let mut parsed =
+            FlowReader::new(file.as_bytes(), "test.json", ParseMode::Strict).read_all()?;
+        Ok(parsed.flows.remove(0))
+    }
+
+    #[test]
+    fn protocols_are_read_by_name_or_number() {
+        use categories::IpProtocol;
+        let flow = |ip_proto| protocol_flow(ip_proto).unwrap();
+        for (ip_proto, number) in [
+            (r#""esp""#, IpProtocol::ESP),
+            (r#""AH""#, IpProtocol::AH),
+            (r#""icmpv6""#, IpProtocol::ICMPV6),
+            (r#""ipv6-icmp""#, IpProtocol::ICMPV6),
+            (r#""sctp""#, IpProtocol::SCTP),
+            (r#""132""#, IpProtocol::SCTP),
+            ("132", IpProtocol::SCTP),
+            ("50", IpProtocol::ESP),
+        ] {
+            assert_eq!(flow(ip_proto).number(), number, "{ip_proto}");
+        }
+        assert!(matches!(flow(r#""esp""#), IpProtocol::Esp(_)));
+        assert!(matches!(flow(r#""ah""#), IpProtocol::Ah(_)));
+        assert!(matches!(flow(r#""ipv6-icmp""#), IpProtocol::Icmpv6(_)));
+        assert!(matches!(
+            flow(r#""132""#),
+            IpProtocol::Other(IpProtocol::SCTP, _)
+        ));
+        assert!(matches!(flow("253"), IpProtocol::Other(253, _)));
+
+        assert!(protocol_flow(r#""quic""#).is_err());
+    }
+
+    #[test]
+    fn udp_lengths_are_read_or_derived() {
+        let udp_flow = |bytes: &str, packets: &str, extra: &str| {
+            format!(
+                r#"[{{"ip_proto": "udp", "port_src": 1, "port_dst": 2, "x_packets": [{{"bytes": "{bytes}", "ip_header_len": "20", "packets": "{packets}", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"{extra}}}]}}]"#
+            )
+        };
+        let read = |file: String| {
+            FlowReader::new(file.as_bytes(), "test.json", ParseMode::Strict).read_all()
+        };
+        let udp_packet = |file: String| {
+            let parsed = read(file).unwrap();
+            let categories::IpProtocol::Udp(flow) = &parsed.flows[0] else {
+                panic!("expected a UDP flow: {:?}", parsed.flows[0]);
+            };
+            (flow.packets[0].udp_length, flow.packets[0].payload_length)
+        };
+        // Taken as given, even where it disagrees with the IP length
+        assert_eq!(
+            udp_packet(udp_flow("128", "1", r#", "udp_len": "50""#)),
+            (50, 42)
+        );
+        assert_eq!(udp_packet(udp_flow("-128", "1", "")), (108, 100));
+        assert_eq!(udp_packet(udp_flow("1280", "10", "")), (108, 100));
+
+        assert!(read(udp_flow("128", "1", r#", "udp_len": "65536""#)).is_err());
+    }
+
+    #[test]
+    fn flows_longer_than_a_byte_keep_every_packet() {
+        for count in [255, 256, 257] {
+            let packets = vec![tcp_packet(""); count];
+            let packets = packets.iter().map(String::as_str).collect::<Vec<_>>();
+            let file = format!("[{}]", tcp_flow(&packets));
+            let parsed = FlowReader::new(file.as_bytes(), "test.json", ParseMode::Strict)
+                .read_all()
+                .unwrap();
+            assert_eq!(Vec::<&BasePacket>::from(&parsed.flows[0]).len(), count);
+        }
+    }
+
+    #[test]
+    fn epoch_units_are_told_apart_by_magnitude() {
+        let parse = |value: &str| custom_datetime_format::parse(value).unwrap().to_rfc3339();
+        assert_eq!(parse("1677664800"), "2023-03-01T10:00:00+00:00");
+        assert_eq!(parse("1677664800.25"), "2023-03-01T10:00:00.250+00:00");
+        assert_eq!(parse("1677664800123"), "2023-03-01T10:00:00.123+00:00");
+        assert_eq!(parse("1677664800123.5"), "2023-03-01T10:00:00.123500+00:00");
+        assert_eq!(
+            parse("1677664800123456"),
+            "2023-03-01T10:00:00.123456+00:00"
+        );
+        // Either side of the switch from seconds to milliseconds.
+        assert_eq!(parse("99999999999"), "5138-11-16T09:46:39+00:00");
+        assert_eq!(parse("100000000000"), "1973-03-03T09:46:40+00:00");
+        assert_eq!(parse("-100000000000"), "1966-10-31T14:13:20+00:00");
+        // And from milliseconds to microseconds.
+        assert_eq!(parse("99999999999999"), "5138-11-16T09:46:39.999+00:00");
+        assert_eq!(parse("100000000000000"), "1973-03-03T09:46:40+00:00");
+    }
+
+    #[test]
+    fn text_timestamps_are_read_as_utc() {
+        let parse = |value: &str| custom_datetime_format::parse(value).unwrap().to_rfc3339();
+        assert_eq!(
+            parse("2023-03-01 10:00:00.5"),
+            "2023-03-01T10:00:00.500+00:00"
+        );
+        assert_eq!(
+            parse("2023-03-01T12:00:00+02:00"),
+            "2023-03-01T10:00:00+00:00"
+        );
+        assert!(custom_datetime_format::parse("yesterday").is_err());
+        assert!(custom_datetime_format::parse("NaN").is_err());
+    }
 }
@@ -0,0 +1,544 @@
+use crate::categories;
+use crate::categories::PacketDirection;
+use crate::data_structure::{BasePacket, Data, Endpoint, FlowKey, TcpFlags, TcpPacket, UdpPacket};
+use crate::parse_data::{ParseError, ParsedFile};
+use chrono::{DateTime, Utc};
+use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+use pcap_parser::pcapng::Block;
+use pcap_parser::traits::PcapNGPacketBlock;
+use pcap_parser::{create_reader, Linktype, PcapBlockOwned, PcapError};
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+const READER_CAPACITY: usize = 1 << 20;
+const CAPTURE_EXTENSIONS: [&str; 3] = ["pcap", "pcapng", "cap"];
+
+/// Whether `path` names a capture rather than a JSON flow file, judged by
+/// its extension.
+pub fn is_capture(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            CAPTURE_EXTENSIONS
+                .iter()
+                .any(|capture| capture.eq_ignore_ascii_case(extension))
+        })
+}
+
+#[derive(Debug)]
+struct TcpFields {
+    header_len: u16,
+    flags: u8,
+    acknowledgment_number: u32,
+    sequence_number: u32,
+}
+
+#[derive(Debug)]
+struct CapturedPacket {
+    protocol: u8,
+    source: Endpoint,
+    destination: Endpoint,
+    bytes: u64,
+    ip_header_length: u16,
+    timestamp: DateTime<Utc>,
+    tcp: Option<TcpFields>,
+    udp_length: Option<u16>,
+}
+
+/// A flow is oriented by its first packet: that packet's source becomes
+/// `port_src` and everything it sends is [`PacketDirection::Outgoing`], which
+/// is what a positive `bytes` value means in the JSON dataset.
+struct FlowBuilder {
+    protocol: u8,
+    source: Endpoint,
+    destination: Endpoint,
+    packets: Vec<(PacketDirection, CapturedPacket)>,
+}
+
+impl FlowBuilder {
+    fn base_packet(direction: PacketDirection, packet: &CapturedPacket) -> BasePacket {
+        BasePacket {
+            bytes: packet.bytes,
+            direction,
+            ip_header_length: packet.ip_header_length,
+            packets: 1,
+            timestamp_start: packet.timestamp,
+            timestamp_end: packet.timestamp,
+        }
+    }
+
+    /// TCP packets without TCP fields never make it into a flow, see
+    /// [`FlowTable::push`].
+    fn into_flow(self) -> categories::IpProtocol {
+        if self.protocol == categories::IpProtocol::TCP {
+            let packets = self
+                .packets
+                .into_iter()
+                .filter_map(|(direction, packet)| {
+                    let base = Self::base_packet(direction, &packet);
+                    let tcp = packet.tcp?;
+                    Some(TcpPacket {
+                        base,
+                        tcp_header_len: tcp.header_len,
+                        tcp_flags: TcpFlags::from_bits_retain(tcp.flags),
+                        tcp_acknowledgment_number: tcp.acknowledgment_number,
+                        tcp_sequence_number: tcp.sequence_number,
+                    })
+                })
+                .collect();
+            return categories::IpProtocol::Tcp(Data {
+                ip_destination: self.destination.address,
+                ip_source: self.source.address,
+                port_destination: self.destination.port,
+                port_source: self.source.port,
+                packets,
+            });
+        }
+        if self.protocol == categories::IpProtocol::UDP {
+            let packets = self
+                .packets
+                .iter()
+                .map(|(direction, packet)| {
+                    let base = Self::base_packet(*direction, packet);
+                    match packet.udp_length {
+                        Some(udp_length) => UdpPacket::new(base, udp_length),
+                        None => UdpPacket::from_base(base),
+                    }
+                })
+                .collect();
+            return categories::IpProtocol::Udp(Data {
+                ip_destination: self.destination.address,
+                ip_source: self.source.address,
+                port_destination: self.destination.port,
+                port_source: self.source.port,
+                packets,
+            });
+        }
+        let data = Data {
+            ip_destination: self.destination.address,
+            ip_source: self.source.address,
+            port_destination: self.destination.port,
+            port_source: self.source.port,
+            packets: self
+                .packets
+                .iter()
+                .map(|(direction, packet)| Self::base_packet(*direction, packet))
+                .collect(),
+        };
+        categories::IpProtocol::from_number(self.protocol, data)
+    }
+}
+
+fn slice_frame(linktype: Linktype, data: &[u8]) -> Option<SlicedPacket<'_>> {
+    match linktype {
+        Linktype::ETHERNET => SlicedPacket::from_ethernet(data).ok(),
+        Linktype::LINUX_SLL => SlicedPacket::from_linux_sll(data).ok(),
+        Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => SlicedPacket::from_ip(data).ok(),
+        // BSD loopback prefixes every frame with a 4 byte address family.
+        Linktype::NULL | Linktype::LOOP => SlicedPacket::from_ip(data.get(4..)?).ok(),
+        _ => None,
+    }
+}
+
+fn capture_packet(
+    linktype: Linktype,
+    timestamp: DateTime<Utc>,
+    data: &[u8],
+) -> Option<CapturedPacket> {
+    let sliced = slice_frame(linktype, data)?;
+    let (source, destination, protocol, bytes, ip_header_length) = match sliced.net? {
+        NetSlice::Ipv4(ipv4) => {
+            let header = ipv4.header();
+            (
+                IpAddr::V4(header.source_addr()),
+                IpAddr::V4(header.destination_addr()),
+                ipv4.payload_ip_number().0,
+                u64::from(header.total_len()),
+                u16::from(header.ihl()) * 4,
+            )
+        }
+        NetSlice::Ipv6(ipv6) => {
+            let header = ipv6.header();
+            let header_length = 40 + ipv6.extensions().slice().len();
+            (
+                IpAddr::V6(header.source_addr()),
+                IpAddr::V6(header.destination_addr()),
+                ipv6.payload().ip_number.0,
+                40 + u64::from(header.payload_length()),
+                u16::try_from(header_length).unwrap_or(u16::MAX),
+            )
+        }
+    };
+    let (source_port, destination_port, tcp, udp_length) = match sliced.transport {
+        Some(TransportSlice::Tcp(tcp)) => (
+            tcp.source_port(),
+            tcp.destination_port(),
+            Some(TcpFields {
+                header_len: u16::from(tcp.data_offset()) * 4,
+                flags: tcp.header_slice()[13],
+                acknowledgment_number: tcp.acknowledgment_number(),
+                sequence_number: tcp.sequence_number(),
+            }),
+            None,
+        ),
+        Some(TransportSlice::Udp(udp)) => (
+            udp.source_port(),
+            udp.destination_port(),
+            None,
+            Some(udp.length()),
+        ),
+        _ => (0, 0, None, None),
+    };
+    Some(CapturedPacket {
+        protocol,
+        source: Endpoint {
+            address: Some(source),
+            port: source_port,
+        },
+        destination: Endpoint {
+            address: Some(destination),
+            port: destination_port,
+        },
+        bytes,
+        ip_header_length,
+        timestamp,
+        tcp,
+        udp_length,
+    })
+}
+
+fn timestamp(seconds: i64, nanoseconds: u32) -> DateTime<Utc> {
+    DateTime::from_timestamp(seconds, nanoseconds).unwrap_or_default()
+}
+
+struct Interface {
+    linktype: Linktype,
+    /// Timestamp units per second.
+    resolution: u64,
+    /// Seconds added to every timestamp.
+    offset: i64,
+}
+
+impl Interface {
+    fn timestamp(&self, units: u64) -> DateTime<Utc> {
+        let seconds = (units / self.resolution) as i64 + self.offset;
+        let fraction =
+            u128::from(units % self.resolution) * 1_000_000_000 / u128::from(self.resolution);
+        timestamp(seconds, fraction as u32)
+    }
+}
+
+#[derive(Default)]
+struct FlowTable {
+    flows: Vec<FlowBuilder>,
+    index: HashMap<FlowKey, usize>,
+    skipped_packets: usize,
+    /// TCP packets without a TCP header to read, such as IPv4 fragments after
+    /// the first or packets cut short by the snapshot length.
+    dropped_packets: usize,
+}
+
+impl FlowTable {
+    fn push(&mut self, linktype: Linktype, timestamp: DateTime<Utc>, data: &[u8]) {
+        let Some(packet) = capture_packet(linktype, timestamp, data) else {
+            self.skipped_packets += 1;
+            return;
+        };
+        if packet.protocol == categories::IpProtocol::TCP && packet.tcp.is_none() {
+            self.dropped_packets += 1;
+            return;
+        }
+        let key = FlowKey::new(packet.protocol, packet.source, packet.destination);
+        let index = *self.index.entry(key).or_insert_with(|| {
+            self.flows.push(FlowBuilder {
+                protocol: packet.protocol,
+                source: packet.source,
+                destination: packet.destination,
+                packets: vec![],
+            });
+            self.flows.len() - 1
+        });
+        let flow = &mut self.flows[index];
+        let direction = if packet.source == flow.source {
+            PacketDirection::Outgoing
+        } else {
+            PacketDirection::Incoming
+        };
+        flow.packets.push((direction, packet));
+    }
+
+    fn finish(self) -> ParsedFile {
+        ParsedFile {
+            flows: self.flows.into_iter().map(FlowBuilder::into_flow).collect(),
+            skipped_flows: 0,
+        }
+    }
+}
+
+/// Reads a pcap or pcapng capture and groups its packets into bidirectional
+/// 5-tuple flows, in the order each flow was first seen.
+pub fn get_pcap_data(path: impl AsRef<Path>) -> Result<ParsedFile, ParseError> {
+    let path = path.as_ref();
+    let pcap_error = |message: String| ParseError::Pcap {
+        path: path.to_path_buf(),
+        message,
+    };
+    let file = fs::File::open(path).map_err(|source| ParseError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut reader =
+        create_reader(READER_CAPACITY, file).map_err(|err| pcap_error(err.to_string()))?;
+
+    let mut table = FlowTable::default();
+    let mut legacy_linktype = Linktype::ETHERNET;
+    let mut legacy_fraction_unit = 1_000;
+    let mut interfaces: Vec<Interface> = vec![];
+    // Simple packet blocks carry no timestamp, so they reuse the last one seen.
+    let mut last_timestamp = DateTime::<Utc>::default();
+    loop {
+        match reader.next() {
+            Ok((offset, block)) => {
+                match block {
+                    PcapBlockOwned::LegacyHeader(header) => {
+                        legacy_linktype = header.network;
+                        legacy_fraction_unit = if header.is_nanosecond_precision() {
+                            1
+                        } else {
+                            1_000
+                        };
+                    }
+                    PcapBlockOwned::Legacy(frame) => {
+                        last_timestamp = timestamp(
+                            frame.ts_sec.into(),
+                            frame.ts_usec.saturating_mul(legacy_fraction_unit),
+                        );
+                        table.push(legacy_linktype, last_timestamp, frame.data)
+                    }
+                    PcapBlockOwned::NG(Block::SectionHeader(_)) => interfaces.clear(),
+                    PcapBlockOwned::NG(Block::InterfaceDescription(interface)) => {
+                        interfaces.push(Interface {
+                            linktype: interface.linktype,
+                            resolution: interface.ts_resolution().ok_or_else(|| {
+                                pcap_error("invalid interface timestamp resolution".to_string())
+                            })?,
+                            offset: interface.ts_offset(),
+                        })
+                    }
+                    PcapBlockOwned::NG(Block::EnhancedPacket(packet)) => {
+                        let Some(interface) = interfaces.get(packet.if_id as usize) else {
+                            return Err(pcap_error(format!(
+                                "packet refers to unknown interface {}",
+                                packet.if_id
+                            )));
+                        };
+                        let units = (u64::from(packet.ts_high) << 32) | u64::from(packet.ts_low);
+                        last_timestamp = interface.timestamp(units);
+                        table.push(interface.linktype, last_timestamp, packet.packet_data())
+                    }
+                    PcapBlockOwned::NG(Block::SimplePacket(packet)) => {
+                        let interface = interfaces.first().ok_or_else(|| {
+                            pcap_error("packet before any interface description".to_string())
+                        })?;
+                        table.push(interface.linktype, last_timestamp, packet.packet_data())
+                    }
+                    PcapBlockOwned::NG(_) => {}
+                }
+                reader.consume(offset);
+            }
+            Err(PcapError::Eof) => break,
+            Err(PcapError::Incomplete(_)) => {
+                reader.refill().map_err(|err| pcap_error(err.to_string()))?
+            }
+            Err(err) => return Err(pcap_error(err.to_string())),
+        }
+    }
+
+    if table.skipped_packets > 0 {
+        log::warn!(
+            "skipped {} non-IP packets in {}",
+            table.skipped_packets,
+            path.display()
+        );
+    }
+    if table.dropped_packets > 0 {
+        log::warn!(
+            "dropped {} TCP packets without a readable TCP header in {}",
+            table.dropped_packets,
+            path.display()
+        );
+    }
+    Ok(table.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::IpProtocol;
+    use crate::test_support::{fixture, scratch_dir};
+
+    fn directions<P>(flow: &Data<P>, base: impl Fn(&P) -> &BasePacket) -> Vec<PacketDirection>
+    where
+        P: Clone + std::fmt::Debug,
+    {
+        flow.packets
+            .iter()
+            .map(|packet| base(packet).direction)
+            .collect()
+    }
+
+    #[test]
+    fn recognises_capture_extensions() {
+        assert!(is_capture(Path::new("a/b.pcap")));
+        assert!(is_capture(Path::new("b.PCAPNG")));
+        assert!(is_capture(Path::new("b.cap")));
+        assert!(!is_capture(Path::new("b.json")));
+        assert!(!is_capture(Path::new("pcap")));
+    }
+
+    #[test]
+    fn merges_both_directions_of_a_five_tuple() {
+        let parsed = get_pcap_data(fixture("capture.pcap")).unwrap();
+        assert_eq!(parsed.flows.len(), 3);
+        assert_eq!(parsed.skipped_flows, 0);
+
+        let IpProtocol::Tcp(handshake) = &parsed.flows[0] else {
+            panic!("expected the first flow to be TCP: {:?}", parsed.flows[0]);
+        };
+        assert_eq!(handshake.port_source, 5000);
+        assert_eq!(handshake.port_destination, 443);
+        assert_eq!(handshake.ip_source, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(
+            directions(handshake, |packet| &packet.base),
+            [
+                PacketDirection::Outgoing,
+                PacketDirection::Incoming,
+                PacketDirection::Outgoing,
+                PacketDirection::Incoming,
+            ]
+        );
+        let flags = handshake
+            .packets
+            .iter()
+            .map(|packet| packet.tcp_flags)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flags,
+            [
+                TcpFlags::SYN,
+                TcpFlags::SYN | TcpFlags::ACK,
+                TcpFlags::ACK,
+                TcpFlags::FIN | TcpFlags::ACK,
+            ]
+        );
+        assert_eq!(handshake.packets[0].tcp_sequence_number, 100);
+        assert_eq!(handshake.packets[3].base.bytes, 42);
+        assert_eq!(
+            handshake.inter_arrival_times()[0].num_microseconds(),
+            Some(500)
+        );
+
+        // Another source port is another flow, even towards the same server.
+        let IpProtocol::Tcp(second) = &parsed.flows[1] else {
+            panic!("expected the second flow to be TCP: {:?}", parsed.flows[1]);
+        };
+        assert_eq!(second.port_source, 5001);
+        assert_eq!(second.packets.len(), 1);
+
+        let IpProtocol::Udp(dns) = &parsed.flows[2] else {
+            panic!("expected the third flow to be UDP: {:?}", parsed.flows[2]);
+        };
+        assert_eq!(dns.port_destination, 53);
+        assert_eq!(dns.packets[0].payload_length, 4);
+        assert_eq!(dns.packets[0].base.timestamp_start, timestamp(3, 0));
+    }
+
+    #[test]
+    fn resolves_timestamps_per_interface() {
+        let parsed = get_pcap_data(fixture("capture.pcapng")).unwrap();
+        assert_eq!(parsed.flows.len(), 1);
+        let IpProtocol::Udp(flow) = &parsed.flows[0] else {
+            panic!("expected a UDP flow: {:?}", parsed.flows[0]);
+        };
+        assert_eq!(
+            directions(flow, |packet| &packet.base),
+            [
+                PacketDirection::Outgoing,
+                PacketDirection::Incoming,
+                PacketDirection::Outgoing,
+            ]
+        );
+        let timestamps = flow
+            .packets
+            .iter()
+            .map(|packet| packet.base.timestamp_start)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            timestamps,
+            [
+                // Interface 0 counts milliseconds from an offset of 100 s,
+                timestamp(101, 500_000_000),
+                // interface 1 counts microseconds from the epoch.
+                timestamp(2, 250_000),
+                timestamp(102, 500_000_000),
+            ]
+        );
+        let payloads = flow
+            .packets
+            .iter()
+            .map(|packet| packet.payload_length)
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, [5, 2, 5]);
+    }
+
+    /// An IPv4 packet from 10.0.0.1 to 10.0.0.2, `offset` 8 byte units into
+    /// the original datagram.
+    fn ipv4(protocol: u8, offset: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend((20 + payload.len() as u16).to_be_bytes());
+        packet.extend([0, 1]);
+        packet.extend(offset.to_be_bytes());
+        packet.extend([64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend(payload);
+        packet
+    }
+
+    #[test]
+    fn drops_tcp_packets_without_a_tcp_header() {
+        let syn = [
+            0x13, 0x88, 0, 80, 0, 0, 0, 100, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0,
+        ];
+        // A raw IP capture: the global header, then every frame behind its
+        // record header
+        let mut capture = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        capture.extend([0; 8]);
+        capture.extend(65535u32.to_le_bytes());
+        capture.extend(101u32.to_le_bytes());
+        for (second, frame) in [
+            ipv4(6, 0, &syn),
+            ipv4(6, 1, &[0; 8]),
+            ipv4(6, 0, &syn[..10]),
+        ]
+        .iter()
+        .enumerate()
+        {
+            capture.extend((second as u32).to_le_bytes());
+            capture.extend(0u32.to_le_bytes());
+            capture.extend((frame.len() as u32).to_le_bytes());
+            capture.extend((frame.len() as u32).to_le_bytes());
+            capture.extend(frame);
+        }
+        let path = scratch_dir("pcap-tcp-fragments").join("fragments.pcap");
+        fs::write(&path, capture).unwrap();
+
+        let parsed = get_pcap_data(&path).unwrap();
+        assert_eq!(parsed.flows.len(), 1, "{:?}", parsed.flows);
+        let IpProtocol::Tcp(flow) = &parsed.flows[0] else {
+            panic!("expected a TCP flow: {:?}", parsed.flows[0]);
+        };
+        assert_eq!((flow.port_source, flow.port_destination), (5000, 80));
+        assert_eq!(flow.packets.len(), 1);
+    }
+}
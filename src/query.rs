@@ -0,0 +1,505 @@
+use crate::categories::{DataCategory, IpProtocol, VPN};
+use crate::data_structure::{BasePacket, MetadataWrapper};
+use crate::labels::{name_key, Label, ENCRYPTED, UNENCRYPTED};
+use chrono::{DateTime, Utc};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Selects flows by label and by flow properties, e.g. OpenVPN SSH flows on
+/// port 443 with more than 50 packets:
+///
+/// ```ignore
+/// FlowQuery::new()
+///     .with_vpn(VPN::OpenVPN)
+///     .with_category(DataCategory::SSH)
+///     .with_port(443)
+///     .with_min_packets(51)
+/// ```
+///
+/// Every condition left unset matches everything. Names are compared
+/// ignoring case and whitespace, like [`LabelRegistry::find`] does.
+///
+/// [`LabelRegistry::find`]: crate::labels::LabelRegistry::find
+#[derive(Clone, Debug, Default)]
+pub struct FlowQuery {
+    group: Option<Arc<str>>,
+    tunnel: Option<Arc<str>>,
+    application: Option<Arc<str>>,
+    protocol: Option<u8>,
+    port: Option<u16>,
+    min_packets: Option<u64>,
+    max_packets: Option<u64>,
+    min_bytes: Option<u64>,
+    max_bytes: Option<u64>,
+    after: Option<DateTime<Utc>>,
+    before: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue { option: String, value: String },
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::UnknownOption(option) => write!(f, "unknown query option {option}"),
+            QueryError::MissingValue(option) => write!(f, "{option} needs a value"),
+            QueryError::InvalidValue { option, value } => {
+                write!(f, "invalid value {value:?} for {option}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A flow picked by a [`FlowQuery`], borrowed from its wrapper.
+#[derive(Clone, Copy, Debug)]
+pub struct FlowView<'a> {
+    pub label: &'a Label,
+    pub flow: &'a IpProtocol,
+}
+
+impl FlowQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only encrypted or only unencrypted traffic.
+    pub fn encrypted(mut self, encrypted: bool) -> Self {
+        self.group = Some(if encrypted { ENCRYPTED } else { UNENCRYPTED }.into());
+        self
+    }
+
+    pub fn with_group(mut self, group: impl Into<Arc<str>>) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+
+    pub fn with_tunnel(mut self, tunnel: impl Into<Arc<str>>) -> Self {
+        self.tunnel = Some(tunnel.into());
+        self
+    }
+
+    pub fn with_vpn(self, vpn: VPN) -> Self {
+        self.with_tunnel(vpn.to_string())
+    }
+
+    pub fn with_application(mut self, application: impl Into<Arc<str>>) -> Self {
+        self.application = Some(application.into());
+        self
+    }
+
+    pub fn with_category(self, category: DataCategory) -> Self {
+        self.with_application(category.to_string())
+    }
+
+    /// The IANA protocol number, see [`IpProtocol::number`].
+    pub fn with_protocol(mut self, protocol: u8) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Either the source or the destination port.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Packets are counted through [`BasePacket::packets`], so aggregated
+    /// records count for every packet they stand for.
+    pub fn with_min_packets(mut self, packets: u64) -> Self {
+        self.min_packets = Some(packets);
+        self
+    }
+
+    pub fn with_max_packets(mut self, packets: u64) -> Self {
+        self.max_packets = Some(packets);
+        self
+    }
+
+    /// Bounds on the bytes summed over the flow.
+    pub fn with_min_bytes(mut self, bytes: u64) -> Self {
+        self.min_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Flows overlapping `start..end`, either side open when `None`.
+    pub fn with_time_window(
+        mut self,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Self {
+        self.after = start;
+        self.before = end;
+        self
+    }
+
+    /// Reads a query from command line options, one per condition:
+    /// `--group`, `--tunnel`, `--application`, `--protocol` (a name like
+    /// `tcp` or a number), `--port`, `--min-packets`, `--max-packets`,
+    /// `--min-bytes`, `--max-bytes`, and `--after` and `--before` as RFC 3339
+    /// timestamps.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, QueryError> {
+        fn value<T: FromStr>(option: &str, value: Option<String>) -> Result<T, QueryError> {
+            let value = value.ok_or_else(|| QueryError::MissingValue(option.to_string()))?;
+            value.parse().map_err(|_| QueryError::InvalidValue {
+                option: option.to_string(),
+                value,
+            })
+        }
+
+        let mut query = Self::new();
+        let mut args = args.into_iter();
+        while let Some(option) = args.next() {
+            let next = args.next();
+            query = match option.as_str() {
+                "--group" => query.with_group(value::<String>(&option, next)?),
+                "--tunnel" => query.with_tunnel(value::<String>(&option, next)?),
+                "--application" => query.with_application(value::<String>(&option, next)?),
+                "--protocol" => {
+                    let name = value::<String>(&option, next)?;
+                    match IpProtocol::number_from_name(&name).or_else(|| name.parse().ok()) {
+                        Some(protocol) => query.with_protocol(protocol),
+                        None => {
+                            return Err(QueryError::InvalidValue {
+                                option,
+                                value: name,
+                            })
+                        }
+                    }
+                }
+                "--port" => query.with_port(value(&option, next)?),
+                "--min-packets" => query.with_min_packets(value(&option, next)?),
+                "--max-packets" => query.with_max_packets(value(&option, next)?),
+                "--min-bytes" => query.with_min_bytes(value(&option, next)?),
+                "--max-bytes" => query.with_max_bytes(value(&option, next)?),
+                "--after" => {
+                    let before = query.before;
+                    query.with_time_window(Some(value(&option, next)?), before)
+                }
+                "--before" => {
+                    let after = query.after;
+                    query.with_time_window(after, Some(value(&option, next)?))
+                }
+                _ => return Err(QueryError::UnknownOption(option)),
+            };
+        }
+        Ok(query)
+    }
+
+    pub fn matches_label(&self, label: &Label) -> bool {
+        let matches = |wanted: &Option<Arc<str>>, actual: &str| {
+            wanted
+                .as_deref()
+                .is_none_or(|wanted| name_key(wanted) == name_key(actual))
+        };
+        matches(&self.group, &label.group)
+            && matches(&self.tunnel, &label.tunnel)
+            && matches(&self.application, &label.application)
+    }
+
+    pub fn matches(&self, flow: &IpProtocol) -> bool {
+        if self
+            .protocol
+            .is_some_and(|protocol| protocol != flow.number())
+        {
+            return false;
+        }
+        if let Some(port) = self.port {
+            let (source, destination) = flow.endpoints();
+            if source.port != port && destination.port != port {
+                return false;
+            }
+        }
+        match flow {
+            IpProtocol::Tcp(data) => self.matches_packets(&data.packets),
+            IpProtocol::Udp(data) => self.matches_packets(&data.packets),
+            IpProtocol::Gre(data)
+            | IpProtocol::Icmp(data)
+            | IpProtocol::Esp(data)
+            | IpProtocol::Ah(data)
+            | IpProtocol::Icmpv6(data)
+            | IpProtocol::Other(_, data) => self.matches_packets(&data.packets),
+        }
+    }
+
+    /// The bounds that need the packets, each only walked when it is set.
+    fn matches_packets<P: AsRef<BasePacket>>(&self, packets: &[P]) -> bool {
+        let packets = || packets.iter().map(AsRef::as_ref);
+        let in_range = |value: u64, min: Option<u64>, max: Option<u64>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        if self.min_packets.is_some() || self.max_packets.is_some() {
+            let count = packets().map(|packet| u64::from(packet.packets)).sum();
+            if !in_range(count, self.min_packets, self.max_packets) {
+                return false;
+            }
+        }
+        if self.min_bytes.is_some() || self.max_bytes.is_some() {
+            let bytes = packets().map(|packet| packet.bytes).sum();
+            if !in_range(bytes, self.min_bytes, self.max_bytes) {
+                return false;
+            }
+        }
+        if self.after.is_some() || self.before.is_some() {
+            let start = packets().map(|packet| packet.timestamp_start).min();
+            let end = packets().map(|packet| packet.timestamp_end).max();
+            let (Some(start), Some(end)) = (start, end) else {
+                return false;
+            };
+            if self.after.is_some_and(|after| end < after)
+                || self.before.is_some_and(|before| start >= before)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Every matching flow of `data`, without copying any packets.
+    pub fn select<'a>(
+        &'a self,
+        data: &'a [MetadataWrapper],
+    ) -> impl Iterator<Item = FlowView<'a>> + 'a {
+        data.iter()
+            .filter(|wrapper| self.matches_label(&wrapper.label))
+            .flat_map(move |wrapper| {
+                wrapper
+                    .all_packets
+                    .iter()
+                    .filter(|flow| self.matches(flow))
+                    .map(|flow| FlowView {
+                        label: &wrapper.label,
+                        flow,
+                    })
+            })
+    }
+
+    /// Drops every wrapper and flow that does not match, keeping the rest in
+    /// place.
+    pub fn retain(&self, data: &mut Vec<MetadataWrapper>) {
+        data.retain(|wrapper| self.matches_label(&wrapper.label));
+        for wrapper in data.iter_mut() {
+            wrapper.all_packets.retain(|flow| self.matches(flow));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::{Encryption, PacketDirection};
+    use crate::data_structure::{Data, TcpPacket};
+    use chrono::TimeDelta;
+
+    fn at(milliseconds: i64) -> DateTime<Utc> {
+        DateTime::UNIX_EPOCH + TimeDelta::milliseconds(milliseconds)
+    }
+
+    /// `packets` records of one packet and 100 bytes each, one millisecond
+    /// apart from `start`.
+    fn tcp_flow(port: u16, packets: usize, start: i64) -> IpProtocol {
+        let packets = (0..packets)
+            .map(|packet| TcpPacket {
+                base: BasePacket {
+                    bytes: 100,
+                    direction: PacketDirection::Outgoing,
+                    ip_header_length: 20,
+                    packets: 1,
+                    timestamp_start: at(start + packet as i64),
+                    timestamp_end: at(start + packet as i64),
+                },
+                tcp_header_len: 20,
+                tcp_flags: Default::default(),
+                tcp_acknowledgment_number: 0,
+                tcp_sequence_number: 0,
+            })
+            .collect();
+        IpProtocol::Tcp(Data {
+            ip_destination: None,
+            ip_source: None,
+            port_destination: port,
+            port_source: 50000,
+            packets,
+        })
+    }
+
+    fn wrapper(
+        encryption: Encryption,
+        category: DataCategory,
+        flows: Vec<IpProtocol>,
+    ) -> MetadataWrapper {
+        MetadataWrapper {
+            label: Label::builtin(&encryption, category),
+            all_packets: flows,
+            skipped_flows: 0,
+        }
+    }
+
+    fn openvpn_ssh_on_443() -> FlowQuery {
+        FlowQuery::new()
+            .with_vpn(VPN::OpenVPN)
+            .with_category(DataCategory::SSH)
+            .with_port(443)
+            .with_min_packets(51)
+    }
+
+    #[test]
+    fn selects_openvpn_ssh_on_443_with_more_than_50_packets() {
+        let openvpn = Encryption::VPN(VPN::OpenVPN);
+        let data = vec![
+            wrapper(
+                openvpn.clone(),
+                DataCategory::SSH,
+                vec![
+                    tcp_flow(443, 51, 0),
+                    tcp_flow(443, 50, 0),
+                    tcp_flow(22, 60, 0),
+                ],
+            ),
+            wrapper(openvpn, DataCategory::Mail, vec![tcp_flow(443, 60, 0)]),
+            wrapper(
+                Encryption::NonVPN,
+                DataCategory::SSH,
+                vec![tcp_flow(443, 60, 0)],
+            ),
+        ];
+
+        let query = openvpn_ssh_on_443();
+        let views = query.select(&data).collect::<Vec<_>>();
+        assert_eq!(views.len(), 1);
+        // A view points into the wrapper instead of holding a copy
+        assert!(std::ptr::eq(views[0].flow, &data[0].all_packets[0]));
+        assert!(std::ptr::eq(views[0].label, &data[0].label));
+
+        let mut retained = data.clone();
+        openvpn_ssh_on_443().retain(&mut retained);
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].all_packets.len(), 1);
+
+        assert_eq!(FlowQuery::new().select(&data).count(), 5);
+        assert_eq!(
+            FlowQuery::new().with_max_packets(50).select(&data).count(),
+            1
+        );
+        assert_eq!(
+            FlowQuery::new().with_min_bytes(5100).select(&data).count(),
+            4
+        );
+        assert_eq!(
+            FlowQuery::new().with_max_bytes(5000).select(&data).count(),
+            1
+        );
+        assert_eq!(FlowQuery::new().encrypted(false).select(&data).count(), 1);
+        assert_eq!(
+            FlowQuery::new()
+                .with_protocol(IpProtocol::UDP)
+                .select(&data)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn time_window_includes_flows_touching_its_start() {
+        // Ten packets from 1000 to 1009 milliseconds
+        let flow = tcp_flow(443, 10, 1000);
+        let window = |start: Option<i64>, end: Option<i64>| {
+            FlowQuery::new()
+                .with_time_window(start.map(at), end.map(at))
+                .matches(&flow)
+        };
+        assert!(window(None, None));
+        assert!(window(Some(1009), None));
+        assert!(!window(Some(1010), None));
+        assert!(window(None, Some(1001)));
+        // The end of the window is exclusive
+        assert!(!window(None, Some(1000)));
+        assert!(window(Some(1003), Some(1005)));
+        assert!(window(Some(0), Some(5000)));
+        assert!(!window(Some(0), Some(1000)));
+
+        // A flow without packets has no time to compare
+        let empty = tcp_flow(443, 0, 0);
+        assert!(!FlowQuery::new()
+            .with_time_window(Some(at(0)), None)
+            .matches(&empty));
+    }
+
+    #[test]
+    fn label_names_ignore_case_and_whitespace() {
+        let label = Label::builtin(&Encryption::VPN(VPN::OpenVPN), DataCategory::SSH);
+        assert!(FlowQuery::new()
+            .with_group("ENCRYPTED")
+            .with_tunnel("openvpn")
+            .with_application("ssh")
+            .matches_label(&label));
+        assert!(FlowQuery::new()
+            .with_tunnel("Open VPN")
+            .with_application(" S S H ")
+            .matches_label(&label));
+        assert!(!FlowQuery::new()
+            .with_tunnel("Open VPN2")
+            .matches_label(&label));
+        assert!(!FlowQuery::new().encrypted(false).matches_label(&label));
+    }
+
+    #[test]
+    fn reads_queries_from_arguments() {
+        let args = |line: &str| {
+            line.split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let query = FlowQuery::from_args(args(
+            "--tunnel openvpn --application SSH --port 443 --min-packets 51 --protocol tcp",
+        ))
+        .unwrap();
+        assert!(query.matches_label(&Label::builtin(
+            &Encryption::VPN(VPN::OpenVPN),
+            DataCategory::SSH
+        )));
+        assert_eq!(query.protocol, Some(IpProtocol::TCP));
+        assert_eq!(query.port, Some(443));
+        assert_eq!(query.min_packets, Some(51));
+
+        let query = FlowQuery::from_args(args(
+            "--before 1970-01-01T00:00:01.005Z --after 1970-01-01T00:00:01.003Z",
+        ))
+        .unwrap();
+        assert_eq!(
+            (query.after, query.before),
+            (Some(at(1003)), Some(at(1005)))
+        );
+
+        let protocol = FlowQuery::from_args(args("--protocol 47")).unwrap();
+        assert_eq!(protocol.protocol, Some(IpProtocol::GRE));
+
+        assert!(matches!(
+            FlowQuery::from_args(args("--port https")),
+            Err(QueryError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            FlowQuery::from_args(args("--protocol tcpip")),
+            Err(QueryError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            FlowQuery::from_args(args("--min-packets")),
+            Err(QueryError::MissingValue(_))
+        ));
+        assert!(matches!(
+            FlowQuery::from_args(args("--vpn OpenVPN")),
+            Err(QueryError::UnknownOption(_))
+        ));
+    }
+}
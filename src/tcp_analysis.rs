@@ -0,0 +1,429 @@
+use crate::categories::PacketDirection;
+use crate::data_structure::{Data, TcpPacket};
+use crate::flow_features::Stats;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::{HashSet, VecDeque};
+
+/// Where a TCP flow opens and closes, as indices into `Data::packets`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpLifecycle {
+    /// The first SYN without ACK.
+    pub syn: Option<usize>,
+    /// The first SYN-ACK answering it from the other side.
+    pub syn_ack: Option<usize>,
+    /// The first ACK from the SYN side after the SYN-ACK.
+    pub ack: Option<usize>,
+    /// The first FIN from each side.
+    pub fin_forward: Option<usize>,
+    pub fin_backward: Option<usize>,
+    pub reset: Option<usize>,
+}
+
+impl TcpLifecycle {
+    pub fn new(data: &Data<TcpPacket>) -> Self {
+        let packets = &data.packets;
+        let mut lifecycle = Self::default();
+        let mut syn_direction = None;
+        for (index, packet) in packets.iter().enumerate() {
+            let flags = packet.tcp_flags;
+            let direction = packet.base.direction;
+            match (lifecycle.syn, lifecycle.syn_ack, lifecycle.ack) {
+                (None, _, _) if flags.syn() && !flags.ack() => {
+                    lifecycle.syn = Some(index);
+                    syn_direction = Some(direction);
+                }
+                (Some(_), None, _)
+                    if flags.syn() && flags.ack() && Some(direction) != syn_direction =>
+                {
+                    lifecycle.syn_ack = Some(index)
+                }
+                (Some(_), Some(_), None)
+                    if flags.ack() && !flags.syn() && Some(direction) == syn_direction =>
+                {
+                    lifecycle.ack = Some(index)
+                }
+                _ => {}
+            }
+            if flags.fin() {
+                let fin = match direction {
+                    PacketDirection::Outgoing => &mut lifecycle.fin_forward,
+                    PacketDirection::Incoming => &mut lifecycle.fin_backward,
+                    PacketDirection::Unknown => &mut None,
+                };
+                fin.get_or_insert(index);
+            }
+            if flags.rst() {
+                lifecycle.reset.get_or_insert(index);
+            }
+        }
+        lifecycle
+    }
+
+    /// The three-way handshake was seen in full.
+    pub fn established(&self) -> bool {
+        self.ack.is_some()
+    }
+
+    /// Both sides sent a FIN, or either side reset the connection.
+    pub fn closed(&self) -> bool {
+        (self.fin_forward.is_some() && self.fin_backward.is_some()) || self.reset.is_some()
+    }
+
+    /// Time from the SYN to the ACK completing the handshake.
+    pub fn handshake_duration(&self, data: &Data<TcpPacket>) -> Option<TimeDelta> {
+        let start = &data.packets[self.syn?].base;
+        let end = &data.packets[self.ack?].base;
+        Some(end.timestamp_start - start.timestamp_start)
+    }
+}
+
+/// Per-flow TCP health. Retransmissions and RTT inflation are what TCP-over-TCP
+/// tunnels such as SSTP and OpenVPN over TCP suffer from.
+#[derive(Clone, Debug, Default)]
+pub struct TcpStats {
+    pub lifecycle: TcpLifecycle,
+    /// Segments that occupy sequence space: data, SYN or FIN.
+    pub segments: usize,
+    /// Segments whose start was already sent.
+    pub retransmissions: usize,
+    /// New segments that start behind data already sent, filling a gap.
+    pub out_of_order: usize,
+    /// Pure ACKs repeating the previous acknowledgment number of their side.
+    pub duplicate_acks: usize,
+    /// Round-trip times in microseconds, from a segment being sent to the
+    /// first ACK covering it. Retransmitted segments are not sampled.
+    pub rtt: Stats,
+}
+
+struct Segment {
+    end: u32,
+    sent: DateTime<Utc>,
+    retransmitted: bool,
+}
+
+/// Sequence numbers of one side, relative to its first segment so the
+/// comparisons survive wrap-around.
+#[derive(Default)]
+struct Side {
+    initial_sequence: Option<u32>,
+    highest_end: u32,
+    starts: HashSet<u32>,
+    outstanding: VecDeque<Segment>,
+    last_ack: Option<u32>,
+}
+
+impl TcpStats {
+    pub fn new(data: &Data<TcpPacket>) -> Self {
+        let mut stats = Self {
+            lifecycle: TcpLifecycle::new(data),
+            ..Self::default()
+        };
+        let mut sides = [Side::default(), Side::default()];
+        let mut rtt = vec![];
+        for packet in &data.packets {
+            let (sender, receiver) = match packet.base.direction {
+                PacketDirection::Outgoing => (0, 1),
+                PacketDirection::Incoming => (1, 0),
+                PacketDirection::Unknown => continue,
+            };
+            let flags = packet.tcp_flags;
+            // Sequence numbers count modulo 2^32, so truncating is exact.
+            let payload = packet.payload_length() as u32;
+            let length = payload
+                .wrapping_add(u32::from(flags.syn()))
+                .wrapping_add(u32::from(flags.fin()));
+
+            if flags.ack() {
+                let ack = packet.tcp_acknowledgment_number;
+                let pure = length == 0 && !flags.rst();
+                if pure && sides[sender].last_ack == Some(ack) {
+                    stats.duplicate_acks += 1;
+                }
+                sides[sender].last_ack = Some(ack);
+                if let Some(sample) = sides[receiver].acknowledge(ack, packet.base.timestamp_start)
+                {
+                    rtt.push(sample.num_microseconds().unwrap_or(i64::MAX) as f64);
+                }
+            }
+
+            if length == 0 {
+                continue;
+            }
+            stats.segments += 1;
+            let side = &mut sides[sender];
+            let initial = *side
+                .initial_sequence
+                .get_or_insert(packet.tcp_sequence_number);
+            let start = packet.tcp_sequence_number.wrapping_sub(initial);
+            let end = start.wrapping_add(length);
+            if !side.starts.insert(start) {
+                stats.retransmissions += 1;
+                side.outstanding
+                    .iter_mut()
+                    .filter(|segment| segment.end == end)
+                    .for_each(|segment| segment.retransmitted = true);
+            } else if start < side.highest_end {
+                stats.out_of_order += 1;
+            } else {
+                side.highest_end = end;
+                side.outstanding.push_back(Segment {
+                    end,
+                    sent: packet.base.timestamp_start,
+                    retransmitted: false,
+                });
+            }
+        }
+        stats.rtt = Stats::new(&rtt);
+        stats
+    }
+
+    pub fn retransmission_rate(&self) -> f64 {
+        if self.segments == 0 {
+            return 0.0;
+        }
+        self.retransmissions as f64 / self.segments as f64
+    }
+}
+
+impl Side {
+    /// Drops every segment `ack` covers and returns the round trip of the
+    /// last one, unless it was retransmitted.
+    fn acknowledge(&mut self, ack: u32, time: DateTime<Utc>) -> Option<TimeDelta> {
+        let acknowledged = ack.wrapping_sub(self.initial_sequence?);
+        let mut sample = None;
+        while let Some(segment) = self.outstanding.front() {
+            if segment.end > acknowledged {
+                break;
+            }
+            sample = (!segment.retransmitted).then(|| time - segment.sent);
+            self.outstanding.pop_front();
+        }
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::{BasePacket, TcpFlags};
+    use PacketDirection::{Incoming, Outgoing, Unknown};
+
+    const HEADERS: u16 = 20;
+
+    fn segment(
+        direction: PacketDirection,
+        flags: TcpFlags,
+        sequence: u32,
+        acknowledgment: u32,
+        payload: u64,
+        milliseconds: i64,
+    ) -> TcpPacket {
+        let timestamp = DateTime::UNIX_EPOCH + TimeDelta::milliseconds(milliseconds);
+        TcpPacket {
+            base: BasePacket {
+                bytes: u64::from(2 * HEADERS) + payload,
+                direction,
+                ip_header_length: HEADERS,
+                packets: 1,
+                timestamp_start: timestamp,
+                timestamp_end: timestamp,
+            },
+            tcp_header_len: HEADERS,
+            tcp_flags: flags,
+            tcp_acknowledgment_number: acknowledgment,
+            tcp_sequence_number: sequence,
+        }
+    }
+
+    fn flow(packets: Vec<TcpPacket>) -> Data<TcpPacket> {
+        Data {
+            ip_destination: None,
+            ip_source: None,
+            port_destination: 443,
+            port_source: 50000,
+            packets,
+        }
+    }
+
+    fn handshake(client: PacketDirection, server: PacketDirection) -> Vec<TcpPacket> {
+        vec![
+            segment(client, TcpFlags::SYN, 100, 0, 0, 0),
+            segment(server, TcpFlags::SYN | TcpFlags::ACK, 900, 101, 0, 30),
+            segment(client, TcpFlags::ACK, 101, 901, 0, 40),
+        ]
+    }
+
+    #[test]
+    fn handshake_from_either_side() {
+        for (client, server) in [(Outgoing, Incoming), (Incoming, Outgoing)] {
+            let data = flow(handshake(client, server));
+            let lifecycle = TcpLifecycle::new(&data);
+            assert_eq!(
+                (lifecycle.syn, lifecycle.syn_ack, lifecycle.ack),
+                (Some(0), Some(1), Some(2))
+            );
+            assert!(lifecycle.established());
+            assert!(!lifecycle.closed());
+            assert_eq!(
+                lifecycle.handshake_duration(&data),
+                Some(TimeDelta::milliseconds(40))
+            );
+        }
+    }
+
+    #[test]
+    fn syn_ack_from_the_syn_side_does_not_answer_it() {
+        let data = flow(vec![
+            segment(Outgoing, TcpFlags::SYN, 100, 0, 0, 0),
+            segment(Outgoing, TcpFlags::SYN | TcpFlags::ACK, 100, 0, 0, 10),
+            segment(Outgoing, TcpFlags::ACK, 101, 0, 0, 20),
+        ]);
+        let lifecycle = TcpLifecycle::new(&data);
+        assert_eq!(lifecycle.syn_ack, None);
+        assert!(!lifecycle.established());
+    }
+
+    #[test]
+    fn fin_from_both_sides_closes() {
+        let mut packets = handshake(Outgoing, Incoming);
+        packets.extend([
+            segment(Outgoing, TcpFlags::FIN | TcpFlags::ACK, 101, 901, 0, 50),
+            segment(Incoming, TcpFlags::ACK, 901, 102, 0, 60),
+            segment(Outgoing, TcpFlags::FIN | TcpFlags::ACK, 101, 901, 0, 70),
+        ]);
+        let half_closed = TcpLifecycle::new(&flow(packets.clone()));
+        assert_eq!(half_closed.fin_forward, Some(3));
+        assert_eq!(half_closed.fin_backward, None);
+        assert!(!half_closed.closed());
+
+        packets.push(segment(
+            Incoming,
+            TcpFlags::FIN | TcpFlags::ACK,
+            901,
+            102,
+            0,
+            80,
+        ));
+        let closed = TcpLifecycle::new(&flow(packets));
+        assert_eq!(closed.fin_forward, Some(3));
+        assert_eq!(closed.fin_backward, Some(6));
+        assert!(closed.closed());
+    }
+
+    #[test]
+    fn reset_closes_in_every_direction() {
+        for direction in [Outgoing, Incoming, Unknown] {
+            let mut packets = handshake(Outgoing, Incoming);
+            packets.push(segment(
+                direction,
+                TcpFlags::RST | TcpFlags::FIN,
+                101,
+                0,
+                0,
+                50,
+            ));
+            let lifecycle = TcpLifecycle::new(&flow(packets));
+            assert_eq!(lifecycle.reset, Some(3), "{direction:?}");
+            assert!(lifecycle.closed(), "{direction:?}");
+        }
+    }
+
+    #[test]
+    fn fin_without_direction_is_not_attributed() {
+        let mut packets = handshake(Outgoing, Incoming);
+        packets.push(segment(Unknown, TcpFlags::FIN, 101, 0, 0, 50));
+        let lifecycle = TcpLifecycle::new(&flow(packets));
+        assert_eq!(
+            (lifecycle.fin_forward, lifecycle.fin_backward),
+            (None, None)
+        );
+        assert!(!lifecycle.closed());
+    }
+
+    /// Handshake, then `data` from the client acknowledged by the server.
+    fn transfer(initial: u32, data: &[(u32, u64, i64)], acks: &[(u32, i64)]) -> TcpStats {
+        let mut packets = vec![
+            segment(Outgoing, TcpFlags::SYN, initial, 0, 0, 0),
+            segment(
+                Incoming,
+                TcpFlags::SYN | TcpFlags::ACK,
+                900,
+                initial.wrapping_add(1),
+                0,
+                10,
+            ),
+        ];
+        let mut events = data
+            .iter()
+            .map(|&(offset, payload, time)| {
+                let sequence = initial.wrapping_add(1).wrapping_add(offset);
+                (
+                    time,
+                    segment(Outgoing, TcpFlags::ACK, sequence, 901, payload, time),
+                )
+            })
+            .chain(acks.iter().map(|&(offset, time)| {
+                let ack = initial.wrapping_add(1).wrapping_add(offset);
+                (time, segment(Incoming, TcpFlags::ACK, 901, ack, 0, time))
+            }))
+            .collect::<Vec<_>>();
+        events.sort_by_key(|(time, _)| *time);
+        packets.extend(events.into_iter().map(|(_, packet)| packet));
+        TcpStats::new(&flow(packets))
+    }
+
+    #[test]
+    fn clean_transfer_samples_rtt() {
+        let stats = transfer(1000, &[(0, 100, 20), (100, 100, 25)], &[(200, 45)]);
+        assert!(stats.lifecycle.established());
+        // SYN, SYN-ACK and two data segments.
+        assert_eq!(stats.segments, 4);
+        assert_eq!(stats.retransmissions, 0);
+        assert_eq!(stats.out_of_order, 0);
+        assert_eq!(stats.duplicate_acks, 0);
+        // SYN → SYN-ACK, SYN-ACK → first data ACK, and the cumulative ACK
+        // sampling the last segment it covers.
+        assert_eq!(stats.rtt.count, 3);
+        assert_eq!(stats.rtt.min, 10_000.0);
+        assert_eq!(stats.rtt.max, 20_000.0);
+    }
+
+    #[test]
+    fn retransmission_is_counted_and_not_sampled() {
+        let stats = transfer(1000, &[(0, 100, 20), (0, 100, 220)], &[(100, 230)]);
+        assert_eq!(stats.retransmissions, 1);
+        assert_eq!(stats.segments, 4);
+        assert_eq!(stats.retransmission_rate(), 0.25);
+        // Only the handshake is sampled; the retransmitted segment is ambiguous.
+        assert_eq!(stats.rtt.count, 2);
+    }
+
+    #[test]
+    fn segment_filling_a_gap_is_out_of_order() {
+        let stats = transfer(1000, &[(0, 100, 20), (200, 100, 21), (100, 100, 22)], &[]);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.retransmissions, 0);
+    }
+
+    #[test]
+    fn repeated_pure_ack_is_a_duplicate() {
+        let stats = transfer(
+            1000,
+            &[(0, 100, 20), (200, 100, 21), (300, 100, 22)],
+            &[(100, 30), (100, 31), (100, 32)],
+        );
+        assert_eq!(stats.duplicate_acks, 2);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        // The SYN takes the last sequence number before the wrap, the data
+        // starts at 0 and runs on past it.
+        let initial = u32::MAX;
+        let stats = transfer(initial, &[(0, 100, 20), (100, 100, 25)], &[(200, 45)]);
+        assert_eq!(stats.retransmissions, 0);
+        assert_eq!(stats.out_of_order, 0);
+        assert_eq!(stats.rtt.count, 3);
+        assert_eq!(stats.rtt.max, 20_000.0);
+    }
+}
@@ -0,0 +1,24 @@
+use crate::dataset_layout::DatasetLayout;
+use std::path::PathBuf;
+
+/// A file under `tests/fixtures`.
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures")).join(name)
+}
+
+/// An empty directory of its own for one test, so tests running in parallel
+/// never share files.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir()
+        .join(env!("CARGO_PKG_NAME"))
+        .join(format!("{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// The standard tree in `tests/fixtures/dataset`, caching into a scratch
+/// directory named after the test instead of the fixtures.
+pub fn fixture_layout(name: &str) -> DatasetLayout {
+    DatasetLayout::scan(fixture("dataset")).with_cache_dir(Some(scratch_dir(name)))
+}
@@ -1,28 +1,37 @@
-use crate::burn_dataset::{NetworkDataset, NetworkTrafficBatcher};
-use crate::categories::{DataCategory, Encryption, VPN};
-use crate::data_structure::{get_all_data, get_some_data};
+use crate::burn_dataset::{
+    BatchError, FlowSequenceBatch, NetworkDataset, NetworkTrafficBatcher, StratifiedSplitter,
+};
+use crate::data_structure::{get_some_data, stream_some_data, FlowStream};
+use crate::dataset_layout::DatasetLayout;
+use crate::features::{FeatureError, FeatureManifest, FeatureSet, FlowStatistics};
+use crate::labels::{Label, LabelError, LabelRegistry};
 use crate::model::Model;
-use burn::data::dataset::transform::{PartialDataset, ShuffledDataset};
+use crate::normalization::{Normalization, NormalizationError, Scaling};
+use crate::query::FlowQuery;
 use burn::train::metric::AccuracyMetric;
 use burn::{
-    data::{dataloader::DataLoaderBuilder, dataset::Dataset},
+    config::ConfigError,
+    data::dataloader::DataLoaderBuilder,
+    module::AutodiffModule,
     optim::SgdConfig,
     prelude::*,
-    record::{CompactRecorder, NoStdTrainingRecorder},
+    record::{CompactRecorder, RecorderError},
     tensor::backend::AutodiffBackend,
-    train::{
-        metric::store::{Aggregate, Direction, Split},
-        metric::LossMetric,
-        LearnerBuilder, MetricEarlyStoppingStrategy, StoppingCondition,
-    },
+    train::{metric::LossMetric, LearnerBuilder},
 };
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 use rayon::prelude::*;
-use std::sync::Mutex;
-use strum::IntoEnumIterator;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 static ARTIFACT_DIR: &str = "network-analysis-model";
+/// Where [`train`] keeps its [`ExpConfig`] and the model record, within the
+/// artifact directory.
+const CONFIG_FILE: &str = "config.json";
+const MODEL_FILE: &str = "model";
+const BATCH_SIZE: usize = 64;
 
 #[derive(Config)]
 pub struct ExpConfig {
@@ -37,48 +46,340 @@ pub struct ExpConfig {
 
     pub optimizer: SgdConfig,
 
-    #[config(default = 1225)]
-    pub input_feature_len: usize,
+    /// Packets per flow the model sees. With the 73 columns of
+    /// [`model_features`] and the mask this is 12950 inputs.
+    #[config(default = 175)]
+    pub sequence_length: usize,
+
+    /// Width of both hidden layers of the [`Model`].
+    #[config(default = 128)]
+    pub hidden_size: usize,
 
     #[config(default = 1.0e-4)]
     pub learning_rate: f64,
 
     #[config(default = 0.9)]
     pub train_ratio: f32,
+
+    /// The test split gets what is left after training and validation.
+    #[config(default = 0.05)]
+    pub valid_ratio: f32,
+
+    /// Trains on the applications of this tunnel only, named as in the label
+    /// registry. Every labelled file of the layout is used when unset.
+    pub tunnel: Option<String>,
+}
+
+/// The features the model sees: the [`PacketFeatures`] of every packet next
+/// to the [`FlowStatistics`] of its flow.
+///
+/// [`PacketFeatures`]: crate::features::PacketFeatures
+pub fn model_features() -> FeatureSet {
+    FeatureSet::default().with(FlowStatistics::default())
+}
+
+/// The byte counts and lengths of [`FlowStatistics`] span many orders of
+/// magnitude and are log-scaled. The `log_*` packet columns are logged by
+/// their extractor already, so they and every other column are scaled
+/// linearly.
+fn scaling(name: &str) -> Scaling {
+    if ["Bytes", "Length", "Size"]
+        .iter()
+        .any(|unit| name.contains(unit))
+    {
+        Scaling::Log
+    } else {
+        Scaling::MinMax
+    }
 }
 
-pub fn train<B: AutodiffBackend>(device: B::Device) {
+fn new_model<B: Backend>(
+    device: &B::Device,
+    config: &ExpConfig,
+    features: &FeatureSet,
+    labels: &LabelRegistry,
+) -> Model<B> {
+    Model::new(
+        device,
+        Model::<B>::sequence_input_size(config.sequence_length, features.width()),
+        config.hidden_size,
+        labels.application_count(),
+    )
+}
+
+/// The labels of `layout` that `query` selects, from the applications of
+/// [`ExpConfig::tunnel`] when it is set.
+fn config_labels(layout: &DatasetLayout, config: &ExpConfig, query: &FlowQuery) -> Vec<Label> {
+    let labels = match &config.tunnel {
+        Some(tunnel) => layout.labels().tunnel_labels(tunnel).unwrap(),
+        None => layout.present().map(|(label, _)| label.clone()).collect(),
+    };
+    labels
+        .into_iter()
+        .filter(|label| query.matches_label(label))
+        .collect()
+}
+
+/// The flows of `layout` that `query` selects, see [`config_labels`].
+fn load_dataset(layout: &DatasetLayout, config: &ExpConfig, query: &FlowQuery) -> NetworkDataset {
+    let data = Mutex::new(vec![]);
+    config_labels(layout, config, query)
+        .par_iter()
+        .for_each(|label| match get_some_data(layout, label) {
+            Ok(Some(metadata)) => data.lock().unwrap().push(metadata),
+            Ok(None) => log::warn!("no {label} data"),
+            Err(err) => log::error!("{err}"),
+        });
+    NetworkDataset::with_query(data.into_inner().unwrap(), query)
+}
+
+#[derive(Debug)]
+pub enum ModelError {
+    Config(ConfigError),
+    /// The model was trained on other features than [`model_features`].
+    Features(FeatureError),
+    Record(RecorderError),
+    Batch(BatchError),
+    /// The normalization statistics are missing or do not fit the features.
+    Normalization(NormalizationError),
+    Label(LabelError),
+}
+
+impl Display for ModelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelError::Config(err) => write!(f, "invalid training config: {err}"),
+            ModelError::Features(err) => write!(f, "{err}"),
+            ModelError::Record(err) => write!(f, "could not load the model record: {err}"),
+            ModelError::Batch(err) => write!(f, "{err}"),
+            ModelError::Normalization(err) => write!(f, "{err}"),
+            ModelError::Label(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl From<FeatureError> for ModelError {
+    fn from(err: FeatureError) -> Self {
+        ModelError::Features(err)
+    }
+}
+
+impl From<BatchError> for ModelError {
+    fn from(err: BatchError) -> Self {
+        ModelError::Batch(err)
+    }
+}
+
+impl From<NormalizationError> for ModelError {
+    fn from(err: NormalizationError) -> Self {
+        ModelError::Normalization(err)
+    }
+}
+
+impl From<LabelError> for ModelError {
+    fn from(err: LabelError) -> Self {
+        ModelError::Label(err)
+    }
+}
+
+/// A model [`train`] saved, with a batcher that prepares flows the way they
+/// were prepared for training.
+pub struct TrainedModel<B: Backend> {
+    pub config: ExpConfig,
+    pub model: Model<B>,
+    pub batcher: NetworkTrafficBatcher<B>,
+}
+
+impl<B: Backend> TrainedModel<B> {
+    /// Fails unless the model in `directory` was trained on the features
+    /// [`model_features`] extracts now, and its normalization statistics
+    /// were fitted on them.
+    pub fn load(
+        device: B::Device,
+        directory: impl AsRef<Path>,
+        labels: Arc<LabelRegistry>,
+    ) -> Result<Self, ModelError> {
+        let directory = directory.as_ref();
+        let config = ExpConfig::load(directory.join(CONFIG_FILE)).map_err(ModelError::Config)?;
+        let features = model_features();
+        features.check(&FeatureManifest::load(directory)?)?;
+        let normalization = Normalization::load(directory)?;
+        normalization.check(&features)?;
+        let model = new_model(&device, &config, &features, &labels)
+            .load_file(directory.join(MODEL_FILE), &CompactRecorder::new(), &device)
+            .map_err(ModelError::Record)?;
+        let batcher = NetworkTrafficBatcher::new(device)
+            .with_labels(labels)
+            .with_features(features)
+            .with_normalization(Arc::new(normalization))
+            .with_sequence_length(config.sequence_length);
+        Ok(Self {
+            config,
+            model,
+            batcher,
+        })
+    }
+
+    pub fn evaluate(&self, dataset: NetworkDataset) -> Result<Evaluation, ModelError> {
+        self.batcher.check_labels(&dataset)?;
+        Ok(evaluate(&self.model, self.batcher.clone(), dataset))
+    }
+
+    /// Like [`TrainedModel::evaluate`], but reads the flows `query` selects
+    /// batch by batch instead of loading them first.
+    pub fn evaluate_stream(
+        &self,
+        stream: FlowStream,
+        query: &FlowQuery,
+    ) -> Result<Evaluation, ModelError> {
+        let mut evaluation = Evaluation::default();
+        for batch in self.batcher.batch_stream(stream, query, BATCH_SIZE)? {
+            evaluation.add(&self.model, batch?);
+        }
+        Ok(evaluation)
+    }
+}
+
+/// Restores the model [`train`] saved and evaluates it on the flows of
+/// `layout` that `query` selects, streaming one file at a time. Flows it was
+/// trained on count as well, so `query` should leave them out for an honest
+/// estimate.
+pub fn evaluate_saved<B: Backend>(
+    device: B::Device,
+    layout: &DatasetLayout,
+    query: &FlowQuery,
+) -> Result<Evaluation, ModelError> {
+    let trained = TrainedModel::<B>::load(device, ARTIFACT_DIR, layout.labels().clone())?;
+    let mut evaluation = Evaluation::default();
+    for label in config_labels(layout, &trained.config, query) {
+        if let Some(stream) = stream_some_data(layout, &label) {
+            let label_evaluation = trained.evaluate_stream(stream, query)?;
+            log::info!("{label}: {label_evaluation}");
+            evaluation.flows += label_evaluation.flows;
+            evaluation.correct += label_evaluation.correct;
+        }
+    }
+    Ok(evaluation)
+}
+
+/// How many flows of a dataset a model classified correctly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Evaluation {
+    pub flows: usize,
+    pub correct: usize,
+}
+
+impl Evaluation {
+    /// Counts the flows of `batch` and how many of them `model` gets right.
+    fn add<B: Backend>(&mut self, model: &Model<B>, batch: FlowSequenceBatch<B>) {
+        let predictions = model
+            .forward_sequence(batch.inputs, batch.mask)
+            .argmax(1)
+            .squeeze::<1>(1);
+        self.flows += batch.targets.dims()[0];
+        self.correct += predictions
+            .equal(batch.targets)
+            .int()
+            .sum()
+            .into_scalar()
+            .elem::<i64>() as usize;
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        if self.flows == 0 {
+            0.0
+        } else {
+            self.correct as f64 / self.flows as f64
+        }
+    }
+}
+
+impl Display for Evaluation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} flows correct ({:.2}%)",
+            self.correct,
+            self.flows,
+            self.accuracy() * 100.0
+        )
+    }
+}
+
+/// Classifies every flow of `dataset` once by its application.
+pub fn evaluate<B: Backend>(
+    model: &Model<B>,
+    batcher: NetworkTrafficBatcher<B>,
+    dataset: NetworkDataset,
+) -> Evaluation {
+    let dataloader = DataLoaderBuilder::<_, FlowSequenceBatch<B>>::new(batcher)
+        .batch_size(BATCH_SIZE)
+        .build(dataset);
+    let mut evaluation = Evaluation::default();
+    for batch in dataloader.iter() {
+        evaluation.add(model, batch);
+    }
+    evaluation
+}
+
+/// Trains on the flows of `layout` that `query` selects.
+pub fn train<B: AutodiffBackend>(device: B::Device, layout: &DatasetLayout, query: &FlowQuery) {
     let optimizer = SgdConfig::new();
     let config = ExpConfig::new(optimizer);
-    let model = Model::new(&device, 1225, 1, 5);
 
+    let data = load_dataset(layout, &config, query);
     // Set the random seed
-    let data = Mutex::new(vec![]);
-    DataCategory::iter().par_bridge().for_each(|category| {
-        let metadata = get_some_data(Encryption::VPN(VPN::L2TP), category);
-        data.lock().unwrap().push(metadata);
-    });
     let mut rng = StdRng::seed_from_u64(config.seed);
-
-    let data = NetworkDataset(data.into_inner().unwrap().into());
-    let (train, learn) = data.split(config.train_ratio, &mut rng);
+    let splits =
+        StratifiedSplitter::new(config.train_ratio, config.valid_ratio).split(&data, &mut rng);
+    match serde_json::to_string_pretty(&splits.report) {
+        Ok(report) => log::info!("flows per split: {report}"),
+        Err(err) => log::error!("{err}"),
+    }
     // Initialize model, optimizer, and data loaders
     let optimizer = config.optimizer.init();
 
-    let batcher_train = NetworkTrafficBatcher::<B>::new(device.clone());
-    let batcher_valid = NetworkTrafficBatcher::<B::InnerBackend>::new(device.clone());
+    // Checkpoints are only usable with the features and config they were
+    // trained with, see TrainedModel::load
+    let features = model_features();
+    std::fs::create_dir_all(ARTIFACT_DIR).unwrap();
+    features.manifest().save(ARTIFACT_DIR).unwrap();
+    config
+        .save(Path::new(ARTIFACT_DIR).join(CONFIG_FILE))
+        .unwrap();
+    // Fitted on the training split only, so validation sees the same scale
+    // without leaking anything into it
+    let normalization = Arc::new(Normalization::fit(&features, &splits.train, scaling).unwrap());
+    normalization.save(ARTIFACT_DIR).unwrap();
+    let model = new_model(&device, &config, &features, layout.labels());
 
-    let dataloader_train = DataLoaderBuilder::new(batcher_train)
-        .batch_size(64)
-        .shuffle(config.seed)
-        .num_workers(config.num_workers)
-        .build(train);
+    let batcher_train = NetworkTrafficBatcher::<B>::new(device.clone())
+        .with_labels(layout.labels().clone())
+        .with_features(features.clone())
+        .with_normalization(normalization.clone())
+        .with_sequence_length(config.sequence_length);
+    let batcher_valid = NetworkTrafficBatcher::<B::InnerBackend>::new(device.clone())
+        .with_labels(layout.labels().clone())
+        .with_features(features)
+        .with_normalization(normalization)
+        .with_sequence_length(config.sequence_length);
+    // Batching cannot fail, so flows the model has no class for are caught here
+    batcher_train.check_labels(&data).unwrap();
 
-    let dataloader_valid = DataLoaderBuilder::new(batcher_valid)
-        .batch_size(64)
+    let dataloader_train = DataLoaderBuilder::<_, FlowSequenceBatch<B>>::new(batcher_train)
+        .batch_size(BATCH_SIZE)
         .shuffle(config.seed)
         .num_workers(config.num_workers)
-        .build(learn);
+        .build(splits.train);
+
+    let dataloader_valid =
+        DataLoaderBuilder::<_, FlowSequenceBatch<B::InnerBackend>>::new(batcher_valid.clone())
+            .batch_size(BATCH_SIZE)
+            .shuffle(config.seed)
+            .num_workers(config.num_workers)
+            .build(splits.valid);
 
     // Set up the learner
     let learner = LearnerBuilder::new("network-analysis-model")
@@ -94,11 +395,123 @@ pub fn train<B: AutodiffBackend>(device: B::Device) {
     // Run the training
     let trained_model = learner.fit(dataloader_train, dataloader_valid);
 
+    // The test split took no part in training or early stopping, so this is
+    // the only unbiased estimate
+    let evaluation = evaluate(&trained_model.valid(), batcher_valid, splits.test);
+    log::info!("test split: {evaluation}");
+
     // Save the trained model
     trained_model
         .save_file(
-            "network-analysis-model/model".to_string(),
+            Path::new(ARTIFACT_DIR).join(MODEL_FILE),
             &CompactRecorder::new(),
         )
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structure::get_all_data;
+    use crate::test_support::{fixture_layout, scratch_dir};
+    use burn::backend::NdArray;
+
+    #[test]
+    fn evaluates_every_flow_once() {
+        let layout = fixture_layout("evaluate");
+        let dataset = NetworkDataset::new(get_all_data(&layout));
+        let features = FeatureSet::default();
+        let model = Model::<NdArray>::new(
+            &Default::default(),
+            Model::<NdArray>::sequence_input_size(4, features.width()),
+            8,
+            layout.labels().application_count(),
+        );
+        let normalization = Normalization::fit(&features, &dataset, scaling).unwrap();
+        let batcher = NetworkTrafficBatcher::new(Default::default())
+            .with_labels(layout.labels().clone())
+            .with_features(features)
+            .with_normalization(Arc::new(normalization))
+            .with_sequence_length(4);
+        let evaluation = evaluate(&model, batcher, dataset);
+        assert_eq!(evaluation.flows, 3);
+        assert!(evaluation.correct <= 3);
+        assert_eq!(
+            Evaluation {
+                flows: 4,
+                correct: 1
+            }
+            .to_string(),
+            "1 of 4 flows correct (25.00%)"
+        );
+    }
+
+    #[test]
+    fn new_models_depend_on_their_inputs() {
+        let device = Default::default();
+        let features = model_features();
+        let config = ExpConfig::new(SgdConfig::new()).with_sequence_length(2);
+        let model = new_model::<NdArray>(&device, &config, &features, &LabelRegistry::default());
+        let classify = |value: f32| {
+            let inputs = Tensor::<NdArray, 3>::full([1, 2, features.width()], value, &device);
+            let mask = Tensor::<NdArray, 2, Bool>::from_data(
+                TensorData::new(vec![true, false], [1, 2]),
+                &device,
+            );
+            model
+                .forward_sequence(inputs, mask)
+                .into_data()
+                .to_vec::<f32>()
+                .unwrap()
+        };
+        assert_ne!(classify(0.0), classify(1.0));
+        assert_ne!(classify(0.2), classify(0.8));
+    }
+
+    #[test]
+    fn restores_models_trained_on_the_same_features() {
+        let directory = scratch_dir("trained-model");
+        let device = Default::default();
+        let labels = Arc::new(LabelRegistry::default());
+        let config = ExpConfig::new(SgdConfig::new()).with_sequence_length(4);
+        config.save(directory.join(CONFIG_FILE)).unwrap();
+        let dataset = NetworkDataset::new(get_all_data(&fixture_layout("trained-model-data")));
+        model_features().manifest().save(&directory).unwrap();
+        Normalization::fit(&model_features(), &dataset, scaling)
+            .unwrap()
+            .save(&directory)
+            .unwrap();
+        new_model::<NdArray>(&device, &config, &model_features(), &labels)
+            .save_file(directory.join(MODEL_FILE), &CompactRecorder::new())
+            .unwrap();
+
+        let trained = TrainedModel::<NdArray>::load(device, &directory, labels.clone()).unwrap();
+        assert_eq!(trained.config.sequence_length, 4);
+        let evaluation = trained.evaluate(dataset.clone()).unwrap();
+        assert_eq!(evaluation.flows, 3);
+        let layout = fixture_layout("trained-model-stream");
+        let ssh = layout.labels().find("OpenVPN", "SSH").unwrap();
+        let stream = stream_some_data(&layout, &ssh).unwrap();
+        let streamed = trained.evaluate_stream(stream, &FlowQuery::new()).unwrap();
+        assert_eq!(streamed.flows, 2);
+
+        // Statistics fitted on other columns than the model's
+        Normalization::fit(&FeatureSet::default(), &dataset, scaling)
+            .unwrap()
+            .save(&directory)
+            .unwrap();
+        assert!(matches!(
+            TrainedModel::<NdArray>::load(device, &directory, labels.clone()),
+            Err(ModelError::Normalization(
+                NormalizationError::Mismatch { .. }
+            ))
+        ));
+
+        // A checkpoint from before the flow statistics were added
+        FeatureSet::default().manifest().save(&directory).unwrap();
+        assert!(matches!(
+            TrainedModel::<NdArray>::load(device, &directory, labels),
+            Err(ModelError::Features(FeatureError::Mismatch { .. }))
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::dataset_layout::DatasetLayout;
+use crate::parse_data::{FlowIssue, FlowReader, ParseMode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Serialize)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub flows: usize,
+    pub packets: usize,
+    /// Packets without `ip_header_len`. The parser leaves them out, which is
+    /// expected in the dataset and not an issue.
+    pub dropped_packets: usize,
+    /// Flows with at least one issue.
+    pub invalid_flows: usize,
+    pub issues: BTreeMap<FlowIssue, usize>,
+    /// Set when the file could not be read to the end, for example on a JSON
+    /// syntax error. The counts cover the flows before it.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CombinationReport {
+    pub group: String,
+    pub encryption: String,
+    pub category: String,
+    pub flows: usize,
+    pub packets: usize,
+    pub dropped_packets: usize,
+    pub invalid_flows: usize,
+    pub unreadable_files: usize,
+    pub issues: BTreeMap<FlowIssue, usize>,
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DatasetReport {
+    pub root: PathBuf,
+    pub combinations: Vec<CombinationReport>,
+    /// Labels of the registry without any file.
+    pub missing: Vec<String>,
+}
+
+impl DatasetReport {
+    /// True when every file was read to the end and no flow had an issue.
+    pub fn is_clean(&self) -> bool {
+        self.combinations
+            .iter()
+            .all(|combination| combination.invalid_flows == 0 && combination.unreadable_files == 0)
+    }
+}
+
+/// Reads every flow of `path` as raw JSON and collects its
+/// [`RawData::issues`](crate::parse_data::RawData::issues).
+pub fn validate_file(path: &Path) -> FileReport {
+    let mut report = FileReport {
+        path: path.to_path_buf(),
+        ..FileReport::default()
+    };
+    let mut reader = match FlowReader::open(path, ParseMode::Strict) {
+        Ok(reader) => reader,
+        Err(err) => {
+            report.error = Some(err.to_string());
+            return report;
+        }
+    };
+    while let Some(raw) = reader.next_raw() {
+        let raw = match raw {
+            Ok(raw) => raw,
+            Err(err) => {
+                report.error = Some(err.to_string());
+                break;
+            }
+        };
+        let issues = raw.issues();
+        report.flows += 1;
+        report.packets += raw.packet_count();
+        report.dropped_packets += raw.dropped_packets();
+        report.invalid_flows += usize::from(!issues.is_empty());
+        for issue in issues {
+            *report.issues.entry(issue).or_default() += 1;
+        }
+    }
+    report
+}
+
+/// Validates every file of `layout`, with counts per label.
+pub fn validate_dataset(layout: &DatasetLayout) -> DatasetReport {
+    let mut combinations: Vec<CombinationReport> = layout
+        .present()
+        .map(|(label, files)| {
+            let mut combination = CombinationReport {
+                group: label.group.to_string(),
+                encryption: label.tunnel.to_string(),
+                category: label.application.to_string(),
+                ..CombinationReport::default()
+            };
+            for file in files.iter().map(|path| validate_file(path)) {
+                combination.flows += file.flows;
+                combination.packets += file.packets;
+                combination.dropped_packets += file.dropped_packets;
+                combination.invalid_flows += file.invalid_flows;
+                combination.unreadable_files += usize::from(file.error.is_some());
+                for (issue, count) in &file.issues {
+                    *combination.issues.entry(*issue).or_default() += count;
+                }
+                combination.files.push(file);
+            }
+            combination
+        })
+        .collect();
+    combinations.sort_by(|a, b| (&a.encryption, &a.category).cmp(&(&b.encryption, &b.category)));
+    DatasetReport {
+        root: layout.root().to_path_buf(),
+        combinations,
+        missing: layout
+            .missing()
+            .into_iter()
+            .map(|label| label.to_string())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn dropped_packets_are_counted_apart_from_issues() {
+        let path = scratch_dir("validate-dropped").join("flows.json");
+        std::fs::write(
+            &path,
+            r#"[{"ip_proto": "tcp", "port_src": 1, "port_dst": 2, "x_packets": [
+                {"bytes": "0", "packets": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"}
+            ]}, {"ip_proto": "udp", "port_src": 1, "port_dst": 2, "x_packets": [
+                {"bytes": "60", "direction": "left", "ip_header_len": "20", "packets": "1", "timestamp_start": "2023-03-01 10:00:00", "timestamp_end": "2023-03-01 10:00:00"}
+            ]}]"#,
+        )
+        .unwrap();
+        let report = validate_file(&path);
+        assert_eq!(report.error, None);
+        assert_eq!(report.flows, 2);
+        assert_eq!(report.packets, 2);
+        assert_eq!(report.dropped_packets, 1);
+        // The first flow has nothing left once its packet is dropped
+        assert_eq!(report.invalid_flows, 2);
+        assert_eq!(
+            report.issues,
+            BTreeMap::from([(FlowIssue::EmptyFlow, 1), (FlowIssue::InvalidDirection, 1)])
+        );
+    }
+}
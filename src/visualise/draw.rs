@@ -1,11 +1,8 @@
-use crate::categories::DataCategory;
+use crate::labels::LabelRegistry;
 use crate::visualise::DataHash;
 use itertools::Itertools;
-use plotters::prelude::*;
-use rayon::prelude::*;
 use std::collections::HashMap;
-use std::sync::RwLock;
-use strum::*;
+use std::sync::Arc;
 /*pub fn plot(data: DataHash<HashMap<u32, usize>>) -> Result<(), Box<dyn std::error::Error>> {
     let max_x = RwLock::new(0u32);
     let max_y = RwLock::new(0usize);
@@ -117,22 +114,31 @@ use eframe::egui;
 use egui::Color32;
 use egui_plot::{Bar, BarChart, Plot};
 
-pub fn run_chart(data: DataHash<HashMap<u32, usize>>) -> eframe::Result {
+/// Legends list the applications of `labels`, each in its own color.
+pub fn run_chart(
+    data: DataHash<HashMap<u64, usize>>,
+    labels: Arc<LabelRegistry>,
+) -> eframe::Result {
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(
         "Stacked Bar Chart Example",
         native_options,
-        Box::new(|cc| Ok(Box::new(MyApp::new(cc, data)))),
+        Box::new(|cc| Ok(Box::new(MyApp::new(cc, data, labels)))),
     )
 }
 
 struct MyApp {
-    data: DataHash<HashMap<u32, usize>>,
+    data: DataHash<HashMap<u64, usize>>,
+    labels: Arc<LabelRegistry>,
 }
 
 impl MyApp {
-    fn new(_cc: &eframe::CreationContext<'_>, data: DataHash<HashMap<u32, usize>>) -> Self {
-        Self { data }
+    fn new(
+        _cc: &eframe::CreationContext<'_>,
+        data: DataHash<HashMap<u64, usize>>,
+        labels: Arc<LabelRegistry>,
+    ) -> Self {
+        Self { data, labels }
     }
 }
 
@@ -152,11 +158,14 @@ impl eframe::App for MyApp {
                         Color32::ORANGE,
                         Color32::LIGHT_GRAY,
                     ];
-                    let mut color_map: HashMap<DataCategory, Color32> = HashMap::new();
-                    DataCategory::iter().enumerate().for_each(|(i, category)| {
-                        color_map.insert(category, colors[i]);
-                    });
-                    self.data.iter().for_each(|(encryption, map)| {
+                    let mut color_map: HashMap<&str, Color32> = HashMap::new();
+                    self.labels
+                        .application_names()
+                        .enumerate()
+                        .for_each(|(i, category)| {
+                            color_map.insert(category, colors[i % colors.len()]);
+                        });
+                    self.data.iter().for_each(|(_, map)| {
                         map.iter().for_each(|(category, map)| {
                             let category_bar_charts = map
                                 .iter()
@@ -164,7 +173,7 @@ impl eframe::App for MyApp {
                                 .collect_vec();
                             let chart = BarChart::new(category_bar_charts)
                                 .name(category)
-                                .color(*color_map.get(category).unwrap());
+                                .color(*color_map.get(&**category).unwrap_or(&Color32::GRAY));
                             plot_ui.bar_chart(chart);
                         })
                     });
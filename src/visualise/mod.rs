@@ -1,37 +1,44 @@
-use crate::categories::{DataCategory, Encryption, IpProtocol};
-use crate::data_structure::{get_all_data, get_some_data, BasePacket, MetadataWrapper};
-use itertools::Itertools;
+use crate::categories::IpProtocol;
+use crate::data_structure::{
+    get_all_data, get_some_data, stream_some_data, BasePacket, FlowStream, MetadataWrapper,
+};
+use crate::dataset_layout::DatasetLayout;
+use crate::labels::Label;
+use crate::parse_data::ParseError;
+use crate::query::FlowQuery;
+use crate::tcp_analysis::{TcpLifecycle, TcpStats};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::{Arc, Mutex};
-use strum::IntoEnumIterator;
 
 mod draw;
 
 
 pub use draw::run_chart;
-pub type DataHash<T> = HashMap<Encryption, HashMap<DataCategory, T>>;
+/// Keyed by tunnel, then by application, see [`Label`](crate::labels::Label).
+pub type DataHash<T> = HashMap<Arc<str>, HashMap<Arc<str>, T>>;
 
 #[derive(Debug, Default)]
 pub struct MinAndMax {
-    min: u32,
-    max: u32,
+    min: u64,
+    max: u64,
 }
 
-pub fn collect_data() -> DataHash<HashMap<u32, usize>> {
-    let data = get_all_data();
+pub fn collect_data(layout: &DatasetLayout, query: &FlowQuery) -> DataHash<HashMap<u64, usize>> {
+    let mut data = get_all_data(layout);
+    query.retain(&mut data);
     collect_data_hash(data)
 }
+type Histogram = HashMap<u64, usize>;
+
 #[inline(always)]
-fn collect_data_hash(data: Vec<MetadataWrapper>) -> DataHash<HashMap<u32, usize>> {
-    dbg!("data loaded");
-    let mut hash: HashMap<Encryption, Arc<Mutex<HashMap<DataCategory, HashMap<u32, usize>>>>> =
-        HashMap::new();
+fn collect_data_hash(data: Vec<MetadataWrapper>) -> DataHash<HashMap<u64, usize>> {
+    let mut hash: HashMap<_, Arc<Mutex<HashMap<_, Histogram>>>> = HashMap::new();
     data.into_iter().for_each(|data| {
-        let encryption = data.encryption;
-        let category = data.data_category;
-        let mut map = hash.entry(encryption).or_default().lock().unwrap();
+        let tunnel = data.label.tunnel;
+        let category = data.label.application;
+        let mut map = hash.entry(tunnel).or_default().lock().unwrap();
         let maybe_vec = map.get_mut(&category);
         let amount_per_byte_size = Mutex::new(HashMap::new());
 
@@ -68,21 +75,218 @@ fn collect_data_hash(data: Vec<MetadataWrapper>) -> DataHash<HashMap<u32, usize>
         .collect()
 }
 
-pub fn collect_data_specific_encryption(encryption: Encryption) -> DataHash<HashMap<u32, usize>> {
-    let data = DataCategory::iter()
-        .map(|category| get_some_data(encryption.clone(), category))
-        .collect_vec();
-    collect_data_hash(data)
+/// Histogram of `labels`, usually every application of one tunnel from
+/// [`LabelRegistry::tunnel_labels`](crate::labels::LabelRegistry::tunnel_labels).
+pub fn collect_data_specific_encryption(
+    layout: &DatasetLayout,
+    labels: &[Label],
+) -> Result<DataHash<HashMap<u64, usize>>, ParseError> {
+    let data = labels
+        .iter()
+        .filter_map(|label| get_some_data(layout, label).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(collect_data_hash(data))
+}
+
+/// Same histogram as [`collect_data_hash`], but folded flow by flow so no
+/// file is ever held in memory as a whole.
+pub fn collect_streamed_data_hash(
+    streams: impl IntoIterator<Item = FlowStream>,
+    query: &FlowQuery,
+) -> Result<DataHash<HashMap<u64, usize>>, ParseError> {
+    let mut hash: DataHash<HashMap<u64, usize>> = HashMap::new();
+    for stream in streams {
+        if !query.matches_label(&stream.label) {
+            continue;
+        }
+        let amount_per_byte_size = hash
+            .entry(stream.label.tunnel.clone())
+            .or_default()
+            .entry(stream.label.application.clone())
+            .or_default();
+        for flow in stream {
+            let flow = flow?;
+            if !query.matches(&flow) {
+                continue;
+            }
+            let packet_data: Vec<&BasePacket> = (&flow).into();
+            for packet in packet_data {
+                *amount_per_byte_size.entry(packet.bytes).or_default() += 1;
+            }
+        }
+    }
+    Ok(hash)
+}
+
+pub fn collect_streamed_data_specific_encryption(
+    layout: &DatasetLayout,
+    labels: &[Label],
+) -> Result<DataHash<HashMap<u64, usize>>, ParseError> {
+    collect_streamed_data_hash(
+        labels
+            .iter()
+            .filter_map(|label| stream_some_data(layout, label)),
+        &FlowQuery::default(),
+    )
+}
+
+/// Histogram of TCP payload lengths, the application data counterpart of
+/// [`collect_data_hash`].
+pub fn collect_tcp_payload_hash(
+    data: &[MetadataWrapper],
+    query: &FlowQuery,
+) -> DataHash<HashMap<u64, usize>> {
+    let mut hash: DataHash<HashMap<u64, usize>> = HashMap::new();
+    for view in query.select(data) {
+        if let IpProtocol::Tcp(flow) = view.flow {
+            let amount_per_payload_size = hash
+                .entry(view.label.tunnel.clone())
+                .or_default()
+                .entry(view.label.application.clone())
+                .or_default();
+            for packet in &flow.packets {
+                *amount_per_payload_size
+                    .entry(packet.payload_length())
+                    .or_default() += 1;
+            }
+        }
+    }
+    hash
+}
+
+#[derive(Debug, Default)]
+pub struct HandshakeCounts {
+    pub flows: usize,
+    pub established: usize,
+    pub closed: usize,
+}
+
+/// How many TCP flows per combination were seen opening and closing.
+pub fn collect_handshake_hash(
+    data: &[MetadataWrapper],
+    query: &FlowQuery,
+) -> DataHash<HandshakeCounts> {
+    let mut hash: DataHash<HandshakeCounts> = HashMap::new();
+    for view in query.select(data) {
+        if let IpProtocol::Tcp(flow) = view.flow {
+            let counts = hash
+                .entry(view.label.tunnel.clone())
+                .or_default()
+                .entry(view.label.application.clone())
+                .or_default();
+            let lifecycle = TcpLifecycle::new(flow);
+            counts.flows += 1;
+            counts.established += usize::from(lifecycle.established());
+            counts.closed += usize::from(lifecycle.closed());
+        }
+    }
+    hash
+}
+
+/// [`TcpStats`] summed over the TCP flows of each combination.
+#[derive(Debug, Default)]
+pub struct TcpHealth {
+    pub flows: usize,
+    pub segments: usize,
+    pub retransmissions: usize,
+    pub out_of_order: usize,
+    pub duplicate_acks: usize,
+    pub rtt_samples: usize,
+    /// Sum of all RTT samples in microseconds.
+    pub rtt_total: f64,
+}
+
+impl TcpHealth {
+    fn add(&mut self, stats: &TcpStats) {
+        self.flows += 1;
+        self.segments += stats.segments;
+        self.retransmissions += stats.retransmissions;
+        self.out_of_order += stats.out_of_order;
+        self.duplicate_acks += stats.duplicate_acks;
+        self.rtt_samples += stats.rtt.count;
+        self.rtt_total += stats.rtt.total;
+    }
+
+    pub fn retransmission_rate(&self) -> f64 {
+        if self.segments == 0 {
+            return 0.0;
+        }
+        self.retransmissions as f64 / self.segments as f64
+    }
+
+    /// Mean over every RTT sample of the combination, in microseconds.
+    pub fn mean_rtt(&self) -> Option<f64> {
+        (self.rtt_samples > 0).then(|| self.rtt_total / self.rtt_samples as f64)
+    }
+}
+
+impl Display for TcpHealth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} flows, {:.2}% of {} segments retransmitted, {} out of order, {} duplicate ACKs",
+            self.flows,
+            self.retransmission_rate() * 100.0,
+            self.segments,
+            self.out_of_order,
+            self.duplicate_acks
+        )?;
+        match self.mean_rtt() {
+            Some(rtt) => write!(f, ", mean RTT {:.3} ms", rtt / 1000.0),
+            None => write!(f, ", no RTT samples"),
+        }
+    }
+}
+
+/// Retransmissions, reordering and RTT of the TCP flows per combination,
+/// which is where TCP-over-TCP tunnels stand out.
+pub fn collect_tcp_health_hash(data: &[MetadataWrapper], query: &FlowQuery) -> DataHash<TcpHealth> {
+    let mut hash: DataHash<TcpHealth> = HashMap::new();
+    for view in query.select(data) {
+        if let IpProtocol::Tcp(flow) = view.flow {
+            hash.entry(view.label.tunnel.clone())
+                .or_default()
+                .entry(view.label.application.clone())
+                .or_default()
+                .add(&TcpStats::new(flow));
+        }
+    }
+    hash
 }
 
 pub fn print_all_maximum_byte_values<T: Debug>(hash: &DataHash<T>) {
-    hash.iter().for_each(|(encryption, hash)| {
+    hash.iter().for_each(|(tunnel, hash)| {
         hash.iter().for_each(|(category, number)| {
-            let encryption = match encryption {
-                Encryption::VPN(vpn) => format!("VPN: {}", vpn),
-                Encryption::NonVPN => "NonVPN".to_string(),
-            };
-            println!("{encryption} {category} has sizes of {:#?} ", number);
+            println!("{tunnel} {category} has sizes of {:#?} ", number);
+        });
+    })
+}
+
+pub fn print_tcp_health(hash: &DataHash<TcpHealth>) {
+    hash.iter().for_each(|(tunnel, hash)| {
+        hash.iter().for_each(|(category, health)| {
+            println!("{tunnel} {category}: {health}");
         });
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixture_layout;
+
+    #[test]
+    fn tcp_health_sums_the_tcp_flows_of_each_combination() {
+        let data = get_all_data(&fixture_layout("tcp-health"));
+        let hash = collect_tcp_health_hash(&data, &FlowQuery::default());
+        let ssh = &hash["OpenVPN"]["SSH"];
+        // The UDP flow of the same file is not counted.
+        assert_eq!(ssh.flows, 1);
+        // SYN and SYN-ACK, each acknowledged 20 ms later.
+        assert_eq!(ssh.segments, 2);
+        assert_eq!(ssh.rtt_samples, 2);
+        assert_eq!(ssh.mean_rtt(), Some(20_000.0));
+        assert_eq!(ssh.retransmission_rate(), 0.0);
+        assert_eq!(hash["NonVPN"]["Mail"].flows, 1);
+    }
+}